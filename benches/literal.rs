@@ -0,0 +1,62 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use grep::{
+    matcher::Matcher,
+    regex::RegexMatcher,
+    searcher::{sinks::UTF8, Searcher},
+};
+
+// The crate is binary-only, so pull the matcher module in directly
+#[allow(dead_code)]
+#[path = "../src/matcher.rs"]
+mod matcher;
+
+use matcher::{LiteralMatcher, QueryMatcher};
+
+const QUERY: &str = "needle";
+
+/// Roughly 15 MB of text with a match every 100 lines
+fn corpus() -> Vec<u8> {
+    let words = ["alpha", "beta", "gamma", "delta", "epsilon", "zeta", "eta", "theta"];
+    let mut text = String::new();
+
+    for i in 0..250_000 {
+        for j in 0..8 {
+            text.push_str(words[(i * 7 + j * 3) % words.len()]);
+            text.push(' ');
+        }
+        if i % 100 == 0 {
+            text.push_str(QUERY);
+        }
+        text.push('\n');
+    }
+    text.into_bytes()
+}
+
+fn count_matches<M: Matcher>(matcher: &M, corpus: &[u8]) -> u64 {
+    let mut count = 0;
+    Searcher::new()
+        .search_slice(matcher, corpus, UTF8(|_, _| {
+            count += 1;
+            Ok(true)
+        }))
+        .unwrap();
+    count
+}
+
+fn literal_search(c: &mut Criterion) {
+    let corpus = corpus();
+    let mut group = c.benchmark_group("literal");
+    group.throughput(Throughput::Bytes(corpus.len() as u64));
+
+    // Previous behaviour: the escaped literal compiled as a regex
+    let regex = RegexMatcher::new(&regex::escape(QUERY)).unwrap();
+    group.bench_function("escaped_regex", |b| b.iter(|| count_matches(&regex, &corpus)));
+
+    let literal = QueryMatcher::Literal(LiteralMatcher::new(QUERY));
+    group.bench_function("literal_fast_path", |b| b.iter(|| count_matches(&literal, &corpus)));
+
+    group.finish();
+}
+
+criterion_group!(benches, literal_search);
+criterion_main!(benches);
@@ -0,0 +1,162 @@
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::Value;
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+    sync::{atomic::AtomicBool, Arc},
+};
+use crate::output::{print_results, PrintOptions};
+use crate::search::{list_files, search_files, SearchResult};
+use crate::SearchConfig;
+
+/// One search of a `--queries-from` file
+#[derive(Debug, Clone)]
+pub struct Query {
+    pub id: String,
+    pub query: String,
+    /// Only files whose name matches this glob are searched for the query
+    pub glob: Option<String>,
+    /// Encoding of the query's files, like `--encoding`
+    pub encoding: Option<String>,
+}
+
+/// Parses a queries file: one query per line, or a JSON object per line like
+/// `{"id": "q1", "query": "needle"}`. Objects can limit their query to files
+/// matching a `"glob"` and decode those with an `"encoding"`. Plain lines and
+/// objects without an id are identified by their line number. Empty lines and
+/// `#` comments are skipped.
+pub fn parse_queries(content: &str) -> Result<Vec<Query>> {
+    let mut queries = Vec::new();
+
+    for (index, line) in content.lines().enumerate() {
+        let line_number = index + 1;
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+
+        if !trimmed.starts_with('{') {
+            queries.push(Query { id: line_number.to_string(), query: line.to_string(), glob: None, encoding: None });
+            continue;
+        }
+
+        let value: Value = serde_json::from_str(trimmed)
+            .with_context(|| format!("line {}: invalid JSON", line_number))?;
+        let query = match value.get("query").and_then(Value::as_str) {
+            Some(query) => query.to_string(),
+            None => bail!("line {}: missing \"query\" string", line_number),
+        };
+        let id = match value.get("id") {
+            Some(Value::String(id)) => id.clone(),
+            Some(Value::Number(id)) => id.to_string(),
+            _ => line_number.to_string(),
+        };
+        let text = |key: &str| match value.get(key) {
+            None => Ok(None),
+            Some(Value::String(text)) => Ok(Some(text.clone())),
+            Some(_) => Err(anyhow!("line {}: \"{}\" must be a string", line_number, key)),
+        };
+        let glob = text("glob")?;
+        let encoding = text("encoding")?;
+        queries.push(Query { id, query, glob, encoding });
+    }
+
+    Ok(queries)
+}
+
+pub fn load_queries(path: &Path) -> Result<Vec<Query>> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read queries from {}", path.display()))?;
+    parse_queries(&content)
+}
+
+/// Runs every query over the same file list, walking the directory tree only once.
+/// Queries with a glob only search the listed files it matches
+pub fn search_queries(
+    config: &SearchConfig,
+    queries: Vec<Query>,
+    quit: Arc<AtomicBool>,
+) -> Result<Vec<(Query, Vec<SearchResult>)>> {
+    let files = list_files(config, &quit)?;
+    if config.verbose {
+        println!("Running {} queries over {} files", queries.len(), files.len());
+    }
+
+    let mut batches = Vec::new();
+    for query in queries {
+        // Without files the search would fall back to the current directory
+        let results = if files.is_empty() {
+            Vec::new()
+        } else {
+            // The listed files are walked as roots, so the glob still filters them
+            let patterns = match &query.glob {
+                Some(glob) => vec![glob.clone()],
+                None => config.patterns.clone(),
+            };
+            // Ahead of the --encoding globs, the first match decides
+            let mut encodings = config.encodings.clone();
+            if let Some(encoding) = &query.encoding {
+                let glob = query.glob.clone().unwrap_or_else(|| "*".to_string());
+                encodings.insert(0, (encoding.clone(), glob));
+            }
+            let query_config = SearchConfig {
+                paths: files.clone(),
+                query: query.query.clone(),
+                patterns,
+                encodings,
+                // The files are already split, one pool serves them all
+                threads_per_path: 0,
+                ..config.clone()
+            };
+            search_files(&query_config, quit.clone())?
+        };
+        batches.push((query, results));
+    }
+
+    Ok(batches)
+}
+
+/// Prints results grouped by query, each group under a header naming the query
+pub fn write_batch(out: &mut dyn Write, batches: Vec<(Query, Vec<SearchResult>)>, options: &PrintOptions) -> io::Result<()> {
+    for (query, results) in batches {
+        let glob = query.glob.as_ref().map(|glob| format!(" in {}", glob)).unwrap_or_default();
+        writeln!(out, "{}=== Query {}: {}{} ==={}", options.colors.path, query.id, query.query, glob, options.colors.reset)?;
+        print_results(out, &results, options)?;
+        writeln!(out, "{} matches for query {}", results.len(), query.id)?;
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_lines_and_objects_are_queries() {
+        let content = "# comment\nneedle\n\n\
+            {\"id\": \"q1\", \"query\": \"haystack\", \"glob\": \"*.md\"}\n\
+            {\"query\": \"x\", \"id\": 7, \"encoding\": \"latin1\"}\n\
+            {\"query\": \"y\"}\n  spaced \n";
+        let queries = parse_queries(content).expect("valid queries");
+        let parsed: Vec<(&str, &str, Option<&str>, Option<&str>)> = queries.iter()
+            .map(|query| (query.id.as_str(), query.query.as_str(), query.glob.as_deref(), query.encoding.as_deref()))
+            .collect();
+        assert_eq!(parsed, [
+            ("2", "needle", None, None),
+            ("q1", "haystack", Some("*.md"), None),
+            ("7", "x", None, Some("latin1")),
+            ("6", "y", None, None),
+            ("7", "  spaced ", None, None),
+        ]);
+    }
+
+    #[test]
+    fn malformed_objects_name_their_line() {
+        let error = parse_queries("needle\n{not json}\n").unwrap_err();
+        assert!(error.to_string().starts_with("line 2:"), "{}", error);
+        assert!(parse_queries("{\"id\": \"a\"}").is_err());
+        assert!(parse_queries("{\"query\": \"a\", \"glob\": 3}").is_err());
+    }
+}
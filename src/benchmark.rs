@@ -0,0 +1,70 @@
+use anyhow::Result;
+use std::{
+    io::Write,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Instant,
+};
+use crate::search::search;
+use crate::SearchConfig;
+
+// Searched without --text, it matches nothing so only searching is timed, not printing
+const BENCHMARK_QUERY: &str = "quicksearch benchmark query";
+
+/// Worker counts to try: powers of two up to twice the cores, and the core count itself
+fn thread_counts() -> Vec<usize> {
+    let cores = thread::available_parallelism().map(|v| v.get()).unwrap_or(2);
+    let mut counts: Vec<usize> = std::iter::successors(Some(1), |count| Some(count * 2))
+        .take_while(|count| *count <= cores * 2)
+        .collect();
+    if !counts.contains(&cores) {
+        counts.push(cores);
+        counts.sort_unstable();
+    }
+    counts
+}
+
+/// Searches the paths of `config` once per worker count and prints files/s and
+/// MB/s of each, for picking `-j`. An untimed first run fills the file cache,
+/// so every timed run reads from it alike
+pub fn run(config: &SearchConfig, out: &mut dyn Write) -> Result<()> {
+    let query = if config.query.is_empty() {
+        BENCHMARK_QUERY.to_string()
+    } else {
+        config.query.clone()
+    };
+    let quit = Arc::new(AtomicBool::new(false));
+
+    let mut results = Vec::new();
+    for (run, threads) in std::iter::once(0).chain(thread_counts()).enumerate() {
+        // Fresh counters for every run, the template's are shared
+        let run_config = SearchConfig {
+            query: query.clone(),
+            num_workers: threads,
+            files_processed: Arc::new(AtomicUsize::new(0)),
+            bytes_searched: Arc::new(AtomicU64::new(0)),
+            ..config.clone()
+        };
+        let started = Instant::now();
+        let matches = search(&run_config, quit.clone())?.count();
+        let elapsed = started.elapsed().as_secs_f64();
+        if run == 0 {
+            continue;
+        }
+
+        let files = run_config.files_processed.load(Ordering::Relaxed);
+        let bytes = run_config.bytes_searched.load(Ordering::Relaxed);
+        results.push((threads, files, bytes, matches, elapsed));
+    }
+
+    writeln!(out, "{:>7} {:>9} {:>10} {:>10} {:>9} {:>8}", "threads", "files", "files/s", "MB/s", "matches", "time")?;
+    for (threads, files, bytes, matches, elapsed) in results {
+        let elapsed = elapsed.max(f64::EPSILON);
+        writeln!(out, "{:>7} {:>9} {:>10.0} {:>10.1} {:>9} {:>7.2}s",
+            threads, files, files as f64 / elapsed, bytes as f64 / elapsed / 1e6, matches, elapsed)?;
+    }
+    Ok(())
+}
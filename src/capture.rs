@@ -0,0 +1,72 @@
+use anyhow::{bail, Result};
+use regex::{Regex, RegexBuilder};
+use std::io::{self, Write};
+use crate::search::SearchResult;
+use crate::SearchConfig;
+
+/// Extracts one named capture group from every match, for --capture
+pub struct Capture {
+    regex: Regex,
+    name: String,
+}
+
+impl Capture {
+    pub fn new(config: &SearchConfig, name: &str) -> Result<Self> {
+        if !config.use_regex {
+            bail!("--capture needs a regex query (-r)");
+        }
+
+        // Same pattern rules as the search itself
+        let regex = RegexBuilder::new(&config.query)
+            .dot_matches_new_line(config.dot_all)
+            .multi_line(config.multi_line)
+            .ignore_whitespace(config.ignore_whitespace)
+            .build()?;
+        if !regex.capture_names().flatten().any(|group| group == name) {
+            bail!("The pattern has no capture group named '{}'", name);
+        }
+
+        Ok(Self { regex, name: name.to_string() })
+    }
+
+    /// Values of the group in `line`, one per match where the group took part
+    pub fn extract<'a>(&'a self, line: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.regex.captures_iter(line)
+            .filter_map(|caps| caps.name(&self.name))
+            .map(|value| value.as_str())
+    }
+}
+
+/// Prints the captured values of all results, one per line
+pub fn write_captures(out: &mut dyn Write, results: &[SearchResult], capture: &Capture) -> io::Result<()> {
+    for result in results {
+        for value in capture.extract(&result.line) {
+            writeln!(out, "{}", value)?;
+        }
+    }
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn regex_config(query: &str) -> SearchConfig {
+        SearchConfig { query: query.to_string(), use_regex: true, ..SearchConfig::default() }
+    }
+
+    #[test]
+    fn extracts_the_group_of_every_match() {
+        let capture = Capture::new(&regex_config(r"user=(?P<user>\w+)"), "user").expect("valid capture");
+        let values: Vec<&str> = capture.extract("user=alice and user=bob").collect();
+        assert_eq!(values, ["alice", "bob"]);
+        assert_eq!(capture.extract("no users here").count(), 0);
+    }
+
+    #[test]
+    fn needs_a_regex_with_the_named_group() {
+        let literal = SearchConfig { use_regex: false, ..regex_config(r"(?P<user>\w+)") };
+        assert!(Capture::new(&literal, "user").is_err());
+        assert!(Capture::new(&regex_config(r"(?P<user>\w+)"), "name").is_err());
+    }
+}
@@ -0,0 +1,182 @@
+/// Parts of the output --colors can style
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Component {
+    /// File headers
+    Path,
+    /// Line number gutter of context lines
+    Line,
+    /// Marker and line number of match lines
+    Match,
+    /// Lines removed and added in a --diff preview
+    Removed,
+    Added,
+}
+
+/// What a spec changes about its component
+#[derive(Debug, Clone, PartialEq)]
+pub enum Setting {
+    /// SGR parameters of a foreground or background color, like `31` or `38;5;208`
+    Fg(String),
+    Bg(String),
+    Bold,
+    Underline,
+    /// Clears the component's style
+    None,
+}
+
+/// One --colors spec, `COMPONENT:ATTRIBUTE:VALUE` or `COMPONENT:none`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColorSpec {
+    pub component: Component,
+    pub setting: Setting,
+}
+
+const COLOR_NAMES: [&str; 8] = ["black", "red", "green", "yellow", "blue", "magenta", "cyan", "white"];
+
+/// SGR parameters of a named or 0-255 color, `base` is 30 for foreground, 40 for background
+fn parse_color(value: &str, base: u8) -> Result<String, String> {
+    if let Some(index) = COLOR_NAMES.iter().position(|name| *name == value) {
+        return Ok((base + index as u8).to_string());
+    }
+    match value.parse::<u8>() {
+        Ok(number) => Ok(format!("{};5;{}", base + 8, number)),
+        Err(_) => Err(format!("unknown color '{}', use {} or 0-255", value, COLOR_NAMES.join(", "))),
+    }
+}
+
+/// Parses a --colors spec like `match:fg:red`, `path:style:bold`,
+/// `line:bg:236` or `path:none`
+pub fn parse_color_spec(spec: &str) -> Result<ColorSpec, String> {
+    let parts: Vec<&str> = spec.split(':').collect();
+    let component = match parts[0] {
+        "path" => Component::Path,
+        "line" => Component::Line,
+        "match" => Component::Match,
+        "removed" => Component::Removed,
+        "added" => Component::Added,
+        other => return Err(format!("unknown component '{}', use path, line, match, removed or added", other)),
+    };
+    let setting = match parts[1..] {
+        ["none"] => Setting::None,
+        ["fg", value] => Setting::Fg(parse_color(value, 30)?),
+        ["bg", value] => Setting::Bg(parse_color(value, 40)?),
+        ["style", "bold"] => Setting::Bold,
+        ["style", "underline"] => Setting::Underline,
+        ["style", value] => return Err(format!("unknown style '{}', use bold or underline", value)),
+        _ => return Err(format!("expected COMPONENT:ATTRIBUTE:VALUE like match:fg:red, got '{}'", spec)),
+    };
+    Ok(ColorSpec { component, setting })
+}
+
+/// Colors and attributes of one component
+#[derive(Debug, Clone, Default)]
+pub struct Style {
+    fg: Option<String>,
+    bg: Option<String>,
+    bold: bool,
+    underline: bool,
+}
+
+impl Style {
+    fn fg(color: u8) -> Self {
+        Self { fg: Some(color.to_string()), ..Self::default() }
+    }
+
+    /// The ANSI escape sequence starting this style, empty for no style
+    pub fn escape(&self) -> String {
+        let mut params: Vec<&str> = Vec::new();
+        if self.bold {
+            params.push("1");
+        }
+        if self.underline {
+            params.push("4");
+        }
+        params.extend(self.fg.as_deref());
+        params.extend(self.bg.as_deref());
+        if params.is_empty() {
+            String::new()
+        } else {
+            format!("\x1b[{}m", params.join(";"))
+        }
+    }
+
+    fn apply(&mut self, setting: &Setting) {
+        match setting {
+            Setting::Fg(color) => self.fg = Some(color.clone()),
+            Setting::Bg(color) => self.bg = Some(color.clone()),
+            Setting::Bold => self.bold = true,
+            Setting::Underline => self.underline = true,
+            Setting::None => *self = Self::default(),
+        }
+    }
+}
+
+/// Styles of all components, the defaults changed by the --colors specs in order
+#[derive(Debug, Clone)]
+pub struct Theme {
+    pub path: Style,
+    pub line: Style,
+    pub matched: Style,
+    pub removed: Style,
+    pub added: Style,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            path: Style::fg(35),
+            line: Style::fg(32),
+            matched: Style { bold: true, ..Style::fg(31) },
+            removed: Style::fg(31),
+            added: Style::fg(32),
+        }
+    }
+}
+
+impl Theme {
+    pub fn new(specs: &[ColorSpec]) -> Self {
+        let mut theme = Self::default();
+        for spec in specs {
+            let style = match spec.component {
+                Component::Path => &mut theme.path,
+                Component::Line => &mut theme.line,
+                Component::Match => &mut theme.matched,
+                Component::Removed => &mut theme.removed,
+                Component::Added => &mut theme.added,
+            };
+            style.apply(&spec.setting);
+        }
+        theme
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn color_specs_name_a_component_and_a_setting() {
+        let spec = |component, setting| Ok(ColorSpec { component, setting });
+        assert_eq!(parse_color_spec("match:fg:red"), spec(Component::Match, Setting::Fg("31".to_string())));
+        assert_eq!(parse_color_spec("line:bg:236"), spec(Component::Line, Setting::Bg("48;5;236".to_string())));
+        assert_eq!(parse_color_spec("path:style:bold"), spec(Component::Path, Setting::Bold));
+        assert_eq!(parse_color_spec("added:none"), spec(Component::Added, Setting::None));
+
+        assert!(parse_color_spec("gutter:fg:red").is_err());
+        assert!(parse_color_spec("match:fg:orange").is_err());
+        assert!(parse_color_spec("match:fg:256").is_err());
+        assert!(parse_color_spec("match:style:blink").is_err());
+        assert!(parse_color_spec("match").is_err());
+    }
+
+    #[test]
+    fn specs_apply_in_order_over_the_defaults() {
+        let specs = ["match:none", "match:style:underline", "match:fg:blue", "path:bg:white"]
+            .map(|spec| parse_color_spec(spec).expect("valid spec"));
+        let theme = Theme::new(&specs);
+        assert_eq!(theme.matched.escape(), "\x1b[4;34m");
+        assert_eq!(theme.path.escape(), "\x1b[35;47m");
+        assert_eq!(theme.line.escape(), "\x1b[32m");
+        assert_eq!(Style::default().escape(), "");
+    }
+}
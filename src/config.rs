@@ -0,0 +1,93 @@
+use std::{
+    collections::HashMap,
+    env,
+    fs,
+    io,
+    path::{Path, PathBuf},
+};
+
+/// Directory holding the quicksearch config file
+/// (`$XDG_CONFIG_HOME/quicksearch`, falling back to `~/.config/quicksearch`)
+pub fn config_dir() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .map(|dir| dir.join("quicksearch"))
+}
+
+/// Directories quicksearch writes to itself, never searched below a search root
+/// so it can't match its own settings and history. Resolved, and empty while
+/// they don't exist
+pub fn excluded_dirs() -> Vec<PathBuf> {
+    config_dir()
+        .and_then(|dir| fs::canonicalize(dir).ok())
+        .into_iter()
+        .collect()
+}
+
+pub fn config_path() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join("config"))
+}
+
+/// Append-only log of past searches, written with --log-searches
+pub fn history_path() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join("history"))
+}
+
+/// Settings the GUI saves itself, kept apart from the hand-written config file
+pub fn gui_settings_path() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join("gui"))
+}
+
+/// Settings from the config file: one `key = value` per line, `#` starts a comment.
+/// Command line flags take precedence over these values.
+#[derive(Debug, Default, Clone)]
+pub struct UserConfig {
+    values: HashMap<String, String>,
+}
+
+impl UserConfig {
+    /// Loads the config file, a missing or unreadable file gives an empty config
+    pub fn load() -> Self {
+        config_path()
+            .map(|path| Self::load_from(&path))
+            .unwrap_or_default()
+    }
+
+    pub fn load_from(path: &Path) -> Self {
+        fs::read_to_string(path)
+            .map(|content| Self::parse(&content))
+            .unwrap_or_default()
+    }
+
+    /// Writes all values as `key = value` lines sorted by key, creating the directory if needed
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut keys: Vec<_> = self.values.keys().collect();
+        keys.sort();
+        let content: String = keys.into_iter()
+            .map(|key| format!("{} = {}\n", key, self.values[key]))
+            .collect();
+        fs::write(path, content)
+    }
+
+    pub fn parse(content: &str) -> Self {
+        let values = content.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| line.split_once('='))
+            .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+            .collect();
+        Self { values }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(String::as_str)
+    }
+
+    pub fn set(&mut self, key: &str, value: impl ToString) {
+        self.values.insert(key.to_string(), value.to_string());
+    }
+}
@@ -0,0 +1,140 @@
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+use crate::capture::Capture;
+use crate::search::SearchResult;
+
+/// `path` with `suffix` appended to its file name, `foo.log` -> `foo.log.matches`
+fn extract_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Writes the matching lines of each file, or the values of `capture`, to a
+/// sibling file named with `suffix`, for --extract-to-suffix. Files that can't
+/// be written are reported and skipped, the summary goes to `out`
+pub fn write_extracts(
+    out: &mut dyn Write,
+    results: &[SearchResult],
+    suffix: &str,
+    capture: Option<&Capture>,
+    no_messages: bool,
+) -> io::Result<()> {
+    let mut files: BTreeMap<&Path, Vec<&SearchResult>> = BTreeMap::new();
+    for result in results {
+        // Output of an earlier run, extracting it again would only stack suffixes
+        if result.path.to_string_lossy().ends_with(suffix) {
+            continue;
+        }
+        files.entry(&result.path).or_default().push(result);
+    }
+
+    let mut written = 0;
+    let mut lines = 0;
+    let mut failed = 0;
+    for (path, mut results) in files {
+        results.sort_by_key(|result| result.line_number);
+        let mut text = String::new();
+        for result in results {
+            match capture {
+                Some(capture) => capture.extract(&result.line).for_each(|value| {
+                    text.push_str(value);
+                    text.push('\n');
+                    lines += 1;
+                }),
+                None => {
+                    text.push_str(&result.line);
+                    text.push('\n');
+                    lines += 1;
+                }
+            }
+        }
+
+        let target = extract_path(path, suffix);
+        match fs::write(&target, text) {
+            Ok(()) => written += 1,
+            Err(e) => {
+                failed += 1;
+                if !no_messages {
+                    eprintln!("Warning: could not write {}: {}", target.display(), e);
+                }
+            }
+        }
+    }
+
+    writeln!(out, "Wrote {} lines to {} files ({} suffix)", lines, written, suffix)?;
+    if failed > 0 {
+        writeln!(out, "{} files could not be written", failed)?;
+    }
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::search::SourceKind;
+    use crate::testutil::temp_dir;
+    use crate::SearchConfig;
+
+    fn result(path: &Path, line_number: u64, line: &str) -> SearchResult {
+        SearchResult {
+            path: path.to_path_buf(),
+            line_number,
+            line: line.to_string(),
+            matches: Vec::new(),
+            context_before: Vec::new(),
+            context_after: Vec::new(),
+            lossy: false,
+            seq: 0,
+            source: SourceKind::Text,
+        }
+    }
+
+    fn extracted(results: &[SearchResult], capture: Option<&Capture>) -> String {
+        let mut out = Vec::new();
+        write_extracts(&mut out, results, ".matches", capture, true).expect("write extracts");
+        String::from_utf8(out).expect("UTF-8 output")
+    }
+
+    #[test]
+    fn matching_lines_go_next_to_their_file_in_line_order() {
+        let dir = temp_dir("extracts");
+        let log = dir.path().join("app.log");
+        let earlier = dir.path().join("old.log.matches");
+        let results = [
+            result(&log, 3, "error: third"),
+            result(&log, 1, "error: first"),
+            result(&earlier, 1, "error: first"),
+        ];
+
+        assert_eq!(extracted(&results, None), "Wrote 2 lines to 1 files (.matches suffix)\n");
+        let text = fs::read_to_string(dir.path().join("app.log.matches")).expect("read extract");
+        assert_eq!(text, "error: first\nerror: third\n");
+        assert!(!dir.path().join("old.log.matches.matches").exists());
+    }
+
+    #[test]
+    fn captures_are_written_instead_of_lines() {
+        let dir = temp_dir("extract-captures");
+        let log = dir.path().join("access.log");
+        let config = SearchConfig { query: r"user=(?P<user>\w+)".to_string(), use_regex: true, ..SearchConfig::default() };
+        let capture = Capture::new(&config, "user").expect("valid capture");
+
+        let results = [result(&log, 1, "user=alice user=bob")];
+        assert_eq!(extracted(&results, Some(&capture)), "Wrote 2 lines to 1 files (.matches suffix)\n");
+        let text = fs::read_to_string(dir.path().join("access.log.matches")).expect("read extract");
+        assert_eq!(text, "alice\nbob\n");
+    }
+
+    #[test]
+    fn unwritable_files_are_counted() {
+        let dir = temp_dir("extract-fails");
+        let results = [result(&dir.path().join("missing/a.log"), 1, "error")];
+        assert_eq!(extracted(&results, None), "Wrote 1 lines to 0 files (.matches suffix)\n1 files could not be written\n");
+    }
+}
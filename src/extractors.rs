@@ -0,0 +1,41 @@
+use anyhow::Result;
+use std::{collections::HashMap, fmt, path::Path, sync::Arc};
+
+/// Turns a file into the text to search, e.g. a PDF with pdftotext
+pub type Extractor = Arc<dyn Fn(&Path) -> Result<String> + Send + Sync>;
+
+/// Extractors by file extension. Workers hand files with a registered
+/// extension to their extractor and search the text it returns, all other
+/// files are searched as they are. PDFs are registered like any other format,
+/// their text is then split into pages as well
+#[derive(Clone, Default)]
+pub struct Extractors {
+    by_extension: HashMap<String, Extractor>,
+}
+
+impl Extractors {
+    /// Registers `extractor` for files ending in `.extension` (given without
+    /// the dot, any case), replacing an earlier one
+    pub fn register(&mut self, extension: &str, extractor: impl Fn(&Path) -> Result<String> + Send + Sync + 'static) {
+        self.by_extension.insert(extension.to_lowercase(), Arc::new(extractor));
+    }
+
+    pub fn get(&self, path: &Path) -> Option<&Extractor> {
+        let extension = path.extension()?.to_string_lossy().to_lowercase();
+        self.by_extension.get(&extension)
+    }
+
+    /// Adds the extractors of `other`, they win over the ones registered here
+    pub fn extend(&mut self, other: &Extractors) {
+        self.by_extension.extend(other.by_extension.iter().map(|(ext, extractor)| (ext.clone(), extractor.clone())));
+    }
+}
+
+// Closures can't be printed, their extensions stand for them
+impl fmt::Debug for Extractors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut extensions: Vec<&String> = self.by_extension.keys().collect();
+        extensions.sort();
+        f.debug_set().entries(extensions).finish()
+    }
+}
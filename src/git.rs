@@ -0,0 +1,179 @@
+use anyhow::{bail, Context, Result};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// Lines added or modified per file, as inclusive `(first, last)` line
+/// number ranges in the working tree version. Keys are the search root
+/// joined with the path below it, the same paths the walk produces
+#[derive(Debug, Default)]
+pub struct ChangedLines {
+    files: HashMap<PathBuf, Vec<(u64, u64)>>,
+}
+
+impl ChangedLines {
+    pub fn get(&self, path: &Path) -> Option<&[(u64, u64)]> {
+        self.files.get(path).map(Vec::as_slice)
+    }
+
+    /// Files with at least one added or modified line
+    pub fn files(&self) -> impl Iterator<Item = &Path> {
+        self.files.keys().map(PathBuf::as_path)
+    }
+}
+
+/// Whether `line` lies in one of the `ranges` of a `ChangedLines` entry
+pub fn in_ranges(ranges: &[(u64, u64)], line: u64) -> bool {
+    ranges.iter().any(|&(first, last)| (first..=last).contains(&line))
+}
+
+/// Lines changed below `roots` compared to `base` (HEAD when `None`), staged
+/// and unstaged changes alike. Untracked files have no diff and are left out.
+/// Fails for a root outside a git repository
+pub fn changed_lines(roots: &[PathBuf], base: Option<&str>) -> Result<ChangedLines> {
+    let mut changed = ChangedLines::default();
+
+    for root in roots {
+        // A file root is diffed from its directory, limited to itself
+        let (dir, file) = if root.is_file() {
+            let parent = root.parent().unwrap_or(Path::new(""));
+            (parent, root.file_name().map(Path::new))
+        } else {
+            (root.as_path(), None)
+        };
+        let git_dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+
+        let inside = Command::new("git").arg("-C").arg(git_dir)
+            .args(["rev-parse", "--is-inside-work-tree"])
+            .output()
+            .context("--diff-only needs git, which could not be run")?;
+        if !inside.status.success() {
+            bail!("--diff-only needs a git repository, {} is not inside one", root.display());
+        }
+
+        // --relative prints paths below `dir`, so they can be joined to it
+        let mut command = Command::new("git");
+        command.arg("-C").arg(git_dir)
+            .args(["diff", "--no-color", "--no-ext-diff", "--unified=0", "--relative"])
+            // Fixed prefixes, whatever diff.noprefix or diff.mnemonicPrefix say
+            .args(["--src-prefix=a/", "--dst-prefix=b/"])
+            .arg(base.unwrap_or("HEAD"))
+            .arg("--");
+        if let Some(file) = file {
+            command.arg(file);
+        }
+        let output = command.output().context("Failed to run git diff")?;
+        if !output.status.success() {
+            bail!("git diff failed in {}: {}", git_dir.display(),
+                String::from_utf8_lossy(&output.stderr).trim());
+        }
+
+        for (path, ranges) in parse_diff(&String::from_utf8_lossy(&output.stdout)) {
+            changed.files.entry(dir.join(path)).or_default().extend(ranges);
+        }
+    }
+
+    Ok(changed)
+}
+
+/// Added line ranges per file of a `git diff --unified=0`. Deleted files and
+/// hunks that only remove lines contribute nothing
+fn parse_diff(diff: &str) -> HashMap<String, Vec<(u64, u64)>> {
+    let mut files: HashMap<String, Vec<(u64, u64)>> = HashMap::new();
+    let mut current: Option<String> = None;
+    // An added line starting with "++ " looks like a header inside a hunk
+    let mut in_header = false;
+
+    for line in diff.lines() {
+        if line.starts_with("diff --git ") {
+            in_header = true;
+            current = None;
+        } else if let (true, Some(path)) = (in_header, line.strip_prefix("+++ ")) {
+            // Quoted paths (unusual characters) are taken as they are
+            current = path.strip_prefix("b/").map(str::to_string);
+        } else if let Some(hunk) = line.strip_prefix("@@ ") {
+            in_header = false;
+            let (Some(path), Some(range)) = (&current, hunk_range(hunk)) else {
+                continue;
+            };
+            files.entry(path.clone()).or_default().push(range);
+        }
+    }
+
+    files
+}
+
+/// New side of a hunk header `-a,b +c,d @@`: lines c to c+d-1, the count
+/// defaults to 1 and is 0 for a pure removal
+fn hunk_range(hunk: &str) -> Option<(u64, u64)> {
+    let new = hunk.split_whitespace().find_map(|part| part.strip_prefix('+'))?;
+    let (start, count) = match new.split_once(',') {
+        Some((start, count)) => (start.parse::<u64>().ok()?, count.parse::<u64>().ok()?),
+        None => (new.parse::<u64>().ok()?, 1),
+    };
+    (count > 0).then(|| (start, start + count - 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{temp_dir, WriteFile};
+
+    #[test]
+    fn hunk_headers_give_the_new_lines() {
+        assert_eq!(hunk_range("-2 +2 @@"), Some((2, 2)));
+        assert_eq!(hunk_range("-5,0 +6,2 @@ fn main() {"), Some((6, 7)));
+        assert_eq!(hunk_range("-1,2 +0,0 @@"), None);
+        assert_eq!(hunk_range("garbage"), None);
+    }
+
+    #[test]
+    fn diffs_give_the_added_ranges_per_file() {
+        let diff = "\
+diff --git a/src/a.rs b/src/a.rs
+index 1111111..2222222 100644
+--- a/src/a.rs
++++ b/src/a.rs
+@@ -2 +2 @@
+-old
++new
+@@ -5,0 +6,2 @@
++x
+++++ not a header
+diff --git a/gone.rs b/gone.rs
+deleted file mode 100644
+--- a/gone.rs
++++ /dev/null
+@@ -1,2 +0,0 @@
+-a
+-b
+";
+        let files = parse_diff(diff);
+        assert_eq!(files.len(), 1);
+        assert_eq!(files["src/a.rs"], [(2, 2), (6, 7)]);
+    }
+
+    #[test]
+    fn staged_changes_are_found() {
+        let dir = temp_dir("git-changes");
+        let git = |args: &[&str]| Command::new("git").arg("-C").arg(dir.path())
+            .args(["-c", "user.name=test", "-c", "user.email=test@example.com", "-c", "commit.gpgsign=false"])
+            .args(args)
+            .output()
+            .map(|output| assert!(output.status.success(), "git {:?}: {}", args, String::from_utf8_lossy(&output.stderr)));
+        dir.write("a.txt", "1\n2\n3\n4\n5\n");
+        if git(&["init", "-q"]).is_err() {
+            // git is not installed
+            return;
+        }
+        git(&["add", "a.txt"]).expect("run git");
+        git(&["commit", "-q", "-m", "first"]).expect("run git");
+        dir.write("a.txt", "1\ntwo\n3\nfour\n5\n");
+        git(&["add", "a.txt"]).expect("run git");
+
+        let changed = changed_lines(&[dir.path().to_path_buf()], None).expect("diff the repository");
+        assert_eq!(changed.get(&dir.path().join("a.txt")), Some(&[(2, 2), (4, 4)][..]));
+    }
+}
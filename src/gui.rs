@@ -1,13 +1,211 @@
 use gtk4::prelude::*;
 use libadwaita as adw;
-use crate::search::search_files;
+use crate::search::{find_gitignore_files, spawn_search, worker_threads, SearchHandle, SearchResult};
+use crate::replace::{self, FileChanges, Replacement};
+use crate::config::{gui_settings_path, UserConfig};
+use crate::history;
+use crate::types::FILE_TYPES;
 use crate::SearchConfig;
+use std::borrow::Cow;
+use std::collections::HashSet;
 use std::path::PathBuf;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
 use gio;
-use std::thread;
-use async_channel;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
+
+// Fits line numbers up to 99999 plus the '>' marker
+const GUTTER_WIDTH: usize = 6;
+
+// Loaded again for every window
+const UI_FILE: &str = "src/ui/windows.ui";
+
+fn load_settings() -> UserConfig {
+    gui_settings_path()
+        .map(|path| UserConfig::load_from(&path))
+        .unwrap_or_default()
+}
+
+/// The path to search, the current directory while the path entry is empty
+fn search_root(path_entry: &gtk4::Entry) -> PathBuf {
+    if path_entry.text().is_empty() {
+        std::env::current_dir().unwrap_or_default()
+    } else {
+        PathBuf::from(path_entry.text().as_str())
+    }
+}
+
+/// Stores one value in the GUI settings file, keeping the others
+fn save_setting(key: &str, value: impl ToString) {
+    let path = match gui_settings_path() {
+        Some(path) => path,
+        None => return,
+    };
+    let mut settings = UserConfig::load_from(&path);
+    settings.set(key, value);
+    if let Err(e) = settings.save(&path) {
+        eprintln!("Failed to save {}: {}", path.display(), e);
+    }
+}
+
+/// How results are laid out in the results view, set in the "View options" popover
+#[derive(Debug, Clone, Copy)]
+struct ViewOptions {
+    /// One `File:` heading per file instead of one per match
+    heading: bool,
+    /// `--` between matches of a file that are not next to each other, needs `heading`
+    separator: bool,
+    /// Longer lines are cut off, 0 shows them in full
+    max_columns: usize,
+    /// Results shown before the search is stopped, 0 shows all. Inserting
+    /// hundreds of thousands of lines makes the view unresponsive
+    max_results: usize,
+}
+
+impl ViewOptions {
+    fn load() -> Self {
+        let settings = load_settings();
+        let flag = |key: &str, default: bool| settings.get(key).map_or(default, |value| value == "true");
+        Self {
+            heading: flag("view_heading", false),
+            separator: flag("view_separator", true),
+            max_columns: settings.get("view_max_columns")
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(0),
+            max_results: settings.get("view_max_results")
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(10000),
+        }
+    }
+
+    fn save(&self) {
+        let path = match gui_settings_path() {
+            Some(path) => path,
+            None => return,
+        };
+        let mut settings = UserConfig::load_from(&path);
+        settings.set("view_heading", self.heading);
+        settings.set("view_separator", self.separator);
+        settings.set("view_max_columns", self.max_columns);
+        settings.set("view_max_results", self.max_results);
+        if let Err(e) = settings.save(&path) {
+            eprintln!("Failed to save {}: {}", path.display(), e);
+        }
+    }
+
+    fn is_capped(&self, count: usize) -> bool {
+        self.max_results > 0 && count >= self.max_results
+    }
+
+    /// Status bar text once `total` results were found
+    fn status(&self, total: usize) -> String {
+        if self.is_capped(total) {
+            format!("Showing first {} of {} results, search stopped early", self.max_results, total)
+        } else {
+            format!("Found {} matching files", total)
+        }
+    }
+
+    fn clip<'a>(&self, line: &'a str) -> Cow<'a, str> {
+        match line.char_indices().nth(self.max_columns) {
+            Some((end, _)) if self.max_columns > 0 => Cow::Owned(format!("{}…", &line[..end])),
+            _ => Cow::Borrowed(line),
+        }
+    }
+}
+
+/// Turns results into text for the results view, one at a time as they arrive
+struct ResultFormatter {
+    view: ViewOptions,
+    // The view is monospace, so one gutter width for all results keeps
+    // the '|' column aligned. It only grows, never shifting later results left
+    width: usize,
+    // File and last line shown of the previous result
+    last: Option<(PathBuf, u64)>,
+    // Result index and line number behind each line of text so far, `None`
+    // for blank lines and separators. Used by the context menu
+    rows: Vec<Option<(usize, u64)>>,
+    // Results formatted so far, the index of the next one
+    count: usize,
+}
+
+impl ResultFormatter {
+    fn new(view: ViewOptions) -> Self {
+        Self {
+            view,
+            width: GUTTER_WIDTH,
+            last: None,
+            rows: Vec::new(),
+            count: 0,
+        }
+    }
+
+    /// Result index and line number shown on `line` of the results view
+    fn row(&self, line: usize) -> Option<(usize, u64)> {
+        self.rows.get(line).copied().flatten()
+    }
+
+    fn format(&mut self, result: &SearchResult) -> String {
+        self.width = self.width.max(result.gutter_width());
+        let width = self.width;
+        let index = self.count;
+        self.count += 1;
+        let mut text = String::new();
+
+        // Last line shown of this file, when it continues under the same heading
+        let shown = match &self.last {
+            Some((path, line)) if self.view.heading && *path == result.path => Some(*line),
+            _ => None,
+        };
+        match shown {
+            Some(shown) => {
+                let first_line = result.context_before.first().map_or(result.line_number, |(line_num, _)| *line_num);
+                if self.view.separator && first_line > shown + 1 {
+                    text.push_str("--\n");
+                    self.rows.push(None);
+                }
+            }
+            None => {
+                // Empty line between files
+                if self.last.is_some() {
+                    text.push('\n');
+                    self.rows.push(None);
+                }
+                text.push_str(&format!("File: {}:{}\n", result.path.display(), result.line_number));
+                self.rows.push(Some((index, result.line_number)));
+            }
+        }
+
+        // Context shared with the previous match is shown once
+        let is_new = |line_num: u64| shown.map_or(true, |shown| line_num > shown);
+        for (line_num, line) in result.context_before.iter().filter(|(line_num, _)| is_new(*line_num)) {
+            text.push_str(&format!("{:>width$} | {}\n", line_num, self.view.clip(line), width = width));
+            self.rows.push(Some((index, *line_num)));
+        }
+
+        text.push_str(&format!(">{:>width$} | {}\n", result.line_number, self.view.clip(&result.line), width = width - 1));
+        self.rows.push(Some((index, result.line_number)));
+
+        for (line_num, line) in &result.context_after {
+            text.push_str(&format!("{:>width$} | {}\n", line_num, self.view.clip(line), width = width));
+            self.rows.push(Some((index, *line_num)));
+        }
+
+        let last_line = result.context_after.last().map_or(result.line_number, |(line_num, _)| *line_num);
+        self.last = Some((result.path.clone(), last_line));
+        text
+    }
+}
+
+/// Text of line `line_number` of `result`, the match line or a context line
+fn result_line(result: &SearchResult, line_number: u64) -> Option<&str> {
+    if line_number == result.line_number {
+        return Some(&result.line);
+    }
+    result.context_before.iter()
+        .chain(&result.context_after)
+        .find(|(line_num, _)| *line_num == line_number)
+        .map(|(_, line)| line.as_str())
+}
 
 pub struct SearchGUI {
     pub app: adw::Application,
@@ -20,7 +218,7 @@ impl SearchGUI {
         adw::init().expect("Failed to initialize libadwaita");
 
         // Create builder and load UI file
-        let builder = gtk4::Builder::from_file("src/ui/windows.ui");
+        let builder = gtk4::Builder::from_file(UI_FILE);
         
         // Verify that we can load all required widgets
         let required_widgets = ["main_window", "path_entry", "search_entry", 
@@ -43,11 +241,12 @@ impl SearchGUI {
 
     pub fn build_with_config(&self, config: SearchConfig) {
         // Debug prints commented out for cleaner output, uncomment if needed for debugging
-        println!("GUI received config: {:?}", config);
         let builder_clone = self.builder.clone();
         let config_clone = config.clone();
         
-        self.app.connect_activate(move |app| {
+        // Sets up the window of a builder. Called once per window, each keeps its
+        // own widgets, results and running search
+        let build_window = Rc::new(move |app: &adw::Application, builder_clone: &gtk4::Builder| {
             // println!("Setting initial values from config: {:?}", config_clone);
             
             let window: gtk4::Window = builder_clone
@@ -91,25 +290,123 @@ impl SearchGUI {
             // Set initial regex state from config
             regex_checkbox.set_active(config_clone.use_regex);
 
+            // File type toggles, remembered between runs
+            let pdf_checkbox: gtk4::CheckButton = builder_clone
+                .object("search_pdfs")
+                .expect("Could not get search_pdfs");
+            let compressed_checkbox: gtk4::CheckButton = builder_clone
+                .object("search_compressed")
+                .expect("Could not get search_compressed");
+            let settings = load_settings();
+            pdf_checkbox.set_active(settings.get("search_pdfs").map_or(true, |value| value == "true"));
+            compressed_checkbox.set_active(settings.get("search_compressed").map_or(false, |value| value == "true"));
+            pdf_checkbox.connect_toggled(|checkbox| save_setting("search_pdfs", checkbox.is_active()));
+            compressed_checkbox.connect_toggled(|checkbox| save_setting("search_compressed", checkbox.is_active()));
+            // .gitignore rules, with a picker to switch single files off
+            let gitignore_checkbox: gtk4::CheckButton = builder_clone
+                .object("respect_gitignore")
+                .expect("Could not get respect_gitignore");
+            let ignore_files_button: gtk4::MenuButton = builder_clone
+                .object("ignore_files_button")
+                .expect("Could not get ignore_files_button");
+            let ignore_files_popover: gtk4::Popover = builder_clone
+                .object("ignore_files_popover")
+                .expect("Could not get ignore_files_popover");
+            let ignore_files_box: gtk4::Box = builder_clone
+                .object("ignore_files_box")
+                .expect("Could not get ignore_files_box");
+            gitignore_checkbox.set_active(config_clone.respect_gitignore);
+            ignore_files_button.set_sensitive(config_clone.respect_gitignore);
+            let ignore_files_button_for_toggle = ignore_files_button.clone();
+            gitignore_checkbox.connect_toggled(move |checkbox| {
+                ignore_files_button_for_toggle.set_sensitive(checkbox.is_active());
+            });
+
+            // Switched off files stay off while the picker is rebuilt for another path
+            let disabled_ignore_files: Rc<RefCell<HashSet<PathBuf>>> = Rc::new(RefCell::new(HashSet::new()));
+            let path_entry_for_ignore = path_entry.clone();
+            let disabled_for_picker = disabled_ignore_files.clone();
+            ignore_files_popover.connect_show(move |_| {
+                while let Some(child) = ignore_files_box.first_child() {
+                    ignore_files_box.remove(&child);
+                }
+                let root = search_root(&path_entry_for_ignore);
+                let files = find_gitignore_files(std::slice::from_ref(&root));
+                if files.is_empty() {
+                    ignore_files_box.append(&gtk4::Label::new(Some("No .gitignore files found")));
+                }
+                for file in files {
+                    let checkbox = gtk4::CheckButton::with_label(&file.to_string_lossy());
+                    checkbox.set_active(!disabled_for_picker.borrow().contains(&file));
+                    let disabled = disabled_for_picker.clone();
+                    checkbox.connect_toggled(move |checkbox| {
+                        if checkbox.is_active() {
+                            disabled.borrow_mut().remove(&file);
+                        } else {
+                            disabled.borrow_mut().insert(file.clone());
+                        }
+                    });
+                    ignore_files_box.append(&checkbox);
+                }
+            });
+
+            let log_checkbox: gtk4::CheckButton = builder_clone
+                .object("log_searches")
+                .expect("Could not get log_searches");
+            log_checkbox.set_active(settings.get("log_searches").map_or(false, |value| value == "true"));
+            log_checkbox.connect_toggled(|checkbox| save_setting("log_searches", checkbox.is_active()));
+
             // Set initial values from config
             if !config_clone.paths.is_empty() {
                 path_entry.set_text(&config_clone.paths[0].to_string_lossy());
             }
             search_entry.set_text(&config_clone.query);
             pattern_entry.set_text(&config_clone.patterns.join(","));
+
+            // File types fill in the pattern entry, which stays editable for anything else
+            let file_type: gtk4::DropDown = builder_clone
+                .object("file_type")
+                .expect("Could not get file_type");
+            let mut type_names = vec!["Any type"];
+            type_names.extend(FILE_TYPES.iter().map(|(name, _)| *name));
+            file_type.set_model(Some(&gtk4::StringList::new(&type_names)));
+            // Connected after setting the model, so the command line patterns are kept
+            let pattern_entry_for_type = pattern_entry.clone();
+            file_type.connect_selected_notify(move |dropdown| {
+                let globs = match dropdown.selected() as usize {
+                    0 => vec!["*"],
+                    index => FILE_TYPES.get(index - 1).map_or(vec!["*"], |(_, globs)| globs.to_vec()),
+                };
+                pattern_entry_for_type.set_text(&globs.join(","));
+            });
             
             // Fix: Properly set the SpinButton value and range
             number_processes.set_range(0.0, 32.0);  // Allow 0 for auto-detection
             number_processes.set_increments(1.0, 4.0);  // Step by 1, page by 4
             number_processes.set_value(config_clone.num_workers as f64);
 
-            // Add tooltip to explain 0
-            number_processes.set_tooltip_text(Some("Number of worker threads (0 = automatic)"));
+            // Add tooltip to explain 0, with the count it stands for on this machine
+            number_processes.set_tooltip_text(Some(&format!(
+                "Number of worker threads (0 = automatic, {} here)", worker_threads(0))));
 
             number_lines.set_text(&config_clone.context_lines.to_string());
 
-            // Connect search button
-            let quit_search = Arc::new(AtomicBool::new(false));
+            // Results of the last search, kept for the replace preview
+            let last_results: Rc<RefCell<Vec<SearchResult>>> = Rc::new(RefCell::new(Vec::new()));
+            let last_config: Rc<RefCell<Option<SearchConfig>>> = Rc::new(RefCell::new(None));
+            let pending_replace: Rc<RefCell<Option<(Replacement, Vec<FileChanges>)>>> = Rc::new(RefCell::new(None));
+
+            let apply_button: gtk4::Button = builder_clone
+                .object("apply_button")
+                .expect("Could not get apply_button");
+
+            // Layout of the results view, kept in the GUI settings
+            let view_options = Rc::new(Cell::new(ViewOptions::load()));
+            // Formats the shown results and maps the view's lines back to them
+            let formatter = Rc::new(RefCell::new(ResultFormatter::new(view_options.get())));
+
+            // Connect search button, the running search is kept to cancel it
+            let running_search: Rc<RefCell<Option<SearchHandle>>> = Rc::new(RefCell::new(None));
 
             // Get both buttons
             let search_button: gtk4::Button = builder_clone
@@ -120,9 +417,11 @@ impl SearchGUI {
                 .expect("Could not get cancel_button");
 
             // Set up cancel button handler
-            let quit_search_for_cancel = quit_search.clone();
+            let running_search_for_cancel = running_search.clone();
             cancel_button.connect_clicked(move |button| {
-                quit_search_for_cancel.store(true, Ordering::Relaxed);
+                if let Some(handle) = running_search_for_cancel.borrow().as_ref() {
+                    handle.cancel();
+                }
                 button.set_sensitive(false);
             });
 
@@ -132,13 +431,36 @@ impl SearchGUI {
             let number_processes_clone = number_processes.clone();
             let number_lines_clone = number_lines.clone();
             let regex_checkbox_clone = regex_checkbox.clone();
+            let pdf_checkbox_clone = pdf_checkbox.clone();
+            let compressed_checkbox_clone = compressed_checkbox.clone();
+            let log_checkbox_clone = log_checkbox.clone();
+            let gitignore_checkbox_clone = gitignore_checkbox.clone();
+
+            // Settings without a widget are taken from the command line
+            let base_config = config_clone.clone();
 
             // Modify search button handler
             let builder_for_click = builder_clone.clone();
             let cancel_button_for_search = cancel_button.clone();
+            let last_results_for_search = last_results.clone();
+            let last_config_for_search = last_config.clone();
+            let pending_replace_for_search = pending_replace.clone();
+            let apply_button_for_search = apply_button.clone();
+            let view_options_for_search = view_options.clone();
+            let formatter_for_search = formatter.clone();
             search_button.connect_clicked(move |button| {
-                // Reset quit flag
-                quit_search.store(false, Ordering::Relaxed);
+                // An empty search text would list every line of every file
+                if search_entry_clone.text().is_empty() {
+                    let status_bar: gtk4::Label = builder_for_click
+                        .object("status_bar")
+                        .expect("Could not get status_bar");
+                    status_bar.set_label("Enter a search text first");
+                    return;
+                }
+
+                // A new search invalidates any pending replace
+                pending_replace_for_search.borrow_mut().take();
+                apply_button_for_search.set_sensitive(false);
                 
                 // Get status bar
                 let status_bar: gtk4::Label = builder_for_click
@@ -147,18 +469,16 @@ impl SearchGUI {
                 
                 // Clear previous results
                 buffer.set_text("");
+                last_results_for_search.borrow_mut().clear();
                 
-                // Update status to "Searching..."
-                status_bar.set_label("Searching...");
+                // Update status to "Searching...", with the threads 0 stands for
+                let threads = worker_threads(number_processes_clone.value() as usize);
+                status_bar.set_label(&format!("Searching with {} worker threads...", threads));
                 
                 // Prepare search config
-                let search_path = if path_entry_clone.text().is_empty() {
-                    // If no path entered, use current directory
-                    std::env::current_dir().unwrap_or_default()
-                } else {
-                    PathBuf::from(path_entry_clone.text().as_str())
-                };
+                let search_path = search_root(&path_entry_clone);
 
+                let view = view_options_for_search.get();
                 let search_config = SearchConfig {
                     paths: vec![search_path],  // Use the processed path
                     patterns: pattern_entry_clone.text()
@@ -174,72 +494,331 @@ impl SearchGUI {
                     verbose: false,
                     search_binary: false,
                     use_regex: regex_checkbox_clone.is_active(),
+                    search_pdfs: pdf_checkbox_clone.is_active(),
+                    search_compressed: compressed_checkbox_clone.is_active(),
+                    respect_gitignore: gitignore_checkbox_clone.is_active(),
+                    disabled_ignore_files: disabled_ignore_files.borrow().iter().cloned().collect(),
+                    // Stop the search once the view is full
+                    max_results: (view.max_results > 0).then_some(view.max_results),
+                    ..base_config.clone()
                 };
+                *last_config_for_search.borrow_mut() = Some(search_config.clone());
                 
                 // Disable search button, enable cancel button
                 button.set_sensitive(false);
                 cancel_button_for_search.set_sensitive(true);
                 
-                // Create channel for search results
-                let (tx, rx) = async_channel::bounded(1);
-                
-                // Prepare clones for the search thread
-                let quit_search_for_thread = quit_search.clone();
-                let search_config_for_thread = search_config.clone();
-                let tx_for_thread = tx.clone();
+                // Results stream in while the search runs on its own threads
+                let results_stream = match spawn_search(&search_config) {
+                    Ok(handle) => {
+                        let results_stream = handle.results();
+
+                        // The file count arrives while the search runs, unless it finishes first
+                        let candidates = handle.count_candidates(&search_config);
+                        let status_bar_for_count = status_bar.clone();
+                        let button_for_count = button.clone();
+                        glib::spawn_future_local(async move {
+                            if let Ok(count) = candidates.recv().await {
+                                if !button_for_count.is_sensitive() {
+                                    status_bar_for_count.set_label(&format!(
+                                        "Searching {} files with {} worker threads...", count, threads));
+                                }
+                            }
+                        });
+                        *running_search.borrow_mut() = Some(handle);
+                        results_stream
+                    }
+                    Err(e) => {
+                        let mut end = buffer.end_iter();
+                        buffer.insert(&mut end, &format!("Search error: {}\n", e));
+                        status_bar.set_label("Search failed");
+                        button.set_sensitive(true);
+                        cancel_button_for_search.set_sensitive(false);
+                        return;
+                    }
+                };
 
                 // Prepare clones for the results handler
                 let buffer_for_results = buffer.clone();
                 let status_bar_for_results = status_bar.clone();
                 let button_for_results = button.clone();
                 let cancel_button_for_results = cancel_button_for_search.clone();
+                let last_results_for_results = last_results_for_search.clone();
+                let config_for_history = log_checkbox_clone.is_active().then(|| search_config.clone());
 
-                // Spawn search thread
-                thread::spawn(move || {
-                    let results = search_files(&search_config_for_thread, quit_search_for_thread);
-                    let _ = tx_for_thread.try_send(results);
-                });
+                *formatter_for_search.borrow_mut() = ResultFormatter::new(view);
+                let formatter_for_results = formatter_for_search.clone();
 
-                // Handle results
+                // Handle results, kept as they arrive so the context menu finds them
                 glib::spawn_future_local(async move {
-                    if let Ok(results) = rx.recv().await {
-                        match results {
-                            Ok(results) => {
-                                // Update results in text view
-                                for result in &results {
-                                    let mut text = format!("File: {}:{}\n", result.path.display(), result.line_number);
-                                    
-                                    for (line_num, line) in &result.context_before {
-                                        text.push_str(&format!("{:>3} | {}\n", line_num, line));
-                                    }
-                                    
-                                    text.push_str(&format!(">{:>2} | {}\n", result.line_number, result.line));
-                                    
-                                    for (line_num, line) in &result.context_after {
-                                        text.push_str(&format!("{:>3} | {}\n", line_num, line));
-                                    }
-                                    
-                                    text.push('\n');
-                                    
-                                    let mut end = buffer_for_results.end_iter();
-                                    buffer_for_results.insert(&mut end, &text);
-                                }
+                    while let Ok(result) = results_stream.recv().await {
+                        let mut results = last_results_for_results.borrow_mut();
+                        // Update results in text view, the ones past the cap are only counted
+                        if !view.is_capped(results.len()) {
+                            let text = formatter_for_results.borrow_mut().format(&result);
+                            let mut end = buffer_for_results.end_iter();
+                            buffer_for_results.insert(&mut end, &text);
+                        }
+                        results.push(result);
+                    }
+
+                    // Update status bar with result count
+                    let found = last_results_for_results.borrow().len();
+                    status_bar_for_results.set_label(&view.status(found));
+                    if let Some(config) = &config_for_history {
+                        if let Err(e) = history::log_search(config, found) {
+                            status_bar_for_results.set_label(&format!("Could not write the search history: {}", e));
+                        }
+                    }
+                    
+                    // Re-enable search button, disable cancel button
+                    button_for_results.set_sensitive(true);
+                    cancel_button_for_results.set_sensitive(false);
+                });
+            });
+
+            // View options popover
+            let view_heading: gtk4::CheckButton = builder_clone
+                .object("view_heading")
+                .expect("Could not get view_heading");
+            let view_separator: gtk4::CheckButton = builder_clone
+                .object("view_separator")
+                .expect("Could not get view_separator");
+            let view_max_columns: gtk4::SpinButton = builder_clone
+                .object("view_max_columns")
+                .expect("Could not get view_max_columns");
+            let view_max_results: gtk4::SpinButton = builder_clone
+                .object("view_max_results")
+                .expect("Could not get view_max_results");
+
+            // Set before connecting the handlers, so loading doesn't save again
+            let initial_view = view_options.get();
+            view_heading.set_active(initial_view.heading);
+            view_separator.set_active(initial_view.separator);
+            view_max_columns.set_range(0.0, 10000.0);
+            view_max_columns.set_increments(10.0, 100.0);
+            view_max_columns.set_value(initial_view.max_columns as f64);
+            view_max_results.set_range(0.0, 10_000_000.0);
+            view_max_results.set_increments(1000.0, 10000.0);
+            view_max_results.set_value(initial_view.max_results as f64);
+            view_heading
+                .bind_property("active", &view_separator, "sensitive")
+                .sync_create()
+                .build();
 
-                                // Update status bar with result count
-                                status_bar_for_results.set_label(&format!("Found {} matching files", results.len()));
-                            },
+            let update_view = {
+                let view_heading = view_heading.clone();
+                let view_separator = view_separator.clone();
+                let view_max_columns = view_max_columns.clone();
+                let view_max_results = view_max_results.clone();
+                let view_options = view_options.clone();
+                let last_results = last_results.clone();
+                let formatter = formatter.clone();
+                let search_button = search_button.clone();
+                let buffer = results_view.buffer();
+                Rc::new(move || {
+                    let view = ViewOptions {
+                        heading: view_heading.is_active(),
+                        separator: view_separator.is_active(),
+                        max_columns: view_max_columns.value() as usize,
+                        max_results: view_max_results.value() as usize,
+                    };
+                    view_options.set(view);
+                    view.save();
+
+                    // Show the finished results again, a running search keeps its layout
+                    if search_button.is_sensitive() {
+                        let mut formatter = formatter.borrow_mut();
+                        *formatter = ResultFormatter::new(view);
+                        let results = last_results.borrow();
+                        let shown = if view.max_results > 0 { view.max_results } else { results.len() };
+                        let text: String = results.iter()
+                            .take(shown)
+                            .map(|result| formatter.format(result))
+                            .collect();
+                        buffer.set_text(&text);
+                    }
+                })
+            };
+            let update_view_for_heading = update_view.clone();
+            view_heading.connect_toggled(move |_| update_view_for_heading());
+            let update_view_for_separator = update_view.clone();
+            view_separator.connect_toggled(move |_| update_view_for_separator());
+            let update_view_for_columns = update_view.clone();
+            view_max_columns.connect_value_changed(move |_| update_view_for_columns());
+            view_max_results.connect_value_changed(move |_| update_view());
+
+            // Connect replace preview and apply buttons
+            let status_bar: gtk4::Label = builder_clone
+                .object("status_bar")
+                .expect("Could not get status_bar");
+
+            // Right click menu on a result line: copy it, copy its path or open its folder
+            let clicked_line: Rc<RefCell<Option<(PathBuf, String)>>> = Rc::new(RefCell::new(None));
+            let actions = gio::SimpleActionGroup::new();
+            let copy_line = gio::SimpleAction::new("copy-line", None);
+            let clicked_for_copy_line = clicked_line.clone();
+            let results_view_for_copy_line = results_view.clone();
+            copy_line.connect_activate(move |_, _| {
+                if let Some((_, line)) = clicked_for_copy_line.borrow().as_ref() {
+                    results_view_for_copy_line.clipboard().set_text(line);
+                }
+            });
+            let copy_path = gio::SimpleAction::new("copy-path", None);
+            let clicked_for_copy_path = clicked_line.clone();
+            let results_view_for_copy_path = results_view.clone();
+            copy_path.connect_activate(move |_, _| {
+                if let Some((path, _)) = clicked_for_copy_path.borrow().as_ref() {
+                    results_view_for_copy_path.clipboard().set_text(&path.to_string_lossy());
+                }
+            });
+            let open_folder = gio::SimpleAction::new("open-folder", None);
+            let clicked_for_open = clicked_line.clone();
+            let status_bar_for_open = status_bar.clone();
+            open_folder.connect_activate(move |_, _| {
+                let folder = match clicked_for_open.borrow().as_ref().and_then(|(path, _)| path.parent().map(PathBuf::from)) {
+                    Some(folder) if folder.as_os_str().is_empty() => PathBuf::from("."),
+                    Some(folder) => folder,
+                    None => return,
+                };
+                let uri = gio::File::for_path(&folder).uri();
+                if let Err(e) = gio::AppInfo::launch_default_for_uri(&uri, None::<&gio::AppLaunchContext>) {
+                    status_bar_for_open.set_label(&format!("Could not open {}: {}", folder.display(), e));
+                }
+            });
+            actions.add_action(&copy_line);
+            actions.add_action(&copy_path);
+            actions.add_action(&open_folder);
+            results_view.insert_action_group("results", Some(&actions));
+
+            let menu = gio::Menu::new();
+            menu.append(Some("Copy line"), Some("results.copy-line"));
+            menu.append(Some("Copy path"), Some("results.copy-path"));
+            menu.append(Some("Open containing folder"), Some("results.open-folder"));
+            let results_menu = gtk4::PopoverMenu::from_model(Some(&menu));
+            results_menu.set_parent(&results_view);
+            results_menu.set_has_arrow(false);
+
+            let right_click = gtk4::GestureClick::new();
+            right_click.set_button(gtk4::gdk::BUTTON_SECONDARY);
+            let results_view_for_click = results_view.clone();
+            let last_results_for_menu = last_results.clone();
+            right_click.connect_pressed(move |_, _, x, y| {
+                let (buffer_x, buffer_y) = results_view_for_click
+                    .window_to_buffer_coords(gtk4::TextWindowType::Widget, x as i32, y as i32);
+                let row = results_view_for_click.iter_at_location(buffer_x, buffer_y)
+                    .and_then(|iter| formatter.borrow().row(iter.line() as usize));
+                let results = last_results_for_menu.borrow();
+                let target = row.and_then(|(index, line_number)| {
+                    let result = results.get(index)?;
+                    Some((result.path.clone(), result_line(result, line_number)?.to_string()))
+                });
+                // Blank lines and separators belong to no result
+                if target.is_none() {
+                    return;
+                }
+                *clicked_line.borrow_mut() = target;
+                results_menu.set_pointing_to(Some(&gtk4::gdk::Rectangle::new(x as i32, y as i32, 1, 1)));
+                results_menu.popup();
+            });
+            results_view.add_controller(right_click);
+            let replace_entry: gtk4::Entry = builder_clone
+                .object("replace_entry")
+                .expect("Could not get replace_entry");
+            let preview_button: gtk4::Button = builder_clone
+                .object("preview_button")
+                .expect("Could not get preview_button");
+            let preview_window: gtk4::ScrolledWindow = builder_clone
+                .object("preview_window")
+                .expect("Could not get preview_window");
+            let preview_view: gtk4::TextView = builder_clone
+                .object("preview_view")
+                .expect("Could not get preview_view");
+            let preview_buffer = preview_view.buffer();
+
+            // Editing the replacement invalidates the previous preview
+            let pending_replace_for_edit = pending_replace.clone();
+            let apply_button_for_edit = apply_button.clone();
+            replace_entry.connect_changed(move |_| {
+                pending_replace_for_edit.borrow_mut().take();
+                apply_button_for_edit.set_sensitive(false);
+            });
+
+            let replace_entry_clone = replace_entry.clone();
+            let status_bar_for_preview = status_bar.clone();
+            let preview_buffer_for_preview = preview_buffer.clone();
+            let pending_replace_for_preview = pending_replace.clone();
+            let apply_button_for_preview = apply_button.clone();
+            preview_button.connect_clicked(move |_| {
+                let config = match last_config.borrow().clone() {
+                    Some(config) => config,
+                    None => {
+                        status_bar_for_preview.set_label("Run a search before previewing a replace");
+                        return;
+                    }
+                };
+
+                let replacement = match Replacement::new(&config, replace_entry_clone.text().as_str()) {
+                    Ok(replacement) => replacement,
+                    Err(e) => {
+                        status_bar_for_preview.set_label(&format!("Replace error: {}", e));
+                        return;
+                    }
+                };
+
+                let files = replace::plan(&last_results.borrow(), &replacement);
+
+                // Show each affected line before and after the replace
+                let mut text = String::new();
+                for file in &files {
+                    text.push_str(&format!("File: {}\n", file.path.display()));
+                    for change in &file.changes {
+                        text.push_str(&format!("-{:>width$} | {}\n", change.line_number, change.before, width = GUTTER_WIDTH - 1));
+                        text.push_str(&format!("+{:>width$} | {}\n", change.line_number, change.after, width = GUTTER_WIDTH - 1));
+                    }
+                    text.push('\n');
+                }
+                for path in replace::lossy_files(&last_results.borrow()) {
+                    text.push_str(&format!("Not replaced, not valid UTF-8: {}\n", path.display()));
+                }
+                preview_buffer_for_preview.set_text(&text);
+                preview_window.set_visible(true);
+
+                status_bar_for_preview.set_label(&format!(
+                    "{} matches in {} files will be replaced",
+                    replace::count_matches(&files),
+                    files.len()
+                ));
+                apply_button_for_preview.set_sensitive(!files.is_empty());
+                *pending_replace_for_preview.borrow_mut() = Some((replacement, files));
+            });
+
+            let status_bar_for_apply = status_bar.clone();
+            let preview_buffer_for_apply = preview_buffer.clone();
+            apply_button.connect_clicked(move |button| {
+                button.set_sensitive(false);
+
+                let pending = pending_replace.borrow_mut().take();
+                if let Some((replacement, files)) = pending {
+                    let mut replaced = 0;
+                    let mut written = 0;
+                    for file in &files {
+                        match replace::write_in_place(file, &replacement) {
+                            Ok(count) => {
+                                replaced += count;
+                                written += 1;
+                            }
                             Err(e) => {
-                                let mut end = buffer_for_results.end_iter();
-                                buffer_for_results.insert(&mut end, &format!("Search error: {}\n", e));
-                                status_bar_for_results.set_label("Search failed");
+                                let mut end = preview_buffer_for_apply.end_iter();
+                                preview_buffer_for_apply.insert(&mut end, &format!("Replace error: {}\n", e));
                             }
                         }
-                        
-                        // Re-enable search button, disable cancel button
-                        button_for_results.set_sensitive(true);
-                        cancel_button_for_results.set_sensitive(false);
                     }
-                });
+                    status_bar_for_apply.set_label(&format!(
+                        "Replaced {} matches in {} files (originals saved as .bak)",
+                        replaced, written
+                    ));
+                }
             });
 
             // Connect browse button
@@ -275,6 +854,20 @@ impl SearchGUI {
                 );
             });
         });
+
+        self.app.connect_activate(move |app| {
+            // Another window with its own widgets and searches, e.g. to compare
+            // the results of two queries side by side
+            let new_window = gio::SimpleAction::new("new-window", None);
+            let build_new_window = build_window.clone();
+            new_window.connect_activate(glib::clone!(@weak app => move |_, _| {
+                build_new_window(&app, &gtk4::Builder::from_file(UI_FILE));
+            }));
+            app.add_action(&new_window);
+            app.set_accels_for_action("app.new-window", &["<Control>n"]);
+
+            build_window(app, &builder_clone);
+        });
     }
 
     pub fn run(&self) -> i32 {
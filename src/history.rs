@@ -0,0 +1,43 @@
+use serde_json::json;
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use crate::config::history_path;
+use crate::SearchConfig;
+
+// Past this size the log is moved to `history.1`, replacing the older one
+const MAX_HISTORY_BYTES: u64 = 1 << 20;
+
+/// Appends a search to the history file in the config directory, for
+/// --log-searches and the GUI setting
+pub fn log_search(config: &SearchConfig, matches: usize) -> io::Result<()> {
+    match history_path() {
+        Some(path) => log_search_to(&path, config, matches),
+        None => Ok(()),
+    }
+}
+
+/// Appends one JSON line: time in seconds since the epoch, query, paths,
+/// patterns and match count
+pub fn log_search_to(path: &Path, config: &SearchConfig, matches: usize) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    if fs::metadata(path).map_or(false, |m| m.len() >= MAX_HISTORY_BYTES) {
+        fs::rename(path, path.with_extension("1"))?;
+    }
+
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let record = json!({
+        "time": time,
+        "query": config.query,
+        "paths": config.paths,
+        "patterns": config.patterns,
+        "matches": matches,
+    });
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", record)
+}
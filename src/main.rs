@@ -1,14 +1,174 @@
-use clap::Parser;
-use anyhow::Result;
+use clap::{Parser, ValueEnum};
+use anyhow::{Context, Result};
+use flate2::{write::GzEncoder, Compression};
+use std::cell::Cell;
+use std::collections::HashSet;
 use std::env;
-use std::path::PathBuf;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufWriter, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
 use std::sync::Arc;
-use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::Instant;
 
 mod search;
 mod gui;
+mod config;
+mod replace;
+mod matcher;
+mod output;
+mod watch;
+mod batch;
+mod benchmark;
+mod capture;
+mod colors;
+mod extract;
+mod extractors;
+mod git;
+mod history;
+mod structured;
+mod types;
+#[cfg(feature = "tui")]
+mod tui;
+#[cfg(test)]
+mod testutil;
 
-use search::{search_files, SearchResult};
+use capture::Capture;
+use config::UserConfig;
+use colors::ColorSpec;
+use extractors::Extractors;
+use git::ChangedLines;
+use replace::Replacement;
+use output::{print_diff, print_dir_summary, print_files_with_matches, print_files_without_match, print_json, print_json_pretty, print_markdown, print_paths, print_record0, print_results, print_search_result, print_stats_by_type, print_unique_lines, Markers, PrintOptions, ResultPrinter};
+use search::{list_files, search, FailedFiles, SearchedFiles, SearchResult, StatsByType, WalkErrors, DEFAULT_PDF_ARGS, DEFAULT_PDF_COMMAND};
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum ColorMode {
+    /// Color only when writing to a terminal
+    Auto,
+    Always,
+    Never,
+}
+
+/// How --mark underlines the matches, on a line of its own below the match
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum MarkStyle {
+    None,
+    /// `____` under each match
+    Underline,
+    /// `^^^^` under each match, like compiler diagnostics
+    Caret,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum PagerMode {
+    /// Page only when writing to a terminal
+    Auto,
+    Always,
+    Never,
+}
+
+/// How files containing NUL bytes are treated, see --binary-detection
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum BinaryMode {
+    /// Stop searching a file at its first NUL byte
+    Quit,
+    /// Replace NUL bytes with line terminators and keep searching
+    Convert,
+    /// Search the file as is
+    None,
+}
+
+/// When files are memory mapped instead of read in chunks, see --mmap
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum MmapMode {
+    /// Always read files in chunks
+    Never,
+    /// Memory map files of 1 GiB and more
+    Auto,
+    /// Memory map every file
+    Always,
+}
+
+/// Order in which files are handed to the workers with --sort-files
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SortFiles {
+    /// Path, alphabetically
+    Path,
+    /// Largest files first
+    Size,
+    /// Most recently modified first
+    Mtime,
+}
+
+/// Order of the files listed by --files-with-matches
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum SortList {
+    /// Path, alphabetically
+    Path,
+    /// Most matching lines first, needs --count
+    Count,
+}
+
+/// Parses an `ENCODING:GLOB` rule such as `latin1:*.txt`
+fn parse_encoding_rule(rule: &str) -> Result<(String, String), String> {
+    let (label, pattern) = rule.split_once(':')
+        .ok_or_else(|| format!("expected ENCODING:GLOB, got '{}'", rule))?;
+    grep::searcher::Encoding::new(label).map_err(|e| e.to_string())?;
+    glob::Pattern::new(pattern).map_err(|e| e.to_string())?;
+    Ok((label.to_string(), pattern.to_string()))
+}
+
+/// Checks a glob such as `node_modules` or `build-*`, kept as text
+fn parse_glob(pattern: &str) -> Result<String, String> {
+    glob::Pattern::new(pattern).map_err(|e| e.to_string())?;
+    Ok(pattern.to_string())
+}
+
+/// Checks an --extract-to-suffix, an empty one would overwrite the searched files
+fn parse_suffix(suffix: &str) -> Result<String, String> {
+    if suffix.is_empty() {
+        return Err("the suffix can't be empty, the searched files would be overwritten".to_string());
+    }
+    Ok(suffix.to_string())
+}
+
+/// Parses a byte count with an optional K, M, G or T suffix (powers of 1024)
+fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let (digits, multiplier) = match size.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&size[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&size[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&size[..i], 1 << 30),
+        Some((i, 'T' | 't')) => (&size[..i], 1 << 40),
+        _ => (size, 1),
+    };
+    digits.parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("expected a size like 500M or 2G, got '{}'", size))
+}
+
+/// Parses a percentage from 0 to 100, fractions allowed
+fn parse_percent(percent: &str) -> Result<f64, String> {
+    percent.trim().trim_end_matches('%').parse::<f64>()
+        .ok()
+        .filter(|percent| (0.0..=100.0).contains(percent))
+        .ok_or_else(|| format!("expected a percentage from 0 to 100, got '{}'", percent))
+}
+
+/// Parses the two --context-marker characters, for match and context lines
+fn parse_markers(markers: &str) -> Result<Markers, String> {
+    let mut chars = markers.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some(matched), Some(context), None) => Ok(Markers { matched, context }),
+        _ => Err(format!("expected two characters like \"+-\", got '{}'", markers)),
+    }
+}
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -17,18 +177,59 @@ pub struct Args {
     #[arg(short, long, default_value_t = false)]
     pub interactive: bool,
 
+    /// Launch terminal UI mode (requires the `tui` feature)
+    #[arg(long, default_value_t = false)]
+    pub tui: bool,
+
     /// Text to search for
     #[arg(short, long, required = false)]
     pub text: Option<String>,
 
-    /// File pattern to search in (e.g., "*.txt" or "*.{txt,md}")
+    /// File pattern to search in (e.g., "*.txt"), can be repeated.
+    /// Prefix with `!` to exclude (e.g., "!generated_*.rs"), use `\!` for a literal `!`.
+    /// Patterns with a `/` match the path below the search path, where `**` spans
+    /// directories (e.g., "src/**/*.rs", "**/test_*.rs"), the others the file name.
+    /// `{a,b}` matches either alternative (e.g., "*.{txt,md}"). Case sensitive on
+    /// every platform, see --iglob
     #[arg(short, long, default_value = "*")]
-    pub pattern: String,
+    pub pattern: Vec<String>,
+
+    /// Match -p and --dir-pattern globs ignoring case, so "*.{txt,md}" also
+    /// finds NOTES.TXT. Without it they are case sensitive on Linux, macOS and
+    /// Windows alike, whatever the file system does
+    #[arg(long)]
+    pub iglob: bool,
+
+    /// Only search files whose name matches REGEX, on top of -p. Without -t the
+    /// matching files are listed instead
+    #[arg(long = "name", value_name = "REGEX")]
+    pub name: Option<String>,
+
+    /// Directory name pattern (e.g., "src*"), can be repeated. Only directories that match,
+    /// or lie below one that matches, are walked. Prefix with `!` to skip directories
+    /// (e.g., "!target"). Files directly in the search paths are always searched
+    #[arg(long = "dir-pattern")]
+    pub dir_pattern: Vec<String>,
+
+    /// Never descend into directories with this name, can be repeated and take
+    /// a glob (e.g., "node_modules", "build-*"). Pruned while walking, at any
+    /// depth below the search paths, so nothing inside is ever read
+    #[arg(long = "exclude-dir", value_name = "NAME", value_parser = parse_glob)]
+    pub exclude_dir: Vec<String>,
 
     /// Number of worker threads (default: automatic based on CPU cores)
     #[arg(short = 'j', long = "jobs", default_value = "0")]
     pub workers: usize,
 
+    /// Threads walking directories and reading files, separate from matching.
+    /// Raise it for network filesystems (default: the -j value)
+    #[arg(long = "io-threads")]
+    pub io_threads: Option<usize>,
+
+    /// Threads matching file contents read by the IO threads (default: the -j value)
+    #[arg(long = "cpu-threads")]
+    pub cpu_threads: Option<usize>,
+
     /// Show verbose output including error messages
     #[arg(short, long, default_value_t = false)]
     pub verbose: bool,
@@ -40,21 +241,492 @@ pub struct Args {
     #[arg(short = 'c', long = "context", default_value_t = 0)]
     pub context: usize,
 
-    /// Use regex pattern for search
+    /// Allow an empty search text, which matches every line
+    #[arg(long = "match-empty", default_value_t = false)]
+    pub match_empty: bool,
+
+    /// Use regex pattern for search (ignored when -F is given)
     #[arg(short = 'r', long = "regex", default_value_t = false)]
     pub use_regex: bool,
+
+    /// Treat the search text as a literal string, this is the default and takes
+    /// precedence over -r
+    #[arg(short = 'F', long = "fixed-strings", default_value_t = false)]
+    pub fixed_strings: bool,
+
+    /// Let `.` match newlines, like the inline `(?s)` flag (regex mode only).
+    /// Lines are searched one at a time, so this only matters for patterns
+    /// that would otherwise stop at a line break
+    #[arg(long = "dotall", default_value_t = false)]
+    pub dot_all: bool,
+
+    /// Let `^` and `$` match at line boundaries, like the inline `(?m)` flag (regex mode only)
+    #[arg(long = "multiline-regex", default_value_t = false)]
+    pub multi_line: bool,
+
+    /// Ignore whitespace and allow `#` comments in the pattern, like the inline `(?x)` flag (regex mode only)
+    #[arg(long = "extended-regex", default_value_t = false)]
+    pub ignore_whitespace: bool,
+
+    /// Treat \r\n as the line ending, so `$` also matches before the \r of
+    /// Windows line endings. Printed lines never end in that \r, with or without it
+    #[arg(long = "trim-cr", default_value_t = false)]
+    pub trim_cr: bool,
+
+    /// Match the regex with PCRE2, which supports lookaround and backreferences.
+    /// Slower than the default engine, and needs a build with `--features pcre2`
+    #[arg(short = 'P', long = "pcre2", default_value_t = false, conflicts_with_all = ["fixed_strings", "replace", "replace_file", "capture"])]
+    pub pcre2: bool,
+
+    /// Characters marking match and context lines in front of the line number,
+    /// e.g. "+-" for a diff-like look
+    #[arg(long = "context-marker", value_name = "CHARS", default_value = "> ", value_parser = parse_markers)]
+    pub context_marker: Markers,
+
+    /// Character used as path separator in output (default: native separator)
+    #[arg(long = "path-separator")]
+    pub path_separator: Option<char>,
+
+    /// Decode files matching GLOB with ENCODING instead of UTF-8, e.g. "latin1:*.txt".
+    /// Can be repeated, the first matching rule wins
+    #[arg(long = "encoding", value_name = "ENCODING:GLOB", value_parser = parse_encoding_rule)]
+    pub encodings: Vec<(String, String)>,
+
+    /// Give each search path its own walker and N worker threads, so a small path
+    /// isn't starved by a huge one. Costs throughput, as idle workers of one path
+    /// can't help another (default: 0, all paths share the -j workers)
+    #[arg(long = "threads-per-path", default_value_t = 0)]
+    pub threads_per_path: usize,
+
+    /// Replace matches with TEXT and print a preview of the changed lines.
+    /// In regex mode $1 or ${name} insert capture groups, and \U or \L upper or
+    /// lower case the rest of the replacement up to \E (e.g. "\U$1\E")
+    #[arg(long, value_name = "TEXT", conflicts_with_all = ["jsonpath", "ocr"])]
+    pub replace: Option<String>,
+
+    /// Apply the `s/pattern/replacement/` rules from FILE in order to each matched line.
+    /// Without -t, every line one of the rules would change is searched
+    #[arg(long = "replace-file", value_name = "FILE", conflicts_with_all = ["replace", "jsonpath", "ocr"])]
+    pub replace_file: Option<PathBuf>,
+
+    /// Write replacements back to the files, keeping the originals as .bak
+    #[arg(long = "in-place", default_value_t = false)]
+    pub in_place: bool,
+
+    /// With --in-place, show the changes of each file and ask whether to write
+    /// them: y writes the file, n skips it, q stops. Answers are read from stdin
+    #[arg(long, default_value_t = false, requires = "in_place", conflicts_with = "no_messages")]
+    pub confirm: bool,
+
+    /// Preview replacements as `-` old and `+` new line pairs instead of the replaced lines
+    #[arg(long, default_value_t = false, conflicts_with = "in_place")]
+    pub diff: bool,
+
+    /// With --replace or --replace-file, print only how many replacements would be
+    /// made on how many lines and files. With -l, print each file as PATH:COUNT
+    /// of its matching lines, with --unique how often each line occurs
+    #[arg(long, default_value_t = false, conflicts_with_all = ["in_place", "diff"])]
+    pub count: bool,
+
+    /// Show only the first N changed lines of a replace preview or --diff, followed
+    /// by how many more there are. The totals still count every replacement
+    #[arg(long = "replace-preview-limit", value_name = "N", conflicts_with_all = ["in_place", "count"])]
+    pub replace_preview_limit: Option<usize>,
+
+    /// Color file names, line numbers and the match marker
+    #[arg(long, value_enum, default_value_t = ColorMode::Auto)]
+    pub color: ColorMode,
+
+    /// Change a color, can be repeated: COMPONENT:fg:COLOR, COMPONENT:bg:COLOR,
+    /// COMPONENT:style:bold|underline or COMPONENT:none. Components are path, line,
+    /// match, removed and added, colors black, red, green, yellow, blue, magenta,
+    /// cyan, white or 0-255
+    #[arg(long, value_name = "SPEC", value_parser = colors::parse_color_spec)]
+    pub colors: Vec<ColorSpec>,
+
+    /// Mark the matches on a line below each match line, readable without color
+    #[arg(long, value_enum, default_value_t = MarkStyle::None)]
+    pub mark: MarkStyle,
+
+    /// Send output through $PAGER (default: less -R), by default only when
+    /// writing to a terminal. With LESS unset the pager gets LESS=FRX like
+    /// git sets it: short output is printed directly and colors come through
+    #[arg(long, value_enum, default_value_t = PagerMode::Auto)]
+    pub pager: PagerMode,
+
+    /// Write the output to FILE instead of stdout, gzip compressed when the
+    /// name ends in .gz (e.g. results.jsonl.gz with --json)
+    #[arg(long = "output-file", value_name = "FILE", conflicts_with_all = ["pager", "confirm", "watch"])]
+    pub output_file: Option<PathBuf>,
+
+    /// Program used to extract text from PDFs (default: pdftotext, config key: pdf_command)
+    #[arg(long = "pdf-command")]
+    pub pdf_command: Option<String>,
+
+    /// Arguments for the PDF extractor, `{}` is replaced by the PDF path and the
+    /// path is appended if it is missing (default for pdftotext: "-q {} -", config key: pdf_args)
+    #[arg(long = "pdf-args", allow_hyphen_values = true)]
+    pub pdf_args: Option<String>,
+
+    /// Search PDF text verbatim: passes -raw to pdftotext (content stream order instead
+    /// of the reading layout) and keeps whitespace and form feeds in reported lines
+    #[arg(long = "pdf-raw", default_value_t = false)]
+    pub pdf_raw: bool,
+
+    /// Collapse runs of whitespace in PDF text to one space and drop blank lines
+    /// before matching, so context lines carry text. Line numbers count the kept lines
+    #[arg(long = "pdf-normalize", default_value_t = false, conflicts_with = "pdf_raw")]
+    pub pdf_normalize: bool,
+
+    /// Match PDF text by paragraph instead of by line: lines up to a blank line or
+    /// page break are joined with spaces, so a query can span a wrapped sentence.
+    /// The whole paragraph is reported, numbered by paragraph instead of line
+    #[arg(long, default_value_t = false, conflicts_with = "pdf_raw")]
+    pub paragraph: bool,
+
+    /// How to handle files containing NUL bytes: quit stops at the first one,
+    /// convert treats them as line breaks, none searches the bytes as is
+    #[arg(long = "binary-detection", value_enum, default_value_t = BinaryMode::Quit)]
+    pub binary_detection: BinaryMode,
+
+    /// Skip a file as binary only when more than PCT percent of its first
+    /// 64 KiB are NUL bytes. Other files are searched with NUL bytes read as
+    /// line breaks, like --binary-detection convert, so text with a few NULs
+    /// (e.g. some logs and exports) is still found
+    #[arg(long = "binary-threshold", value_name = "PCT", value_parser = parse_percent, conflicts_with = "binary_detection")]
+    pub binary_threshold: Option<f64>,
+
+    /// Stop searching once the searched files add up to more than this many bytes
+    /// (suffixes K, M, G and T are accepted, e.g. 10G)
+    #[arg(long = "max-total-bytes", value_parser = parse_size)]
+    pub max_total_bytes: Option<u64>,
+
+    /// Stop the search after N matches across all files. Workers count at the
+    /// same time, so up to one extra line per worker may be reported
+    #[arg(long = "max-matches-total", value_name = "N")]
+    pub max_matches_total: Option<usize>,
+
+    /// Report at most N matching lines per directory, counting only the files
+    /// directly in it, so one huge directory can't drown out the rest. Workers
+    /// search files at the same time, which of a directory's matches are kept
+    /// can change between runs
+    #[arg(long = "max-per-dir", value_name = "N")]
+    pub max_per_dir: Option<usize>,
+
+    /// Print only the value of this named capture group (e.g. `(?P<user>\w+)`),
+    /// one line per match. Needs a regex query (-r)
+    #[arg(long, value_name = "NAME", conflicts_with_all = ["replace", "replace_file", "queries_from"])]
+    pub capture: Option<String>,
+
+    /// Write the matching lines of each file (or the --capture values) to a file
+    /// next to it named with SUFFIX appended, e.g. foo.log -> foo.log.matches,
+    /// instead of printing them. Existing files are overwritten
+    #[arg(long = "extract-to-suffix", value_name = "SUFFIX", value_parser = parse_suffix,
+        conflicts_with_all = ["replace", "replace_file", "queries_from", "json", "json_pretty", "markdown", "files_without_match", "files_with_matches", "unique", "print0", "watch"])]
+    pub extract_to_suffix: Option<String>,
+
+    /// Memory map files instead of reading them in chunks: never, auto (files of
+    /// 1 GiB and more) or always. Faster on big files, but a file changing while
+    /// it is searched can crash the search
+    #[arg(long, value_enum, default_value_t = MmapMode::Never)]
+    pub mmap: MmapMode,
+
+    /// Don't print warnings and hints, errors are still reported
+    #[arg(long = "no-messages", default_value_t = false)]
+    pub no_messages: bool,
+
+    /// Search exactly the files listed in FILE, one per line, instead of walking
+    /// the paths. Use `-` to read the list from stdin
+    #[arg(long = "files-from", value_name = "FILE", conflicts_with = "files0_from")]
+    pub files_from: Option<PathBuf>,
+
+    /// Like --files-from, with the paths separated by NUL bytes (find -print0, fd -0)
+    #[arg(long = "files0-from", value_name = "FILE")]
+    pub files0_from: Option<PathBuf>,
+
+    /// Search only the files git tracks (`git ls-files`), skipping untracked
+    /// files even when they aren't ignored. Paths outside a git repository are walked as usual
+    #[arg(long = "git-tracked", default_value_t = false, conflicts_with_all = ["files_from", "files0_from"])]
+    pub git_tracked: bool,
+
+    /// Match only lines added or modified compared to REF (default HEAD, so
+    /// staged and unstaged changes), e.g. to check just your own changes.
+    /// Untracked files are skipped, fails outside a git repository
+    #[arg(long = "diff-only", value_name = "REF", num_args = 0..=1, default_missing_value = "HEAD",
+        conflicts_with_all = ["files_from", "files0_from", "git_tracked"])]
+    pub diff_only: Option<String>,
+
+    /// Search the files containing -t again for TEXT and print only those
+    /// matches, to narrow down in one command. Both texts use the same options
+    /// (-r, -F, -P, --dotall), a file list or --diff-only limits the first search
+    #[arg(long = "then-search", value_name = "TEXT", requires = "text",
+        conflicts_with_all = ["replace", "replace_file", "queries_from", "watch"])]
+    pub then_search: Option<String>,
+
+    /// Skip files ignored by git: .gitignore, the global excludes file
+    /// (core.excludesFile) and .git/info/exclude
+    #[arg(long, default_value_t = false)]
+    pub gitignore: bool,
+
+    /// With --gitignore, don't apply the global excludes file
+    #[arg(long = "no-global-ignore", default_value_t = false, requires = "gitignore")]
+    pub no_global_ignore: bool,
+
+    /// With --gitignore, don't apply .git/info/exclude
+    #[arg(long = "no-git-exclude", default_value_t = false, requires = "gitignore")]
+    pub no_git_exclude: bool,
+
+    /// Don't apply .quicksearchignore files, which are used even without --gitignore
+    #[arg(long = "no-ignore-dot", default_value_t = false)]
+    pub no_ignore_dot: bool,
+
+    /// Print results as JSON Lines, one `match` object per result and a final
+    /// `summary` object with the totals
+    #[arg(long, default_value_t = false, conflicts_with_all = ["replace", "replace_file", "capture", "queries_from"])]
+    pub json: bool,
+
+    /// Print all results as one pretty-printed JSON array, for reading by eye.
+    /// Unlike --json nothing is printed before the search is done
+    #[arg(long = "json-pretty", default_value_t = false, conflicts_with_all = ["json", "replace", "replace_file", "capture", "queries_from"])]
+    pub json_pretty: bool,
+
+    /// Print results as Markdown, a heading per file and a code block per match,
+    /// for pasting into issues and docs
+    #[arg(long, default_value_t = false, conflicts_with_all = ["json", "json_pretty", "replace", "replace_file", "capture", "queries_from"])]
+    pub markdown: bool,
+
+    /// Don't extract and search the text of PDF files
+    #[arg(long = "no-pdf", default_value_t = false)]
+    pub no_pdf: bool,
+
+    /// Search gzip compressed files (.gz) decompressed
+    #[arg(short = 'z', long = "search-compressed", default_value_t = false)]
+    pub search_compressed: bool,
+
+    /// Search the text of images (.png, .jpg, .jpeg, .tif, .tiff) recognized with
+    /// tesseract, which has to be installed. Slow, so only with this flag
+    #[arg(long, default_value_t = false)]
+    pub ocr: bool,
+
+    /// Match the values at a JSONPath (e.g. `$.servers[*].host`) in .json, .yaml
+    /// and .yml files instead of their lines. Each value is reported as
+    /// `location = value`. Files that don't parse and other files are searched
+    /// as text. Needs a build with `--features structured`
+    #[arg(long, value_name = "EXPR")]
+    pub jsonpath: Option<String>,
+
+    /// Print paths relative to the search path they were found under,
+    /// instead of as searched (which may be absolute)
+    #[arg(long, default_value_t = false)]
+    pub relative: bool,
+
+    /// Report results under their absolute path with symlinks resolved, a file
+    /// reached through several paths is searched once
+    #[arg(long, default_value_t = false)]
+    pub canonicalize: bool,
+
+    /// Report only the first match of each file, with its context, then move on
+    #[arg(long = "first-match", default_value_t = false)]
+    pub first_match: bool,
+
+    /// Print every match with its own full context, even where the context of
+    /// nearby matches overlaps. Shared lines are repeated in each block
+    #[arg(long = "no-context-merge", default_value_t = false, conflicts_with = "first_match")]
+    pub no_context_merge: bool,
+
+    /// Print only the searched files that contain no match
+    #[arg(short = 'L', long = "files-without-match", default_value_t = false, conflicts_with_all = ["replace", "replace_file", "capture", "json", "json_pretty", "markdown", "queries_from"])]
+    pub files_without_match: bool,
+
+    /// Print only the files that contain a match
+    #[arg(short = 'l', long = "files-with-matches", default_value_t = false, conflicts_with_all = ["replace", "replace_file", "capture", "json", "json_pretty", "markdown", "files_without_match", "queries_from"])]
+    pub files_with_matches: bool,
+
+    /// Print each distinct matching line once, whatever file or line it was found on.
+    /// With --count, each line follows its number of occurrences
+    #[arg(long, default_value_t = false, conflicts_with_all = ["replace", "replace_file", "capture", "json", "json_pretty", "markdown", "files_without_match", "files_with_matches", "print0", "queries_from"])]
+    pub unique: bool,
+
+    /// Print no matches, only a tree of the directories with matches and the
+    /// number of matching lines in and below each, busiest first
+    #[arg(long = "summary-only", default_value_t = false, conflicts_with_all = ["replace", "replace_file", "capture", "json", "json_pretty", "markdown", "files_without_match", "files_with_matches", "unique", "print0", "queries_from", "extract_to_suffix"])]
+    pub summary_only: bool,
+
+    /// Order of the files listed by -l, `count` lists the files with the most
+    /// matching lines first and needs --count
+    #[arg(long, value_enum, default_value_t = SortList::Path, requires = "files_with_matches")]
+    pub sort: SortList,
+
+    /// End each match record, with its header and context lines, with a NUL byte
+    /// instead of printing it as several lines. Newlines within a record are
+    /// written as \n and backslashes as \\, so records can be split on NUL
+    #[arg(long, default_value_t = false, conflicts_with_all = ["replace", "replace_file", "capture", "json", "json_pretty", "markdown", "files_without_match", "files_with_matches", "queries_from"])]
+    pub print0: bool,
+
+    /// Print files, bytes and time searched per file extension
+    #[arg(long = "stats-by-type", default_value_t = false)]
+    pub stats_by_type: bool,
+
+    /// After the initial search, keep watching the paths and print new matches in
+    /// changed files. Runs until interrupted with Ctrl-C
+    #[arg(long, default_value_t = false, conflicts_with_all = ["replace", "replace_file", "pager"])]
+    pub watch: bool,
+
+    /// Run every query in FILE instead of --text, one per line or as JSON objects
+    /// like {"id": "q1", "query": "needle"}. Objects can add a "glob" and an "encoding"
+    /// to search only matching files, decoded as given. Results are grouped by query id
+    #[arg(long = "queries-from", value_name = "FILE", conflicts_with_all = ["text", "replace", "replace_file", "watch"])]
+    pub queries_from: Option<PathBuf>,
+
+    /// Walk all paths first and search files in this order instead of as they
+    /// are found. The walk is sequential and searching only starts once it is done
+    #[arg(long = "sort-files", value_enum)]
+    pub sort_files: Option<SortFiles>,
+
+    /// Print results in a reproducible order: files as a sorted sequential walk
+    /// (or --sort-files) finds them, lines in order. Output starts once the search is done
+    #[arg(long, default_value_t = false)]
+    pub ordered: bool,
+
+    /// Append the query, paths, patterns and match count of this search to
+    /// the history file in the config directory
+    #[arg(long = "log-searches", default_value_t = false)]
+    pub log_searches: bool,
+
+    /// Search the paths with 1, 2, 4, ... worker threads and print the files/s
+    /// and MB/s of each, to pick -j for this machine and file system
+    #[arg(long, hide = true, default_value_t = false)]
+    pub benchmark: bool,
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SearchConfig {
     pub paths: Vec<PathBuf>,
     pub patterns: Vec<String>,
+    /// `patterns` and `dir_patterns` match ignoring case
+    pub glob_ignore_case: bool,
+    /// Regex file names must match, in addition to `patterns`
+    pub name_pattern: Option<String>,
+    /// Files to search instead of walking `paths`
+    pub files: Option<Vec<PathBuf>>,
+    /// Directory name patterns pruning the walk, empty walks everything
+    pub dir_patterns: Vec<String>,
+    /// Directory name globs the walk never enters
+    pub exclude_dirs: Vec<String>,
+    /// Skip files ignored by .gitignore
+    pub respect_gitignore: bool,
+    /// .gitignore files not to apply, only with `respect_gitignore`. The GUI
+    /// switches them off one by one, see `search::find_gitignore_files`
+    pub disabled_ignore_files: Vec<PathBuf>,
+    /// Also apply the global excludes file, only with `respect_gitignore`
+    pub git_global: bool,
+    /// Also apply .git/info/exclude, only with `respect_gitignore`
+    pub git_exclude: bool,
+    /// Skip files matched by .quicksearchignore files
+    pub respect_ignore_file: bool,
     pub query: String,
     pub verbose: bool,
     pub context_lines: usize,
     pub search_binary: bool,
+    /// Ignored when `search_binary` is set, which always searches everything
+    pub binary_detection: BinaryMode,
+    /// Percentage of NUL bytes at the start of a file above which it is skipped
+    /// as binary, replaces `binary_detection` when set
+    pub binary_threshold: Option<f64>,
     pub num_workers: usize,
+    pub threads_per_path: usize,
+    /// Setting either splits reading files from matching them, the other one
+    /// defaults to `num_workers`
+    pub io_threads: Option<usize>,
+    pub cpu_threads: Option<usize>,
+    /// Search files in this order, None searches them as the walker finds them
+    pub sort_files: Option<SortFiles>,
+    /// Walk sequentially in a fixed order, results carry their file's position
+    pub ordered: bool,
     pub use_regex: bool,
+    pub dot_all: bool,
+    pub multi_line: bool,
+    pub ignore_whitespace: bool,
+    /// Lines end in \r\n or \n, `$` matches before either
+    pub crlf: bool,
+    /// Regex queries use PCRE2 instead of the default engine
+    pub pcre2: bool,
+    /// An empty query is searched instead of rejected
+    pub match_empty: bool,
+    /// Named capture group printed instead of the matching lines
+    pub capture: Option<String>,
+    pub pdf_command: String,
+    pub pdf_args: Vec<String>,
+    pub pdf_raw: bool,
+    /// Collapse whitespace and drop blank lines of extracted PDF text
+    pub pdf_normalize: bool,
+    /// Match extracted PDF text by paragraph, results are numbered by paragraph
+    pub pdf_paragraphs: bool,
+    pub search_pdfs: bool,
+    /// Search .gz files decompressed
+    pub search_compressed: bool,
+    /// Search the text recognized in images
+    pub ocr: bool,
+    /// JSONPath whose values are matched in JSON and YAML files
+    pub jsonpath: Option<String>,
+    /// Extractors for other file formats, by extension. They replace the
+    /// built-in ones (PDF) for the same extension
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub extractors: Extractors,
+    /// `(encoding, glob)` pairs selecting the encoding per file
+    pub encodings: Vec<(String, String)>,
+    /// Number of files searched so far, shared with the worker threads
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub files_processed: Arc<AtomicUsize>,
+    /// Total size of the files searched so far, shared with the worker threads
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub bytes_searched: Arc<AtomicU64>,
+    /// Directories and files the walker could not read
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub walk_errors: Arc<WalkErrors>,
+    /// Files whose search failed part way, the rest of the search goes on
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub failed_files: FailedFiles,
+    /// Only matches on these lines count, for --diff-only
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub changed_lines: Option<Arc<ChangedLines>>,
+    /// Skips `.bak` files, so an in-place replace never rewrites its own backups
+    pub skip_backups: bool,
+    /// The search stops once `bytes_searched` exceeds this
+    pub max_total_bytes: Option<u64>,
+    /// Match occurrences found so far, counted only with `max_matches_total`
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub matches_found: Arc<AtomicUsize>,
+    /// The search stops once `matches_found` reaches this
+    pub max_matches_total: Option<usize>,
+    /// Results reported per directory at most, files of a full directory are skipped
+    pub max_per_dir: Option<usize>,
+    /// The search stops once this many results were found
+    pub max_results: Option<usize>,
+    /// Bounds the results waiting for the consumer, in batches of up to 256
+    /// results of one file. Workers block once it's full, so a slow consumer
+    /// slows the search down instead of filling memory. Unbounded when `None`.
+    ///
+    /// Setting the quit flag doesn't wake blocked workers: after cancelling,
+    /// keep reading until the results end or drop them, otherwise the search
+    /// threads never finish
+    pub result_buffer: Option<usize>,
+    pub mmap: MmapMode,
+    /// Suppresses warnings and hints
+    pub no_messages: bool,
+    /// Per-extension counters, collected only when requested
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub stats_by_type: Option<StatsByType>,
+    /// Stop searching a file after its first match
+    pub first_match: bool,
+    /// Nearby matches share context lines instead of each getting all of them
+    pub merge_context: bool,
+    /// Results use resolved paths and each file is searched once
+    pub canonicalize: bool,
+    /// Every searched path, kept only for -L since it grows with the tree
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub searched_files: Option<SearchedFiles>,
 }
 
 impl Default for SearchConfig {
@@ -62,66 +734,606 @@ impl Default for SearchConfig {
         Self {
             paths: Vec::new(),
             patterns: Vec::new(),
+            glob_ignore_case: false,
+            name_pattern: None,
+            files: None,
+            dir_patterns: Vec::new(),
+            exclude_dirs: Vec::new(),
+            respect_gitignore: false,
+            disabled_ignore_files: Vec::new(),
+            git_global: true,
+            git_exclude: true,
+            respect_ignore_file: true,
             query: String::new(),
             num_workers: 0,
+            threads_per_path: 0,
+            io_threads: None,
+            cpu_threads: None,
+            sort_files: None,
+            ordered: false,
             context_lines: 0,
             search_binary: false,
+            binary_detection: BinaryMode::Quit,
+            binary_threshold: None,
             verbose: false,
             use_regex: false,
+            dot_all: false,
+            multi_line: false,
+            crlf: false,
+            ignore_whitespace: false,
+            pcre2: false,
+            match_empty: false,
+            capture: None,
+            pdf_command: DEFAULT_PDF_COMMAND.to_string(),
+            pdf_args: DEFAULT_PDF_ARGS.iter().map(|arg| arg.to_string()).collect(),
+            pdf_raw: false,
+            pdf_normalize: false,
+            pdf_paragraphs: false,
+            search_pdfs: true,
+            search_compressed: false,
+            ocr: false,
+            jsonpath: None,
+            extractors: Extractors::default(),
+            encodings: Vec::new(),
+            files_processed: Arc::new(AtomicUsize::new(0)),
+            bytes_searched: Arc::new(AtomicU64::new(0)),
+            walk_errors: Arc::default(),
+            failed_files: Arc::default(),
+            changed_lines: None,
+            skip_backups: false,
+            max_total_bytes: None,
+            matches_found: Arc::new(AtomicUsize::new(0)),
+            max_matches_total: None,
+            max_per_dir: None,
+            max_results: None,
+            result_buffer: None,
+            mmap: MmapMode::Never,
+            no_messages: false,
+            stats_by_type: None,
+            first_match: false,
+            merge_context: true,
+            canonicalize: false,
+            searched_files: None,
         }
     }
 }
 
 impl SearchConfig {
-    fn get_search_path(&self) -> String {
-        self.paths.first()
-            .map(|p| p.to_string_lossy().to_string())
-            .unwrap_or_else(|| ".".to_string())
+    /// Paths to search, the current directory if none were given
+    fn search_roots(&self) -> Vec<PathBuf> {
+        if self.paths.is_empty() {
+            vec![PathBuf::from(".")]
+        } else {
+            self.paths.clone()
+        }
     }
 
     fn from_args(args: &Args, text: String) -> Self {
+        let user_config = UserConfig::load();
+
+        let pdf_command = args.pdf_command.clone()
+            .or_else(|| user_config.get("pdf_command").map(String::from))
+            .unwrap_or_else(|| DEFAULT_PDF_COMMAND.to_string());
+        let pdf_args = match args.pdf_args.as_deref().or(user_config.get("pdf_args")) {
+            Some(pdf_args) => pdf_args.split_whitespace().map(String::from).collect(),
+            // The default arguments only fit the default extractor
+            None if pdf_command == DEFAULT_PDF_COMMAND => {
+                DEFAULT_PDF_ARGS.iter().map(|arg| arg.to_string()).collect()
+            }
+            None => Vec::new(),
+        };
+
         Self {
             paths: args.paths.clone(),
-            patterns: vec![args.pattern.clone()],
+            patterns: args.pattern.clone(),
+            glob_ignore_case: args.iglob,
+            name_pattern: args.name.clone(),
+            // Read by run_cli, so building a config never touches stdin
+            files: None,
+            dir_patterns: args.dir_pattern.clone(),
+            exclude_dirs: args.exclude_dir.clone(),
+            respect_gitignore: args.gitignore,
+            disabled_ignore_files: Vec::new(),
+            git_global: !args.no_global_ignore,
+            git_exclude: !args.no_git_exclude,
+            respect_ignore_file: !args.no_ignore_dot,
             query: text,
             verbose: args.verbose,
             context_lines: args.context,
             search_binary: false,
+            binary_detection: args.binary_detection,
+            binary_threshold: args.binary_threshold,
             num_workers: args.workers,
-            use_regex: args.use_regex,
+            threads_per_path: args.threads_per_path,
+            io_threads: args.io_threads,
+            cpu_threads: args.cpu_threads,
+            sort_files: args.sort_files,
+            ordered: args.ordered,
+            // -F wins over -r
+            // -P implies a regex query
+            use_regex: (args.use_regex || args.pcre2) && !args.fixed_strings,
+            dot_all: args.dot_all,
+            multi_line: args.multi_line,
+            crlf: args.trim_cr,
+            ignore_whitespace: args.ignore_whitespace,
+            pcre2: args.pcre2,
+            match_empty: args.match_empty,
+            capture: args.capture.clone(),
+            pdf_command,
+            pdf_args,
+            pdf_raw: args.pdf_raw,
+            pdf_normalize: args.pdf_normalize,
+            pdf_paragraphs: args.paragraph,
+            search_pdfs: !args.no_pdf,
+            search_compressed: args.search_compressed,
+            ocr: args.ocr,
+            jsonpath: args.jsonpath.clone(),
+            extractors: Extractors::default(),
+            encodings: args.encodings.clone(),
+            files_processed: Arc::new(AtomicUsize::new(0)),
+            bytes_searched: Arc::new(AtomicU64::new(0)),
+            walk_errors: Arc::default(),
+            failed_files: Arc::default(),
+            changed_lines: None,
+            skip_backups: args.in_place,
+            max_total_bytes: args.max_total_bytes,
+            matches_found: Arc::new(AtomicUsize::new(0)),
+            max_matches_total: args.max_matches_total,
+            max_per_dir: args.max_per_dir,
+            max_results: None,
+            result_buffer: None,
+            mmap: args.mmap,
+            no_messages: args.no_messages,
+            stats_by_type: if args.stats_by_type {
+                Some(StatsByType::default())
+            } else {
+                None
+            },
+            first_match: args.first_match,
+            merge_context: !args.no_context_merge,
+            canonicalize: args.canonicalize,
+            searched_files: if args.files_without_match {
+                Some(SearchedFiles::default())
+            } else {
+                None
+            },
         }
     }
 }
 
-fn print_search_result(result: &SearchResult) {
-    println!("File: {}:{}", result.path.display(), result.line_number);
-    
-    // Print context before
-    for (line_num, line) in &result.context_before {
-        println!("{:>3} | {}", line_num, line);
+/// Reads a list of paths separated by `separator` from `source`, `-` is stdin
+fn read_file_list(source: &Path, separator: u8) -> Result<Vec<PathBuf>> {
+    let mut content = Vec::new();
+    if source == Path::new("-") {
+        io::stdin().lock().read_to_end(&mut content)?;
+    } else {
+        content = fs::read(source)
+            .with_context(|| format!("Failed to read file list {}", source.display()))?;
     }
-    
-    // Print matching line with '>' indicator
-    println!(">{:>2} | {}", result.line_number, result.line);
-    
-    // Print context after
-    for (line_num, line) in &result.context_after {
-        println!("{:>3} | {}", line_num, line);
+
+    Ok(content.split(|&b| b == separator)
+        .map(|path| String::from_utf8_lossy(path).trim_end_matches('\r').to_string())
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+        .collect())
+}
+
+/// Where --output-file writes, through gzip for names ending in .gz
+enum OutputFile {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+}
+
+impl OutputFile {
+    fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        let file = BufWriter::new(file);
+        Ok(if path.extension().map_or(false, |ext| ext == "gz") {
+            OutputFile::Gzip(GzEncoder::new(file, Compression::default()))
+        } else {
+            OutputFile::Plain(file)
+        })
+    }
+
+    /// Writes what is buffered and the gzip trailer. Dropping does the same
+    /// but hides errors, e.g. a full disk
+    fn finish(self) -> io::Result<()> {
+        match self {
+            OutputFile::Plain(mut file) => file.flush(),
+            OutputFile::Gzip(gzip) => gzip.finish()?.flush(),
+        }
+    }
+}
+
+impl Write for OutputFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            OutputFile::Plain(file) => file.write(buf),
+            OutputFile::Gzip(gzip) => gzip.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            OutputFile::Plain(file) => file.flush(),
+            OutputFile::Gzip(gzip) => gzip.flush(),
+        }
+    }
+}
+
+/// Starts $PAGER with a piped stdin, or returns None to print directly
+fn spawn_pager(mode: PagerMode) -> Option<Child> {
+    let use_pager = match mode {
+        PagerMode::Always => true,
+        PagerMode::Auto => io::stdout().is_terminal(),
+        PagerMode::Never => false,
+    };
+    if !use_pager {
+        return None;
+    }
+
+    let pager = env::var("PAGER").unwrap_or_else(|_| "less -R".to_string());
+    let mut parts = pager.split_whitespace();
+    let program = parts.next()?;
+
+    let mut command = Command::new(program);
+    command.args(parts).stdin(Stdio::piped());
+    // Colors are on for the terminal, a plain `less` from $PAGER would show
+    // the escape codes
+    if env::var_os("LESS").is_none() {
+        command.env("LESS", "FRX");
+    }
+    // A missing pager falls back to printing directly
+    command.spawn().ok()
+}
+
+/// Builds the replace rules from --replace or --replace-file. A script given
+/// without -t searches for every line one of its rules would change.
+fn load_replacement(args: &Args, config: &mut SearchConfig) -> Result<Option<Replacement>> {
+    if let Some(path) = &args.replace_file {
+        let script = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read replace script {}", path.display()))?;
+        let replacement = Replacement::from_script(&script)
+            .with_context(|| format!("Invalid replace script {}", path.display()))?;
+
+        if config.query.is_empty() {
+            config.query = replacement.combined_pattern();
+            config.use_regex = true;
+        }
+        return Ok(Some(replacement));
+    }
+
+    args.replace.as_deref()
+        .map(|text| Replacement::new(config, text))
+        .transpose()
+}
+
+/// Asks whether to write the file whose changes were just shown, until the
+/// answer is y, n or q. `None` means quit, so does the end of the input
+fn ask_replace(out: &mut dyn Write, input: &mut dyn BufRead) -> io::Result<Option<bool>> {
+    loop {
+        write!(out, "Replace in this file? [y,n,q] ")?;
+        out.flush()?;
+        let mut answer = String::new();
+        if input.read_line(&mut answer)? == 0 {
+            return Ok(None);
+        }
+        match answer.trim() {
+            "y" | "Y" => return Ok(Some(true)),
+            "n" | "N" => return Ok(Some(false)),
+            "q" | "Q" => return Ok(None),
+            _ => writeln!(out, "y - write this file, n - skip it, q - stop without writing the rest")?,
+        }
+    }
+}
+
+/// The "... and M more" after a preview cut short by --replace-preview-limit
+fn write_more(out: &mut dyn Write, lines: usize, limit: Option<usize>) -> io::Result<()> {
+    match limit {
+        Some(limit) if lines > limit => writeln!(out, "... and {} more", lines - limit),
+        _ => Ok(()),
+    }
+}
+
+fn write_replace(out: &mut dyn Write, results: Vec<SearchResult>, replacement: &Replacement, args: &Args, options: &PrintOptions) -> io::Result<()> {
+    let files = replace::plan(&results, replacement);
+    let matches = replace::count_matches(&files);
+    if !args.no_messages {
+        for path in replace::lossy_files(&results) {
+            eprintln!("Warning: {} is not valid UTF-8, its matches are not replaced", path.display());
+        }
+    }
+
+    if args.in_place {
+        let mut replaced = 0;
+        let mut written = 0;
+        let mut input = io::stdin().lock();
+        for file in &files {
+            if args.confirm {
+                print_diff(out, std::slice::from_ref(file), options)?;
+                match ask_replace(out, &mut input)? {
+                    Some(true) => {}
+                    Some(false) => continue,
+                    None => break,
+                }
+            }
+            match replace::write_in_place(file, replacement) {
+                Ok(count) => {
+                    replaced += count;
+                    written += 1;
+                }
+                Err(e) => eprintln!("Replace error: {:#}", e),
+            }
+        }
+        writeln!(out, "Replaced {} matches in {} files (originals saved as .bak)", replaced, written)?;
+    } else if args.count {
+        let lines: usize = files.iter().map(|file| file.changes.len()).sum();
+        writeln!(out, "{} replacements on {} lines in {} files", matches, lines, files.len())?;
+    } else if args.diff {
+        match args.replace_preview_limit {
+            Some(limit) => print_diff(out, &replace::limit_changes(&files, limit), options)?,
+            None => print_diff(out, &files, options)?,
+        }
+        let lines: usize = files.iter().map(|file| file.changes.len()).sum();
+        write_more(out, lines, args.replace_preview_limit)?;
+        writeln!(out, "{} replacements in {} files", matches, files.len())?;
+    } else {
+        // Preview: the matched lines as they would look after the replace
+        let mut preview: Vec<SearchResult> = results.into_iter()
+            .filter(|result| replace::is_writable(result))
+            .filter_map(|mut result| {
+                let (after, count) = replacement.apply(&result.line);
+                result.line = after;
+                (count > 0).then_some(result)
+            })
+            .collect();
+        let lines = preview.len();
+        if let Some(limit) = args.replace_preview_limit {
+            preview.truncate(limit);
+        }
+        print_results(out, &preview, options)?;
+        write_more(out, lines, args.replace_preview_limit)?;
+        writeln!(out, "{} replacements in {} files", matches, files.len())?;
     }
-    
-    // Empty line between files
-    println!();
+
+    // Per-rule counts for replace scripts
+    if args.replace_file.is_some() {
+        let patterns = replacement.rule_patterns();
+        let counts = replace::count_rule_matches(&files, patterns.len());
+        for (pattern, count) in patterns.iter().zip(counts) {
+            writeln!(out, "{:>8}  s/{}/", count, pattern)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn write_output(out: &mut dyn Write, results: Vec<SearchResult>, replacement: Option<&Replacement>, args: &Args, config: &SearchConfig) -> io::Result<()> {
+    let options = &PrintOptions::from_args(args);
+    match replacement {
+        Some(replacement) => write_replace(out, results, replacement, args, options)?,
+        None if args.print0 => results.iter().try_for_each(|result| print_record0(out, result, options))?,
+        None => print_results(out, &results, options)?,
+    }
+
+    if let Some(stats_by_type) = &config.stats_by_type {
+        print_stats_by_type(out, stats_by_type, config.files_processed.load(Ordering::Relaxed))?;
+    }
+
+    out.flush()
+}
+
+/// Prints each result as soon as the search finds it, nothing is buffered
+fn write_stream(
+    out: &mut dyn Write,
+    results: impl Iterator<Item = SearchResult>,
+    seen: &mut HashSet<watch::MatchKey>,
+    args: &Args,
+    config: &SearchConfig,
+) -> io::Result<()> {
+    let options = PrintOptions::from_args(args);
+    let mut printer = ResultPrinter::new(&options);
+    for result in results {
+        if args.print0 {
+            print_record0(out, &result, &options)?;
+        } else {
+            printer.print(out, &result)?;
+        }
+        if args.watch {
+            seen.insert(watch::match_key(&result));
+        }
+    }
+    printer.finish(out)?;
+
+    if let Some(stats_by_type) = &config.stats_by_type {
+        print_stats_by_type(out, stats_by_type, config.files_processed.load(Ordering::Relaxed))?;
+    }
+
+    out.flush()
 }
 
 fn run_cli(mut args: Args) -> Result<()> {
     let quit = Arc::new(AtomicBool::new(false));
+
+    // Ctrl-C stops the search, results found so far are still printed
+    let quit_for_signal = quit.clone();
+    ctrlc::set_handler(move || quit_for_signal.store(true, Ordering::Relaxed))?;
+
     // Take ownership of text before borrowing args
     let text = args.text.take().unwrap_or_default();
-    let config = SearchConfig::from_args(&args, text);
-    let results = search_files(&config, quit)?;
-    
-    for result in results {
-        print_search_result(&result);
+    let mut config = SearchConfig::from_args(&args, text);
+    if let Some(source) = &args.files_from {
+        config.files = Some(read_file_list(source, b'\n')?);
+    } else if let Some(source) = &args.files0_from {
+        config.files = Some(read_file_list(source, b'\0')?);
+    } else if args.git_tracked {
+        config.files = search::git_tracked_files(&config)?;
+        if config.files.is_none() && !config.no_messages {
+            eprintln!("Warning: not in a git repository, --git-tracked searches all files");
+        }
+    } else if let Some(base) = &args.diff_only {
+        let changed = git::changed_lines(&config.search_roots(), Some(base))?;
+        config.files = Some(search::changed_files(&config, &changed)?);
+        config.changed_lines = Some(Arc::new(changed));
+    }
+    if let Some(second) = &args.then_search {
+        config.files = Some(search::files_matching(&config, quit.clone())?);
+        config.query = second.clone();
+    }
+
+    // Load replace rules first, so a malformed script fails before searching
+    let replacement = load_replacement(&args, &mut config)?;
+    // The answers to --confirm come from stdin as well
+    let list_on_stdin = |source: &Option<PathBuf>| source.as_deref() == Some(Path::new("-"));
+    if args.confirm && (list_on_stdin(&args.files_from) || list_on_stdin(&args.files0_from)) {
+        anyhow::bail!("--confirm reads answers from stdin, it can't be combined with a file list from stdin");
+    }
+    if args.count && replacement.is_none() && !args.files_with_matches && !args.unique {
+        anyhow::bail!("--count needs -l, --unique, --replace or --replace-file");
+    }
+    if args.replace_preview_limit.is_some() && replacement.is_none() {
+        anyhow::bail!("--replace-preview-limit needs --replace or --replace-file");
+    }
+    if args.sort == SortList::Count && !args.count {
+        anyhow::bail!("--sort count needs --count");
+    }
+    // An unknown group name fails before searching as well
+    let capture = match &config.capture {
+        Some(name) => Some(Capture::new(&config, name)?),
+        None => None,
+    };
+    let started = Instant::now();
+    let batches = match &args.queries_from {
+        Some(path) => Some(batch::search_queries(&config, batch::load_queries(path)?, quit.clone())?),
+        None => None,
+    };
+    // --name without a search text lists the matching files
+    let names_only = config.name_pattern.is_some() && config.query.is_empty() && !config.match_empty;
+    let found = match batches {
+        Some(_) => None,
+        None if names_only => None,
+        None => Some(search(&config, quit.clone())?),
+    };
+    // Counted as results pass by, for the search history
+    let matches = Cell::new(0);
+    let found = found.map(|results| results.inspect(|_| matches.set(matches.get() + 1)));
+    let searched = found.is_some();
+    // Plain output is printed as results arrive, the other modes need all of them.
+    // --sort-files only orders the files handed to the workers, so it buffers too
+    let streaming = found.is_some() && capture.is_none() && replacement.is_none() && args.extract_to_suffix.is_none()
+        && !args.json && !args.json_pretty && !args.markdown && !args.files_without_match && !args.files_with_matches && !args.unique && !args.summary_only && args.sort_files.is_none() && !args.ordered;
+
+    // Prompts have to reach the terminal, not a pager, and a pager would hold
+    // back --watch output until it ends
+    let no_pager = args.confirm || args.watch || args.output_file.is_some();
+    let mut pager = spawn_pager(if no_pager { PagerMode::Never } else { args.pager });
+    let mut output_file = args.output_file.as_deref().map(OutputFile::create).transpose()?;
+    let mut out: Box<dyn Write + '_> = match (output_file.as_mut(), pager.as_mut().and_then(|child| child.stdin.take())) {
+        (Some(file), _) => Box::new(file),
+        (None, Some(stdin)) => Box::new(stdin),
+        (None, None) => Box::new(io::stdout().lock()),
+    };
+
+    // Matches already printed are not reported again while watching
+    let mut seen = HashSet::new();
+    // Quitting the pager early closes the pipe, which is not an error
+    let written = if streaming {
+        write_stream(&mut out, found.into_iter().flatten(), &mut seen, &args, &config)
+    } else {
+        let mut results: Vec<SearchResult> = found.into_iter().flatten().collect();
+        if args.ordered {
+            // Files in the order they were handed out, then lines
+            results.sort_by_key(|result| (result.seq, result.line_number));
+        }
+        if args.watch {
+            seen.extend(results.iter().map(watch::match_key));
+        }
+        match (batches, &capture) {
+            _ if names_only => {
+                let files = list_files(&config, &quit)?;
+                print_paths(&mut out, files.iter().collect(), &PrintOptions::from_args(&args))
+            }
+            (Some(batches), _) => batch::write_batch(&mut out, batches, &PrintOptions::from_args(&args)),
+            (None, capture) if args.extract_to_suffix.is_some() => {
+                let suffix = args.extract_to_suffix.as_deref().unwrap_or_default();
+                extract::write_extracts(&mut out, &results, suffix, capture.as_ref(), config.no_messages)
+            }
+            (None, Some(capture)) => capture::write_captures(&mut out, &results, capture),
+            (None, None) if args.json => {
+                print_json(&mut out, &results, config.files_processed.load(Ordering::Relaxed), started.elapsed())
+            }
+            (None, None) if args.json_pretty => print_json_pretty(&mut out, &results),
+            (None, None) if args.markdown => print_markdown(&mut out, &results, &PrintOptions::from_args(&args)),
+            (None, None) if args.unique => print_unique_lines(&mut out, &results, args.count),
+            (None, None) if args.summary_only => print_dir_summary(&mut out, &results, &PrintOptions::from_args(&args)),
+            (None, None) if args.files_with_matches => {
+                print_files_with_matches(&mut out, &results, args.count, args.sort == SortList::Count, &PrintOptions::from_args(&args))
+            }
+            (None, None) if args.files_without_match => {
+                let searched = config.searched_files.as_ref().map(|files| files.lock().unwrap().clone()).unwrap_or_default();
+                print_files_without_match(&mut out, &searched, &results, &PrintOptions::from_args(&args))
+            }
+            (None, None) => write_output(&mut out, results, replacement.as_ref(), &args, &config),
+        }
+    };
+    if let Err(e) = written {
+        if e.kind() != io::ErrorKind::BrokenPipe {
+            return Err(e.into());
+        }
+    }
+
+    // Close the pipe so the pager sees the end of input
+    drop(out);
+    if let Some(mut child) = pager {
+        child.wait()?;
+    }
+    if let Some(file) = output_file {
+        file.finish().context("Failed to write the output file")?;
+    }
+
+    // Reported before watching, which only ends with Ctrl-C
+    let denied = config.walk_errors.permission_denied.load(Ordering::Relaxed);
+    let other = config.walk_errors.other.load(Ordering::Relaxed);
+    if (denied > 0 || other > 0) && !config.no_messages {
+        eprintln!("Skipped {} paths without read permission and {} with other errors{}",
+            denied, other, if config.verbose { "" } else { ", use --verbose for details" });
+    }
+    if args.log_searches && searched {
+        if let Err(e) = history::log_search(&config, matches.get()) {
+            if !config.no_messages {
+                eprintln!("Warning: could not write the search history: {}", e);
+            }
+        }
+    }
+    let failed = config.failed_files.lock().unwrap().len();
+    if failed > 0 && !config.no_messages {
+        eprintln!("Searching {} files failed, their results may be incomplete{}",
+            failed, if config.verbose { "" } else { ", use --verbose for details" });
+    }
+
+    if args.watch && !quit.load(Ordering::Relaxed) {
+        let options = PrintOptions::from_args(&args);
+        watch::watch_changes(&config, seen, quit.clone(), |result| {
+            let mut out = io::stdout().lock();
+            print_search_result(&mut out, result, &options)?;
+            out.flush()
+        })?;
+    }
+
+    // The byte limit stops the search through the quit flag as well
+    let bytes_searched = config.bytes_searched.load(Ordering::Relaxed);
+    let bytes_limit_reached = config.max_total_bytes.map_or(false, |max| bytes_searched > max);
+    if bytes_limit_reached && !config.no_messages {
+        eprintln!("Stopped after searching {} bytes in {} files",
+            bytes_searched, config.files_processed.load(Ordering::Relaxed));
+    }
+    let matches_found = config.matches_found.load(Ordering::Relaxed);
+    let matches_limit_reached = config.max_matches_total.map_or(false, |max| matches_found >= max);
+    let limit_reached = bytes_limit_reached || matches_limit_reached;
+
+    // Exit code of a process stopped by SIGINT
+    if quit.load(Ordering::Relaxed) && !limit_reached {
+        std::process::exit(130);
     }
 
     Ok(())
@@ -134,6 +1346,16 @@ fn run_gui(config: SearchConfig) -> Result<()> {
     Ok(())
 }
 
+#[cfg(feature = "tui")]
+fn run_tui(config: SearchConfig) -> Result<()> {
+    tui::run(config)
+}
+
+#[cfg(not(feature = "tui"))]
+fn run_tui(_config: SearchConfig) -> Result<()> {
+    anyhow::bail!("quicksearch was built without the `tui` feature (rebuild with --features tui)")
+}
+
 fn main() -> Result<()> {
     // Set environment variables
     env::set_var("PDF_QUIET", "1");
@@ -150,9 +1372,66 @@ fn main() -> Result<()> {
     let config = SearchConfig::from_args(&args, text);
 
     // Choose mode based on interactive flag
-    if args.interactive {
+    if args.benchmark {
+        benchmark::run(&config, &mut io::stdout().lock())
+    } else if args.interactive {
         run_gui(config)
+    } else if args.tui {
+        run_tui(config)
     } else {
         run_cli(args)
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoding_rules_need_a_known_encoding_and_a_glob() {
+        assert_eq!(parse_encoding_rule("latin1:*.txt"), Ok(("latin1".to_string(), "*.txt".to_string())));
+        assert!(parse_encoding_rule("latin1").is_err());
+        assert!(parse_encoding_rule("klingon:*.txt").is_err());
+        assert!(parse_encoding_rule("latin1:[").is_err());
+    }
+
+    #[test]
+    fn sizes_take_binary_suffixes() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("4K"), Ok(4 << 10));
+        assert_eq!(parse_size("500m"), Ok(500 << 20));
+        assert_eq!(parse_size(" 2G "), Ok(2 << 30));
+        assert_eq!(parse_size("1T"), Ok(1 << 40));
+        assert!(parse_size("").is_err());
+        assert!(parse_size("1.5G").is_err());
+        assert!(parse_size("12X").is_err());
+        assert!(parse_size("99999999T").is_err());
+    }
+
+    #[test]
+    fn markers_are_two_characters() {
+        assert_eq!(parse_markers("+-"), Ok(Markers { matched: '+', context: '-' }));
+        assert_eq!(parse_markers("» "), Ok(Markers { matched: '»', context: ' ' }));
+        assert!(parse_markers("+").is_err());
+        assert!(parse_markers("+-*").is_err());
+        assert!(parse_markers("").is_err());
+    }
+
+    #[test]
+    fn globs_are_checked_and_kept_as_text() {
+        assert_eq!(parse_glob("build-*"), Ok("build-*".to_string()));
+        assert_eq!(parse_glob("node_modules"), Ok("node_modules".to_string()));
+        assert!(parse_glob("[").is_err());
+        assert!(parse_glob("a**b").is_err());
+    }
+
+    #[test]
+    fn percentages_are_between_0_and_100() {
+        assert_eq!(parse_percent("10"), Ok(10.0));
+        assert_eq!(parse_percent("2.5%"), Ok(2.5));
+        assert_eq!(parse_percent(" 0 "), Ok(0.0));
+        assert_eq!(parse_percent("100%"), Ok(100.0));
+        assert!(parse_percent("100.1").is_err());
+        assert!(parse_percent("-1").is_err());
+        assert!(parse_percent("ten").is_err());
+    }
+}
@@ -0,0 +1,109 @@
+use grep::{
+    matcher::{ByteSet, LineMatchKind, LineTerminator, Match, Matcher, NoCaptures, NoError},
+    regex::RegexMatcher,
+};
+use memchr::memmem::Finder;
+use std::io;
+
+/// Plain substring matcher for literal queries, avoiding the regex engine
+#[derive(Clone, Debug)]
+pub struct LiteralMatcher {
+    finder: Finder<'static>,
+    /// Lines end in \r\n, the terminator has to agree with the searcher's
+    crlf: bool,
+}
+
+impl LiteralMatcher {
+    pub fn new(literal: &str) -> Self {
+        Self {
+            finder: Finder::new(literal.as_bytes()).into_owned(),
+            crlf: false,
+        }
+    }
+
+    pub fn with_crlf(mut self, crlf: bool) -> Self {
+        self.crlf = crlf;
+        self
+    }
+}
+
+impl Matcher for LiteralMatcher {
+    type Captures = NoCaptures;
+    type Error = NoError;
+
+    fn find_at(&self, haystack: &[u8], at: usize) -> Result<Option<Match>, NoError> {
+        let needle_len = self.finder.needle().len();
+        Ok(self.finder
+            .find(&haystack[at..])
+            .map(|start| Match::new(at + start, at + start + needle_len)))
+    }
+
+    fn new_captures(&self) -> Result<NoCaptures, NoError> {
+        Ok(NoCaptures::new())
+    }
+
+    fn line_terminator(&self) -> Option<LineTerminator> {
+        // Queries containing a newline always go through the regex engine
+        if self.crlf {
+            Some(LineTerminator::crlf())
+        } else {
+            Some(LineTerminator::byte(b'\n'))
+        }
+    }
+}
+
+/// Matcher used by the search workers: the literal fast path for plain
+/// queries, the regex engine for everything else and PCRE2 for -P
+#[derive(Clone, Debug)]
+pub enum QueryMatcher {
+    Literal(LiteralMatcher),
+    Regex(RegexMatcher),
+    #[cfg(feature = "pcre2")]
+    Pcre2(grep_pcre2::RegexMatcher),
+}
+
+// Only PCRE2 can fail while matching, e.g. when hitting its backtracking limit
+impl Matcher for QueryMatcher {
+    type Captures = NoCaptures;
+    type Error = io::Error;
+
+    fn find_at(&self, haystack: &[u8], at: usize) -> Result<Option<Match>, io::Error> {
+        match self {
+            QueryMatcher::Literal(m) => m.find_at(haystack, at).map_err(io::Error::other),
+            QueryMatcher::Regex(m) => m.find_at(haystack, at).map_err(io::Error::other),
+            #[cfg(feature = "pcre2")]
+            QueryMatcher::Pcre2(m) => m.find_at(haystack, at).map_err(io::Error::other),
+        }
+    }
+
+    fn new_captures(&self) -> Result<NoCaptures, io::Error> {
+        Ok(NoCaptures::new())
+    }
+
+    fn line_terminator(&self) -> Option<LineTerminator> {
+        match self {
+            QueryMatcher::Literal(m) => m.line_terminator(),
+            QueryMatcher::Regex(m) => m.line_terminator(),
+            #[cfg(feature = "pcre2")]
+            QueryMatcher::Pcre2(m) => m.line_terminator(),
+        }
+    }
+
+    fn non_matching_bytes(&self) -> Option<&ByteSet> {
+        match self {
+            QueryMatcher::Literal(_) => None,
+            QueryMatcher::Regex(m) => m.non_matching_bytes(),
+            #[cfg(feature = "pcre2")]
+            QueryMatcher::Pcre2(m) => m.non_matching_bytes(),
+        }
+    }
+
+    fn find_candidate_line(&self, haystack: &[u8]) -> Result<Option<LineMatchKind>, io::Error> {
+        match self {
+            QueryMatcher::Literal(m) => m.find_candidate_line(haystack).map_err(io::Error::other),
+            QueryMatcher::Regex(m) => m.find_candidate_line(haystack).map_err(io::Error::other),
+            #[cfg(feature = "pcre2")]
+            QueryMatcher::Pcre2(m) => m.find_candidate_line(haystack).map_err(io::Error::other),
+        }
+    }
+}
@@ -0,0 +1,664 @@
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::{self, Path, PathBuf};
+use std::time::Duration;
+use crate::replace::FileChanges;
+use crate::search::{SearchResult, SourceKind, StatsByType};
+use unicode_width::UnicodeWidthChar;
+use crate::colors::Theme;
+use crate::{Args, ColorMode, MarkStyle};
+
+/// ANSI escape sequences for each part of a result, all empty without color
+#[derive(Debug, Clone, Default)]
+pub struct Colors {
+    pub path: String,
+    pub gutter: String,
+    pub matched: String,
+    /// Lines removed and added in a --diff preview
+    pub removed: String,
+    pub added: String,
+    pub reset: String,
+}
+
+impl Colors {
+    fn ansi(theme: &Theme) -> Self {
+        Self {
+            path: theme.path.escape(),
+            gutter: theme.line.escape(),
+            matched: theme.matched.escape(),
+            removed: theme.removed.escape(),
+            added: theme.added.escape(),
+            reset: "\x1b[0m".to_string(),
+        }
+    }
+}
+
+/// Characters in the first gutter column, in front of the line numbers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Markers {
+    pub matched: char,
+    pub context: char,
+}
+
+impl Default for Markers {
+    fn default() -> Self {
+        Self { matched: '>', context: ' ' }
+    }
+}
+
+/// How results are printed on the command line
+#[derive(Debug, Clone, Default)]
+pub struct PrintOptions {
+    pub path_separator: Option<char>,
+    pub colors: Colors,
+    pub markers: Markers,
+    /// Character drawn under matches by --mark
+    pub mark: Option<char>,
+    /// Names PDF and decompressed sources in the file headers, with --verbose
+    pub show_source: bool,
+    /// Search roots stripped from printed paths, empty prints paths as searched
+    pub relative_to: Vec<PathBuf>,
+    /// Context shared by nearby matches is printed once, otherwise each match
+    /// is a block of its own
+    pub merge_context: bool,
+}
+
+impl PrintOptions {
+    pub fn from_args(args: &Args) -> Self {
+        let color = match args.color {
+            ColorMode::Always => true,
+            ColorMode::Auto => args.output_file.is_none() && io::stdout().is_terminal(),
+            ColorMode::Never => false,
+        };
+
+        let relative_to = match (args.relative, args.paths.is_empty()) {
+            (false, _) => Vec::new(),
+            (true, true) => vec![PathBuf::from(".")],
+            (true, false) => args.paths.clone(),
+        };
+        // Canonical result paths only share a prefix with canonical roots
+        let relative_to = if args.canonicalize {
+            relative_to.into_iter()
+                .map(|root| fs::canonicalize(&root).unwrap_or(root))
+                .collect()
+        } else {
+            relative_to
+        };
+
+        Self {
+            path_separator: args.path_separator,
+            colors: if color { Colors::ansi(&Theme::new(&args.colors)) } else { Colors::default() },
+            markers: args.context_marker,
+            mark: match args.mark {
+                MarkStyle::None => None,
+                MarkStyle::Underline => Some('_'),
+                MarkStyle::Caret => Some('^'),
+            },
+            show_source: args.verbose,
+            relative_to,
+            merge_context: !args.no_context_merge,
+        }
+    }
+
+    /// `path` as printed: relative to its search root with --relative, using the
+    /// chosen separator
+    pub fn display(&self, path: &Path) -> String {
+        // The longest root wins when roots are nested
+        let relative = self.relative_to.iter()
+            .filter_map(|root| path.strip_prefix(root).ok())
+            .min_by_key(|relative| relative.as_os_str().len());
+        let path = match relative {
+            // A file given as root itself keeps its name
+            Some(relative) if relative.as_os_str().is_empty() => path.file_name().map_or(path, Path::new),
+            Some(relative) => relative,
+            None => path,
+        };
+        display_path(path, self.path_separator)
+    }
+}
+
+pub fn display_path(path: &Path, separator: Option<char>) -> String {
+    let display = path.display().to_string();
+    match separator {
+        // Both separators are accepted on Windows, so normalize either one
+        Some(separator) => display.chars()
+            .map(|c| if c == '/' || c == path::MAIN_SEPARATOR { separator } else { c })
+            .collect(),
+        None => display,
+    }
+}
+
+pub fn print_search_result(out: &mut dyn Write, result: &SearchResult, options: &PrintOptions) -> io::Result<()> {
+    print_file_results(out, &[result], options)
+}
+
+/// Prints one result for --print0 as a single record: the lines the default
+/// output prints for it (header, context before, the match, context after)
+/// without the final newline, followed by a NUL byte. Within the record a
+/// backslash is written as `\\` and a newline as `\n`, NUL bytes of the file
+/// are dropped. So records can be split on `\0`
+pub fn print_record0(out: &mut dyn Write, result: &SearchResult, options: &PrintOptions) -> io::Result<()> {
+    let mut record = Vec::new();
+    print_search_result(&mut record, result, options)?;
+    let text = String::from_utf8_lossy(&record);
+    let escaped = text.trim_end_matches('\n')
+        .replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('\0', "");
+    out.write_all(escaped.as_bytes())?;
+    out.write_all(b"\0")
+}
+
+/// Prints results grouped by file, in the order the files were found. The matches
+/// of a file share one header, blocks that don't touch are separated by `--`
+pub fn print_results(out: &mut dyn Write, results: &[SearchResult], options: &PrintOptions) -> io::Result<()> {
+    for group in group_by_file(results) {
+        print_file_results(out, &group, options)?;
+    }
+    Ok(())
+}
+
+/// Results per file in the order the files were found, each sorted by line number
+fn group_by_file(results: &[SearchResult]) -> Vec<Vec<&SearchResult>> {
+    let mut order: Vec<&Path> = Vec::new();
+    let mut by_path: HashMap<&Path, Vec<&SearchResult>> = HashMap::new();
+    for result in results {
+        by_path.entry(&result.path)
+            .or_insert_with(|| {
+                order.push(&result.path);
+                Vec::new()
+            })
+            .push(result);
+    }
+
+    order.into_iter()
+        .map(|path| {
+            let mut group = by_path.remove(path).unwrap_or_default();
+            group.sort_by_key(|result| result.line_number);
+            group
+        })
+        .collect()
+}
+
+/// Prints the results of one file, sorted by line number
+fn print_file_results(out: &mut dyn Write, group: &[&SearchResult], options: &PrintOptions) -> io::Result<()> {
+    // The marker takes the first column of the line number gutter
+    let width = group.iter().map(|result| result.gutter_width()).max().unwrap_or(3);
+    let mut printer = ResultPrinter::new(options).with_width(width);
+    for result in group {
+        printer.print(out, result)?;
+    }
+    printer.finish(out)
+}
+
+/// Prints results one at a time as they arrive. Consecutive matches of a file
+/// share one header, blocks that don't touch are separated by `--`
+pub struct ResultPrinter<'a> {
+    options: &'a PrintOptions,
+    // Only grows, a streamed result can't know the width of the ones after it
+    width: usize,
+    // File and last line printed of the previous result
+    last: Option<(PathBuf, u64)>,
+    // Context after the previous match, held back until the next match shows
+    // where it ends, so lines shared by neighbouring matches are printed once
+    pending_after: Vec<(u64, String)>,
+}
+
+impl<'a> ResultPrinter<'a> {
+    pub fn new(options: &'a PrintOptions) -> Self {
+        Self {
+            options,
+            width: 3,
+            last: None,
+            pending_after: Vec::new(),
+        }
+    }
+
+    pub fn with_width(mut self, width: usize) -> Self {
+        self.width = width;
+        self
+    }
+
+    pub fn print(&mut self, out: &mut dyn Write, result: &SearchResult) -> io::Result<()> {
+        let options = self.options;
+        let colors = &options.colors;
+        self.width = self.width.max(result.gutter_width());
+        let width = self.width;
+
+        let same_file = matches!(&self.last, Some((path, _)) if *path == result.path);
+        let continues = matches!(&self.last, Some((path, line)) if *path == result.path && result.line_number > *line);
+        let last_line = if same_file && !options.merge_context {
+            // The previous block ends with all of its context, this one starts anew
+            self.print_context_after(out, u64::MAX)?;
+            writeln!(out, "{}--{}", colors.gutter, colors.reset)?;
+            None
+        } else if continues {
+            // Lines from this match on belong to this match
+            self.print_context_after(out, result.line_number)?;
+            self.last.as_ref().map(|(_, line)| *line)
+        } else {
+            self.finish(out)?;
+            let source = match result.source {
+                SourceKind::Text => String::new(),
+                _ if !options.show_source => String::new(),
+                source => format!(" ({})", source.as_str()),
+            };
+            writeln!(out, "{}File: {}:{}{}{}",
+                colors.path, options.display(&result.path), result.line_number, colors.reset, source)?;
+            None
+        };
+
+        let first_line = result.context_before.first().map_or(result.line_number, |(line_num, _)| *line_num);
+        if last_line.map_or(false, |last| first_line > last + 1) {
+            writeln!(out, "{}--{}", colors.gutter, colors.reset)?;
+        }
+
+        // Print context before
+        let markers = options.markers;
+        let is_new = |line_num: u64| last_line.map_or(true, |last| line_num > last);
+        for (line_num, line) in result.context_before.iter().filter(|(line_num, _)| is_new(*line_num)) {
+            writeln!(out, "{}{}{:>width$} |{} {}", colors.gutter, markers.context, line_num, colors.reset, line, width = width - 1)?;
+        }
+
+        // Print matching line with the match marker
+        writeln!(out, "{}{}{:>width$} |{} {}", colors.matched, markers.matched, result.line_number, colors.reset, result.line, width = width - 1)?;
+        if let Some(mark) = options.mark {
+            writeln!(out, "{:width$} | {}", "", mark_line(&result.line, &result.matches, mark), width = width)?;
+        }
+
+        self.last = Some((result.path.clone(), result.line_number));
+        self.pending_after = result.context_after.clone();
+        Ok(())
+    }
+
+    /// Prints the held back context after the previous match, up to `next_match`
+    fn print_context_after(&mut self, out: &mut dyn Write, next_match: u64) -> io::Result<()> {
+        let colors = &self.options.colors;
+        let marker = self.options.markers.context;
+        for (line_num, line) in self.pending_after.drain(..).take_while(|(line_num, _)| *line_num < next_match) {
+            writeln!(out, "{}{}{:>width$} |{} {}", colors.gutter, marker, line_num, colors.reset, line, width = self.width - 1)?;
+            if let Some((_, last)) = &mut self.last {
+                *last = line_num;
+            }
+        }
+        Ok(())
+    }
+
+    /// Ends the current file, call once after the last result
+    pub fn finish(&mut self, out: &mut dyn Write) -> io::Result<()> {
+        if self.last.is_none() {
+            return Ok(());
+        }
+        self.print_context_after(out, u64::MAX)?;
+        self.last = None;
+
+        // Empty line between files
+        writeln!(out)
+    }
+}
+
+/// A line with `mark` under the matched spans of `line`, each character taking
+/// as many columns as it's displayed wide. Tabs are copied, so they line up
+/// the same way as in the line above
+fn mark_line(line: &str, matches: &[(usize, usize)], mark: char) -> String {
+    let mut marks = String::new();
+    for (index, c) in line.char_indices() {
+        if c == '\t' {
+            marks.push('\t');
+            continue;
+        }
+        let matched = matches.iter().any(|&(start, end)| index >= start && index < end);
+        let fill = if matched { mark } else { ' ' };
+        marks.extend(std::iter::repeat(fill).take(c.width().unwrap_or(0)));
+    }
+    marks.trim_end().to_string()
+}
+
+/// Prints results as Markdown: a heading per file and a fenced code block per
+/// match, with the match line marked like in the plain output
+pub fn print_markdown(out: &mut dyn Write, results: &[SearchResult], options: &PrintOptions) -> io::Result<()> {
+    for group in group_by_file(results) {
+        // Code spans take backticks when delimited by a longer run, the padding
+        // spaces are stripped again
+        let path = options.display(&group[0].path);
+        let ticks = "`".repeat(longest_backtick_run(&[path.clone()]) + 1);
+        writeln!(out, "### {0} {1} {0}", ticks, path)?;
+        writeln!(out)?;
+
+        for result in group {
+            let width = result.gutter_width() - 1;
+            let markers = options.markers;
+            let mut lines: Vec<String> = Vec::new();
+            for (line_num, line) in &result.context_before {
+                lines.push(format!("{}{:>width$} | {}", markers.context, line_num, line, width = width));
+            }
+            lines.push(format!("{}{:>width$} | {}", markers.matched, result.line_number, result.line, width = width));
+            for (line_num, line) in &result.context_after {
+                lines.push(format!("{}{:>width$} | {}", markers.context, line_num, line, width = width));
+            }
+
+            // A fence longer than any backtick run in the block can't be closed by its content
+            let fence = "`".repeat(longest_backtick_run(&lines).max(2) + 1);
+            writeln!(out, "{}", fence)?;
+            for line in &lines {
+                writeln!(out, "{}", line)?;
+            }
+            writeln!(out, "{}", fence)?;
+            writeln!(out)?;
+        }
+    }
+    out.flush()
+}
+
+fn longest_backtick_run(lines: &[String]) -> usize {
+    lines.iter()
+        .flat_map(|line| line.split(|c| c != '`'))
+        .map(str::len)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Prints pending replacements as `-` old and `+` new line pairs, file by file
+pub fn print_diff(out: &mut dyn Write, files: &[FileChanges], options: &PrintOptions) -> io::Result<()> {
+    let colors = &options.colors;
+    for file in files {
+        // The '-' and '+' markers take the first column of the gutter
+        let width = file.changes.iter()
+            .map(|change| change.line_number.to_string().len() + 1)
+            .max()
+            .unwrap_or(0)
+            .max(3);
+
+        writeln!(out, "{}File: {}{}", colors.path, options.display(&file.path), colors.reset)?;
+        for change in &file.changes {
+            writeln!(out, "{}-{:>width$} | {}{}", colors.removed, change.line_number, change.before, colors.reset, width = width - 1)?;
+            writeln!(out, "{}+{:>width$} | {}{}", colors.added, change.line_number, change.after, colors.reset, width = width - 1)?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+fn match_record(result: &SearchResult) -> Value {
+    let lines = |lines: &[(u64, String)]| -> Vec<Value> {
+        lines.iter().map(|(line_num, line)| json!([line_num, line])).collect()
+    };
+
+    json!({
+        "type": "match",
+        "path": result.path.to_string_lossy(),
+        "line_number": result.line_number,
+        "line": result.line,
+        "lossy": result.lossy,
+        "source": result.source.as_str(),
+        "matches": result.matches,
+        "context_before": lines(&result.context_before),
+        "context_after": lines(&result.context_after),
+    })
+}
+
+/// Prints one JSON object per line: a `match` record for every result, then a
+/// `summary` record with the totals, so streaming consumers know the search is done
+pub fn print_json(out: &mut dyn Write, results: &[SearchResult], files_searched: usize, elapsed: Duration) -> io::Result<()> {
+    for result in results {
+        writeln!(out, "{}", match_record(result))?;
+    }
+
+    let files_with_matches = results.iter()
+        .map(|result| &result.path)
+        .collect::<HashSet<_>>()
+        .len();
+    let summary = json!({
+        "type": "summary",
+        "matches": results.len(),
+        "files_with_matches": files_with_matches,
+        "files_searched": files_searched,
+        "elapsed_secs": elapsed.as_secs_f64(),
+    });
+    writeln!(out, "{}", summary)?;
+    out.flush()
+}
+
+/// Prints all `match` records as one indented JSON array, easier to read than JSON Lines
+pub fn print_json_pretty(out: &mut dyn Write, results: &[SearchResult]) -> io::Result<()> {
+    let records: Vec<Value> = results.iter().map(match_record).collect();
+    serde_json::to_writer_pretty(&mut *out, &records)?;
+    writeln!(out)?;
+    out.flush()
+}
+
+/// Prints the searched files without any result, sorted, one per line
+pub fn print_files_without_match(out: &mut dyn Write, searched: &[PathBuf], results: &[SearchResult], options: &PrintOptions) -> io::Result<()> {
+    let matched: HashSet<&Path> = results.iter().map(|result| result.path.as_path()).collect();
+    let files: Vec<&PathBuf> = searched.iter()
+        .filter(|path| !matched.contains(path.as_path()))
+        .collect();
+    print_paths(out, files, options)
+}
+
+/// Prints the files with results, sorted by path or with the most matching lines
+/// first. With `counts` each path is followed by `:` and its number of matching lines
+pub fn print_files_with_matches(out: &mut dyn Write, results: &[SearchResult], counts: bool, by_count: bool, options: &PrintOptions) -> io::Result<()> {
+    let mut files: HashMap<&PathBuf, usize> = HashMap::new();
+    for result in results {
+        *files.entry(&result.path).or_default() += 1;
+    }
+    let mut files: Vec<(&PathBuf, usize)> = files.into_iter().collect();
+    if by_count {
+        files.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+    } else {
+        files.sort();
+    }
+
+    for (path, count) in files {
+        let count = if counts { format!(":{}", count) } else { String::new() };
+        writeln!(out, "{}{}{}{}", options.colors.path, options.display(path), options.colors.reset, count)?;
+    }
+    out.flush()
+}
+
+/// Prints every distinct matching line once, in the order they were first found.
+/// With `counts` each line is preceded by how often it matched, like `uniq -c`
+pub fn print_unique_lines(out: &mut dyn Write, results: &[SearchResult], counts: bool) -> io::Result<()> {
+    let mut order: Vec<&str> = Vec::new();
+    let mut seen: HashMap<&str, usize> = HashMap::new();
+    for result in results {
+        let count = seen.entry(&result.line).or_insert_with(|| {
+            order.push(&result.line);
+            0
+        });
+        *count += 1;
+    }
+
+    for line in order {
+        if counts {
+            writeln!(out, "{:>7} {}", seen[line], line)?;
+        } else {
+            writeln!(out, "{}", line)?;
+        }
+    }
+    out.flush()
+}
+
+/// Matching lines in a directory and below it, for --summary-only
+#[derive(Default)]
+struct DirNode {
+    total: usize,
+    /// Matching lines of the files directly in the directory
+    direct: usize,
+    children: BTreeMap<String, DirNode>,
+}
+
+/// Prints the directories with matches as a tree, each with the number of
+/// matching lines in and below it. Busiest directories come first
+pub fn print_dir_summary(out: &mut dyn Write, results: &[SearchResult], options: &PrintOptions) -> io::Result<()> {
+    let mut root = DirNode::default();
+    for result in results {
+        let mut node = &mut root;
+        node.total += 1;
+        for component in result.path.parent().unwrap_or(Path::new("")).components() {
+            node = node.children.entry(component.as_os_str().to_string_lossy().into_owned()).or_default();
+            node.total += 1;
+        }
+        node.direct += 1;
+    }
+
+    let width = root.total.to_string().len();
+    print_dir_children(out, &root, 0, width, options)?;
+    writeln!(out, "{:>width$}  matching lines in total", root.total, width = width)?;
+    out.flush()
+}
+
+fn print_dir_children(out: &mut dyn Write, node: &DirNode, depth: usize, width: usize, options: &PrintOptions) -> io::Result<()> {
+    let mut children: Vec<(&String, &DirNode)> = node.children.iter().collect();
+    children.sort_by(|(a_name, a), (b_name, b)| b.total.cmp(&a.total).then(a_name.cmp(b_name)));
+
+    for (name, mut child) in children {
+        // A directory without matches of its own shares the line of its only subdirectory
+        let mut path = PathBuf::from(name);
+        while child.direct == 0 && child.children.len() == 1 {
+            let (next_name, next) = child.children.iter().next().expect("one subdirectory");
+            path.push(next_name);
+            child = next;
+        }
+        writeln!(out, "{:>width$}  {}{}{}{}", child.total, "  ".repeat(depth),
+            options.colors.path, display_path(&path, options.path_separator), options.colors.reset, width = width)?;
+        print_dir_children(out, child, depth + 1, width, options)?;
+    }
+    Ok(())
+}
+
+/// Prints paths sorted, one per line
+pub fn print_paths(out: &mut dyn Write, mut paths: Vec<&PathBuf>, options: &PrintOptions) -> io::Result<()> {
+    paths.sort();
+    for path in paths {
+        writeln!(out, "{}{}{}", options.colors.path, options.display(path), options.colors.reset)?;
+    }
+    out.flush()
+}
+
+pub fn print_stats_by_type(out: &mut dyn Write, stats_by_type: &StatsByType, files_processed: usize) -> io::Result<()> {
+    let stats = stats_by_type.lock().unwrap();
+    let mut rows: Vec<_> = stats.iter().collect();
+    // Slowest types first
+    rows.sort_by(|a, b| b.1.elapsed.cmp(&a.1.elapsed));
+
+    writeln!(out, "{:<12} {:>8} {:>14} {:>10}", "Type", "Files", "Bytes", "Time")?;
+    for (ext, stat) in rows {
+        writeln!(out, "{:<12} {:>8} {:>14} {:>9.2}s", ext, stat.files, stat.bytes, stat.elapsed.as_secs_f64())?;
+    }
+    writeln!(out, "{} files searched", files_processed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(path: &str, line_number: u64, line: &str) -> SearchResult {
+        SearchResult {
+            path: PathBuf::from(path),
+            line_number,
+            line: line.to_string(),
+            matches: Vec::new(),
+            context_before: Vec::new(),
+            context_after: Vec::new(),
+            lossy: false,
+            seq: 0,
+            source: SourceKind::Text,
+        }
+    }
+
+    fn printed(results: &[SearchResult], options: &PrintOptions) -> String {
+        let mut out = Vec::new();
+        print_results(&mut out, results, options).expect("print to memory");
+        String::from_utf8(out).expect("UTF-8 output")
+    }
+
+    #[test]
+    fn gutter_fits_the_widest_line_number() {
+        let last = SearchResult {
+            context_before: vec![(1199, "line 1199".to_string())],
+            ..result("big.txt", 1200, "needle")
+        };
+        assert_eq!(last.gutter_width(), 5);
+        assert_eq!(printed(&[last], &PrintOptions::default()),
+            "File: big.txt:1200\n 1199 | line 1199\n>1200 | needle\n\n");
+
+        // Context after the match widens the gutter of the match as well
+        let early = SearchResult {
+            context_after: vec![(1000, "line 1000".to_string())],
+            ..result("big.txt", 999, "needle")
+        };
+        assert_eq!(printed(&[early], &PrintOptions::default()),
+            "File: big.txt:999\n> 999 | needle\n 1000 | line 1000\n\n");
+    }
+
+    #[test]
+    fn relative_paths_strip_absolute_roots() {
+        let options = PrintOptions {
+            relative_to: vec![PathBuf::from("/data"), PathBuf::from("/data/project"), PathBuf::from("/etc/hosts")],
+            path_separator: Some('/'),
+            ..PrintOptions::default()
+        };
+        // The deepest root holding the file wins
+        assert_eq!(options.display(Path::new("/data/project/src/main.rs")), "src/main.rs");
+        assert_eq!(options.display(Path::new("/data/notes.txt")), "notes.txt");
+        // A file searched as a root keeps its name
+        assert_eq!(options.display(Path::new("/etc/hosts")), "hosts");
+        assert_eq!(options.display(Path::new("/srv/other.txt")), "/srv/other.txt");
+    }
+
+    #[test]
+    fn marks_line_up_under_wide_characters_and_tabs() {
+        assert_eq!(mark_line("日本 needle", &[(7, 13)], '^'), "     ^^^^^^");
+        assert_eq!(mark_line("\tx needle here", &[(3, 9)], '_'), "\t  ______");
+        assert_eq!(mark_line("no match", &[], '^'), "");
+    }
+
+    #[test]
+    fn files_with_matches_sort_by_path_or_count() {
+        let results = [result("b.txt", 1, "x"), result("a.txt", 1, "x"), result("b.txt", 2, "x"), result("c.txt", 1, "x")];
+        let listed = |counts, by_count| {
+            let mut out = Vec::new();
+            print_files_with_matches(&mut out, &results, counts, by_count, &PrintOptions::default()).expect("print to memory");
+            String::from_utf8(out).expect("UTF-8 output")
+        };
+
+        assert_eq!(listed(false, false), "a.txt\nb.txt\nc.txt\n");
+        assert_eq!(listed(true, false), "a.txt:1\nb.txt:2\nc.txt:1\n");
+        assert_eq!(listed(true, true), "b.txt:2\na.txt:1\nc.txt:1\n");
+    }
+
+    #[test]
+    fn unique_lines_keep_the_first_seen_order() {
+        let results = [result("a.txt", 1, "use std::fs;"), result("a.txt", 2, "use std::io;"), result("b.txt", 1, "use std::fs;")];
+        let unique = |counts| {
+            let mut out = Vec::new();
+            print_unique_lines(&mut out, &results, counts).expect("print to memory");
+            String::from_utf8(out).expect("UTF-8 output")
+        };
+
+        assert_eq!(unique(false), "use std::fs;\nuse std::io;\n");
+        assert_eq!(unique(true), "      2 use std::fs;\n      1 use std::io;\n");
+    }
+
+    #[test]
+    fn dir_summary_nests_and_collapses_single_child_directories() {
+        let mut results = Vec::new();
+        for line_number in 1..=3 {
+            results.push(result("src/main.rs", line_number, "x"));
+        }
+        results.push(result("src/sub/a.rs", 1, "x"));
+        results.push(result("src/sub/b.rs", 1, "x"));
+        results.push(result("docs/deep/c.md", 1, "x"));
+        results.push(result("top.txt", 1, "x"));
+
+        let options = PrintOptions { path_separator: Some('/'), ..PrintOptions::default() };
+        let mut out = Vec::new();
+        print_dir_summary(&mut out, &results, &options).expect("print to memory");
+        assert_eq!(String::from_utf8(out).expect("UTF-8 output"),
+            "5  src\n2    sub\n1  docs/deep\n7  matching lines in total\n");
+    }
+}
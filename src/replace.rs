@@ -0,0 +1,548 @@
+use anyhow::{bail, Context, Result};
+use regex::{escape, Captures, NoExpand, Regex, RegexBuilder};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs,
+    path::{Path, PathBuf},
+};
+use crate::search::{trim_line_ending, SearchResult, SourceKind};
+use crate::SearchConfig;
+
+/// Case change requested by a `\U`, `\L` or `\E` directive in a replacement
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Case {
+    Keep,
+    Upper,
+    Lower,
+}
+
+impl Case {
+    fn apply(self, text: String) -> String {
+        match self {
+            Case::Keep => text,
+            Case::Upper => text.to_uppercase(),
+            Case::Lower => text.to_lowercase(),
+        }
+    }
+}
+
+/// Splits a replacement at its case directives: `\U` upper cases and `\L` lower
+/// cases the rest of the expanded replacement, `\E` ends either
+fn case_segments(replacement: &str) -> Vec<(Case, String)> {
+    let mut segments = vec![(Case::Keep, String::new())];
+    let mut chars = replacement.chars().peekable();
+    while let Some(c) = chars.next() {
+        let case = match (c, chars.peek()) {
+            ('\\', Some('U')) => Some(Case::Upper),
+            ('\\', Some('L')) => Some(Case::Lower),
+            ('\\', Some('E')) => Some(Case::Keep),
+            _ => None,
+        };
+        match case {
+            Some(case) => {
+                chars.next();
+                segments.push((case, String::new()));
+            }
+            None => segments.last_mut().unwrap().1.push(c),
+        }
+    }
+    segments
+}
+
+/// A compiled pattern together with the text that replaces its matches
+struct Rule {
+    regex: Regex,
+    replacement: String,
+    expand: bool,
+    /// Set when an expanded replacement uses case directives
+    case_segments: Option<Vec<(Case, String)>>,
+}
+
+impl Rule {
+    fn new(regex: Regex, replacement: String, expand: bool) -> Self {
+        let segments = case_segments(&replacement);
+        Self {
+            regex,
+            case_segments: (expand && segments.len() > 1).then_some(segments),
+            replacement,
+            expand,
+        }
+    }
+
+    fn apply(&self, text: &str) -> (String, usize) {
+        let matches = self.regex.find_iter(text).count();
+        if matches == 0 {
+            return (text.to_string(), 0);
+        }
+
+        let replaced = if let Some(segments) = &self.case_segments {
+            // Each part is expanded on its own, so captured text is never read as a directive
+            self.regex.replace_all(text, |caps: &Captures| {
+                segments.iter()
+                    .map(|(case, part)| {
+                        let mut expanded = String::new();
+                        caps.expand(part, &mut expanded);
+                        case.apply(expanded)
+                    })
+                    .collect::<String>()
+            })
+        } else if self.expand {
+            self.regex.replace_all(text, self.replacement.as_str())
+        } else {
+            self.regex.replace_all(text, NoExpand(&self.replacement))
+        };
+        (replaced.into_owned(), matches)
+    }
+
+    /// Parses a sed-like `s/pattern/replacement/` rule. Any character after
+    /// the `s` works as delimiter, escape it with a backslash to use it literally.
+    /// Only the `g` flag is accepted since every match on a line is replaced anyway.
+    fn parse(line: &str) -> Result<Self> {
+        let mut chars = line.chars();
+        if chars.next() != Some('s') {
+            bail!("rule must start with 's'");
+        }
+        let delimiter = match chars.next() {
+            Some(c) if !c.is_alphanumeric() && c != '\\' => c,
+            _ => bail!("missing delimiter after 's'"),
+        };
+
+        let mut parts = vec![String::new()];
+        let mut escaped = false;
+        for c in chars {
+            let part = parts.last_mut().unwrap();
+            if escaped {
+                // Other escapes belong to the regex or replacement syntax
+                if c != delimiter {
+                    part.push('\\');
+                }
+                part.push(c);
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == delimiter {
+                parts.push(String::new());
+            } else {
+                part.push(c);
+            }
+        }
+
+        if parts.len() != 3 {
+            bail!("expected s{0}pattern{0}replacement{0}", delimiter);
+        }
+        if !parts[2].is_empty() && parts[2] != "g" {
+            bail!("unsupported flags '{}'", parts[2]);
+        }
+
+        let regex = Regex::new(&parts[0])
+            .with_context(|| format!("invalid pattern '{}'", parts[0]))?;
+        Ok(Self::new(regex, parts[1].clone(), true))
+    }
+}
+
+/// One or more replace rules, applied in order to each matched line.
+pub struct Replacement {
+    rules: Vec<Rule>,
+}
+
+/// One line that would change, as shown in a preview.
+#[derive(Clone)]
+pub struct LineChange {
+    pub line_number: u64,
+    pub before: String,
+    pub after: String,
+    pub matches: usize,
+    /// Matches replaced by each rule, in rule order
+    pub rule_matches: Vec<usize>,
+}
+
+/// All pending line changes for a single file.
+#[derive(Clone)]
+pub struct FileChanges {
+    pub path: PathBuf,
+    pub changes: Vec<LineChange>,
+}
+
+impl Replacement {
+    pub fn new(config: &SearchConfig, replacement: &str) -> Result<Self> {
+        // Same pattern rules as the search itself
+        let regex = if config.use_regex {
+            RegexBuilder::new(&config.query)
+                .dot_matches_new_line(config.dot_all)
+                .multi_line(config.multi_line)
+                .ignore_whitespace(config.ignore_whitespace)
+                .build()
+        } else {
+            Regex::new(&escape(&config.query))
+        }.with_context(|| format!("Invalid search pattern '{}'", config.query))?;
+
+        Ok(Self {
+            // Capture references like $1 and case directives only make sense for regex searches
+            rules: vec![Rule::new(regex, replacement.to_string(), config.use_regex)],
+        })
+    }
+
+    /// Parses a replace script with one `s/pattern/replacement/` rule per line.
+    /// Empty lines and lines starting with `#` are ignored.
+    pub fn from_script(script: &str) -> Result<Self> {
+        let rules = script.lines()
+            .enumerate()
+            .map(|(index, line)| (index + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+            .map(|(line_number, line)| {
+                Rule::parse(line).with_context(|| format!("line {}: malformed rule '{}'", line_number, line))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        if rules.is_empty() {
+            bail!("replace script contains no rules");
+        }
+        Ok(Self { rules })
+    }
+
+    /// Pattern matching any line at least one rule would change
+    pub fn combined_pattern(&self) -> String {
+        self.rules.iter()
+            .map(|rule| format!("(?:{})", rule.regex.as_str()))
+            .collect::<Vec<_>>()
+            .join("|")
+    }
+
+    /// The rule patterns, for reporting per-rule counts
+    pub fn rule_patterns(&self) -> Vec<&str> {
+        self.rules.iter().map(|rule| rule.regex.as_str()).collect()
+    }
+
+    /// Applies the rules in order, returning the rewritten text and the number
+    /// of matches replaced by each rule
+    pub fn apply_rules(&self, text: &str) -> (String, Vec<usize>) {
+        let mut text = text.to_string();
+        let mut counts = Vec::with_capacity(self.rules.len());
+        for rule in &self.rules {
+            let (replaced, matches) = rule.apply(&text);
+            text = replaced;
+            counts.push(matches);
+        }
+        (text, counts)
+    }
+
+    /// Returns the rewritten text and how many matches were replaced
+    pub fn apply(&self, text: &str) -> (String, usize) {
+        let (text, counts) = self.apply_rules(text);
+        (text, counts.iter().sum())
+    }
+}
+
+/// Whether the line of a result can be written back to its file. Only text
+/// files have real lines: extracted, decompressed, recognized and structured
+/// results are numbered by their source, and lossy lines aren't valid UTF-8
+pub fn is_writable(result: &SearchResult) -> bool {
+    result.source == SourceKind::Text && !result.lossy
+}
+
+/// Builds the list of changes a replace would make, grouped per file.
+/// Results that aren't [`is_writable`] are skipped, see also [`lossy_files`].
+pub fn plan(results: &[SearchResult], replacement: &Replacement) -> Vec<FileChanges> {
+    let mut files: BTreeMap<PathBuf, Vec<LineChange>> = BTreeMap::new();
+
+    for result in results {
+        if !is_writable(result) {
+            continue;
+        }
+
+        let (after, rule_matches) = replacement.apply_rules(&result.line);
+        let matches = rule_matches.iter().sum();
+        if matches == 0 {
+            continue;
+        }
+
+        files.entry(result.path.clone())
+            .or_default()
+            .push(LineChange {
+                line_number: result.line_number,
+                before: result.line.clone(),
+                after,
+                matches,
+                rule_matches,
+            });
+    }
+
+    files.into_iter()
+        .map(|(path, mut changes)| {
+            changes.sort_by_key(|change| change.line_number);
+            FileChanges { path, changes }
+        })
+        .collect()
+}
+
+/// Files with results [`plan`] leaves out as they aren't valid UTF-8, so
+/// writing the replaced line back would change more than the match. In path order
+pub fn lossy_files(results: &[SearchResult]) -> Vec<&Path> {
+    let files: BTreeSet<&Path> = results.iter()
+        .filter(|result| result.lossy && result.source == SourceKind::Text)
+        .map(|result| result.path.as_path())
+        .collect();
+    files.into_iter().collect()
+}
+
+/// Total number of matches across all planned changes
+pub fn count_matches(files: &[FileChanges]) -> usize {
+    files.iter()
+        .flat_map(|file| file.changes.iter())
+        .map(|change| change.matches)
+        .sum()
+}
+
+/// The first `limit` changed lines of `files`, for a shortened preview. Files
+/// past the limit are left out
+pub fn limit_changes(files: &[FileChanges], limit: usize) -> Vec<FileChanges> {
+    let mut left = limit;
+    files.iter()
+        .map_while(|file| {
+            (left > 0).then(|| {
+                let changes: Vec<LineChange> = file.changes.iter().take(left).cloned().collect();
+                left -= changes.len();
+                FileChanges { path: file.path.clone(), changes }
+            })
+        })
+        .collect()
+}
+
+/// Number of matches per rule across all planned changes
+pub fn count_rule_matches(files: &[FileChanges], rules: usize) -> Vec<usize> {
+    let mut totals = vec![0; rules];
+    for change in files.iter().flat_map(|file| file.changes.iter()) {
+        for (total, count) in totals.iter_mut().zip(&change.rule_matches) {
+            *total += count;
+        }
+    }
+    totals
+}
+
+pub fn backup_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".bak");
+    PathBuf::from(name)
+}
+
+/// Rewrites the planned lines of a file in place, keeping a `.bak` copy of
+/// the original. Returns the number of matches replaced. Fails without
+/// writing when a planned line no longer reads as it did when planned, e.g.
+/// when the file was edited between the GUI's preview and apply
+pub fn write_in_place(file: &FileChanges, replacement: &Replacement) -> Result<usize> {
+    let content = match fs::read_to_string(&file.path) {
+        Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+            bail!("{} is not valid UTF-8, in-place replace only rewrites UTF-8 files", file.path.display())
+        }
+        result => result.with_context(|| format!("Failed to read {}", file.path.display()))?,
+    };
+
+    let lines: HashMap<u64, &str> = file.changes.iter()
+        .map(|change| (change.line_number, change.before.as_str()))
+        .collect();
+    let mut found = 0;
+    for (index, line) in content.split_inclusive('\n').enumerate() {
+        if let Some(&before) = lines.get(&(index as u64 + 1)) {
+            if trim_line_ending(line) != before {
+                bail!("{} changed since the search, line {} differs, not replaced", file.path.display(), index + 1);
+            }
+            found += 1;
+        }
+    }
+    if found != lines.len() {
+        bail!("{} changed since the search, it has fewer lines, not replaced", file.path.display());
+    }
+
+    fs::copy(&file.path, backup_path(&file.path))
+        .with_context(|| format!("Failed to back up {}", file.path.display()))?;
+
+    let mut output = String::with_capacity(content.len());
+    let mut replaced = 0;
+
+    for (index, line) in content.split_inclusive('\n').enumerate() {
+        if !lines.contains_key(&(index as u64 + 1)) {
+            output.push_str(line);
+            continue;
+        }
+
+        // Keep the original line ending untouched
+        let body = trim_line_ending(line);
+        let (new_body, count) = replacement.apply(body);
+        output.push_str(&new_body);
+        output.push_str(&line[body.len()..]);
+        replaced += count;
+    }
+
+    fs::write(&file.path, output)
+        .with_context(|| format!("Failed to write {}", file.path.display()))?;
+
+    Ok(replaced)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{temp_dir, WriteFile};
+
+    fn replace(rule: &str, text: &str) -> String {
+        Rule::parse(rule).expect("valid rule").apply(text).0
+    }
+
+    #[test]
+    fn rules_take_any_delimiter() {
+        assert_eq!(replace("s/foo/bar/", "foo foo"), "bar bar");
+        assert_eq!(replace("s|a/b|c|g", "a/b"), "c");
+        assert_eq!(replace(r"s/a\/b/c/", "a/b"), "c");
+        assert_eq!(replace(r"s/(\w+)@(\w+)/$2 at $1/", "me@host"), "host at me");
+    }
+
+    #[test]
+    fn malformed_rules_are_rejected() {
+        assert!(Rule::parse("x/a/b/").is_err());
+        assert!(Rule::parse("s").is_err());
+        assert!(Rule::parse("sxaxbx").is_err());
+        assert!(Rule::parse("s/a/b").is_err());
+        assert!(Rule::parse("s/a/b/i").is_err());
+        assert!(Rule::parse("s/(/b/").is_err());
+    }
+
+    #[test]
+    fn script_rules_apply_in_order() {
+        let script = "# a to b, then b to c\ns/a/b/\n\ns/b/c/\n";
+        let replacement = Replacement::from_script(script).expect("valid script");
+        assert_eq!(replacement.apply_rules("ab"), ("cc".to_string(), vec![1, 2]));
+
+        assert!(Replacement::from_script("# only a comment\n").is_err());
+    }
+
+    fn regex_replacement(query: &str, replacement: &str) -> Replacement {
+        let config = SearchConfig { query: query.to_string(), use_regex: true, ..SearchConfig::default() };
+        Replacement::new(&config, replacement).expect("valid replacement")
+    }
+
+    #[test]
+    fn case_directives_split_the_replacement() {
+        assert_eq!(case_segments(r"a\Ub\Lc\Ed"), [
+            (Case::Keep, "a".to_string()),
+            (Case::Upper, "b".to_string()),
+            (Case::Lower, "c".to_string()),
+            (Case::Keep, "d".to_string()),
+        ]);
+        assert_eq!(case_segments(r"$1\n"), [(Case::Keep, r"$1\n".to_string())]);
+    }
+
+    #[test]
+    fn upper_and_lower_transform_captures() {
+        let replacement = regex_replacement(r"(\w+)@(\w+)", r"\U$1\E@\L$2");
+        assert_eq!(replacement.apply("Alice@Example.org"), ("ALICE@example.org".to_string(), 1));
+
+        // Up to the end of the replacement without \E
+        let replacement = regex_replacement(r"(\w+) (\w+)", r"$2 \U$1");
+        assert_eq!(replacement.apply("hello world"), ("world HELLO".to_string(), 1));
+
+        // Captured text is never read as a directive
+        let replacement = regex_replacement(r"<(.+)>", r"\L$1");
+        assert_eq!(replacement.apply(r"<A\UB>"), (r"a\ub".to_string(), 1));
+    }
+
+    #[test]
+    fn literal_replacements_keep_directives() {
+        let config = SearchConfig { query: "x".to_string(), ..SearchConfig::default() };
+        let replacement = Replacement::new(&config, r"\U$1").expect("valid replacement");
+        assert_eq!(replacement.apply("axb"), (r"a\U$1b".to_string(), 1));
+    }
+
+    #[test]
+    fn script_rules_transform_case() {
+        assert_eq!(replace(r"s/(\w+)/\U$1/g", "shout it"), "SHOUT IT");
+    }
+
+    #[test]
+    fn limited_changes_stop_after_the_limit() {
+        let file = |path: &str, lines: u64| FileChanges {
+            path: PathBuf::from(path),
+            changes: (1..=lines).map(|line_number| LineChange {
+                line_number,
+                before: "foo".to_string(),
+                after: "bar".to_string(),
+                matches: 1,
+                rule_matches: vec![1],
+            }).collect(),
+        };
+        let files = [file("a.txt", 2), file("b.txt", 3), file("c.txt", 1)];
+        let shape = |files: Vec<FileChanges>| -> Vec<(PathBuf, usize)> {
+            files.into_iter().map(|file| (file.path, file.changes.len())).collect()
+        };
+
+        assert_eq!(shape(limit_changes(&files, 3)), [(PathBuf::from("a.txt"), 2), (PathBuf::from("b.txt"), 1)]);
+        assert_eq!(shape(limit_changes(&files, 2)), [(PathBuf::from("a.txt"), 2)]);
+        assert_eq!(shape(limit_changes(&files, 10)).len(), 3);
+        assert!(limit_changes(&files, 0).is_empty());
+    }
+
+    fn result(path: &Path, line_number: u64, line: &str) -> SearchResult {
+        SearchResult {
+            path: path.to_path_buf(),
+            line_number,
+            line: line.to_string(),
+            matches: Vec::new(),
+            context_before: Vec::new(),
+            context_after: Vec::new(),
+            lossy: false,
+            seq: 0,
+            source: SourceKind::Text,
+        }
+    }
+
+    fn literal_replacement(query: &str, replacement: &str) -> Replacement {
+        let config = SearchConfig { query: query.to_string(), ..SearchConfig::default() };
+        Replacement::new(&config, replacement).expect("valid replacement")
+    }
+
+    #[test]
+    fn lines_changed_since_the_search_are_not_replaced() {
+        let dir = temp_dir("replace-changed");
+        let path = dir.write("a.txt", "keep\nold one\nold two\n");
+        let replacement = literal_replacement("old", "new");
+        let files = plan(&[result(&path, 2, "old one")], &replacement);
+
+        // Another line was inserted above the planned one
+        fs::write(&path, "inserted\nkeep\nold one\nold two\n").expect("edit file");
+        let error = write_in_place(&files[0], &replacement).err().expect("changed file is refused");
+        assert!(error.to_string().contains("changed since the search"), "{}", error);
+        assert_eq!(fs::read_to_string(&path).expect("read file"), "inserted\nkeep\nold one\nold two\n");
+        assert!(!backup_path(&path).exists());
+
+        fs::write(&path, "keep\nold one\nold two\n").expect("restore file");
+        assert_eq!(write_in_place(&files[0], &replacement).expect("replace"), 1);
+        assert_eq!(fs::read_to_string(&path).expect("read file"), "keep\nnew one\nold two\n");
+    }
+
+    #[test]
+    fn lossy_results_are_left_out_of_the_plan() {
+        let replacement = literal_replacement("old", "new");
+        let valid = result(Path::new("a.txt"), 1, "old");
+        let lossy = SearchResult { lossy: true, ..result(Path::new("b.txt"), 1, "caf\u{FFFD} old") };
+        let results = [valid, lossy];
+
+        let files = plan(&results, &replacement);
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, Path::new("a.txt"));
+        assert_eq!(lossy_files(&results), [Path::new("b.txt")]);
+    }
+
+    #[test]
+    fn structured_results_are_never_planned() {
+        let replacement = literal_replacement("old", "new");
+        let text = result(Path::new("a.json"), 1, "\"name\": \"old\"");
+        let structured = SearchResult {
+            source: SourceKind::Structured,
+            ..result(Path::new("b.json"), 1, "$.name = old")
+        };
+        let recognized = SearchResult { source: SourceKind::Ocr, ..result(Path::new("c.png"), 1, "old") };
+
+        let files = plan(&[text, structured, recognized], &replacement);
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, Path::new("a.json"));
+    }
+}
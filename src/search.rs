@@ -1,167 +1,897 @@
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use grep::{
-    regex::RegexMatcher,
+    regex::{RegexMatcher, RegexMatcherBuilder},
     searcher::{
         Searcher, Sink, SinkMatch, SinkContext, SinkContextKind,
-        SearcherBuilder, BinaryDetection, SinkFinish
+        SearcherBuilder, BinaryDetection, SinkFinish, Encoding, LineTerminator, MmapChoice,
     },
     matcher::Matcher,
 };
-use ignore::{DirEntry, WalkBuilder, WalkState};
+use ignore::{gitignore::Gitignore, DirEntry, WalkBuilder, WalkState};
 use std::{
-    path::PathBuf,
+    borrow::Cow,
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    any::Any,
+    fmt,
+    fs::{self, File},
+    io::Read,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
     sync::{
-        mpsc::{self, Sender},
-        Arc, atomic::{AtomicBool, Ordering},
+        Arc, Mutex, atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
     },
     thread,
-    process::Command,
+    time::{Duration, Instant},
+    process::{Command, Output, Stdio},
 };
-use crossbeam_channel;
-use crate::SearchConfig;
-use regex::escape;
+use crossbeam_channel::{self, Sender};
+use flate2::read::GzDecoder;
+use crate::config::excluded_dirs;
+use crate::git::{in_ranges, ChangedLines};
+use crate::extractors::Extractors;
+use crate::matcher::{LiteralMatcher, QueryMatcher};
+use crate::structured::{is_structured, JsonPathQuery};
+use crate::{BinaryMode, MmapMode, SearchConfig, SortFiles};
+use regex::{escape, Regex};
 
+/// Files, bytes and time spent per file extension
+#[derive(Debug, Default, Clone)]
+pub struct TypeStats {
+    pub files: u64,
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl TypeStats {
+    fn merge(&mut self, other: &TypeStats) {
+        self.files += other.files;
+        self.bytes += other.bytes;
+        self.elapsed += other.elapsed;
+    }
+}
+
+pub type StatsByType = Arc<Mutex<HashMap<String, TypeStats>>>;
+
+/// Directory walk errors, counted so unreadable directories don't go unnoticed
+#[derive(Debug, Default)]
+pub struct WalkErrors {
+    pub permission_denied: AtomicUsize,
+    pub other: AtomicUsize,
+}
+
+impl WalkErrors {
+    fn record(&self, error: &ignore::Error, verbose: bool) {
+        let denied = error.io_error()
+            .map_or(false, |e| e.kind() == std::io::ErrorKind::PermissionDenied);
+        if denied {
+            self.permission_denied.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.other.fetch_add(1, Ordering::Relaxed);
+        }
+        if verbose {
+            eprintln!("Walk error: {}", error);
+        }
+    }
+}
+
+/// Paths of all files searched, collected only when requested
+pub type SearchedFiles = Arc<Mutex<Vec<PathBuf>>>;
+
+/// Files whose search panicked, their results may be incomplete
+pub type FailedFiles = Arc<Mutex<Vec<PathBuf>>>;
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload.downcast_ref::<&str>().copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic")
+}
+
+fn extension_key(path: &Path) -> String {
+    path.extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_else(|| "(none)".to_string())
+}
+
+/// Strips the line terminator, `\n` or `\r\n`, but keeps all other whitespace,
+/// so leading indentation and trailing spaces are reported as they are in the
+/// file. Only one `\r` goes, any before it belong to the line
+pub(crate) fn trim_line_ending(line: &str) -> &str {
+    let line = line.strip_suffix('\n').unwrap_or(line);
+    line.strip_suffix('\r').unwrap_or(line)
+}
+
+/// How file globs match. Case sensitive on every platform unless --iglob is
+/// given, also on macOS and Windows where the file system itself ignores case
+fn match_options(ignore_case: bool) -> glob::MatchOptions {
+    glob::MatchOptions {
+        case_sensitive: !ignore_case,
+        // `*` and `?` stay within a directory, only `**` crosses into subdirectories
+        require_literal_separator: true,
+        require_literal_leading_dot: false,
+    }
+}
+
+/// The alternatives of the first `{a,b}` group in `pattern`, each expanded
+/// again, so `*.{txt,md}` gives `*.txt` and `*.md`. Groups nest, `\{` and
+/// braces inside `[...]` are literal, and a brace without a comma or a
+/// matching `}` is kept as written
+fn expand_braces(pattern: &str) -> Vec<String> {
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut i = 0;
+    let mut in_class = false;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 1,
+            '[' if !in_class => in_class = true,
+            ']' if in_class => in_class = false,
+            '{' if !in_class => {
+                if let Some((end, commas)) = brace_group(&chars, i) {
+                    if !commas.is_empty() {
+                        let prefix: String = chars[..i].iter().collect();
+                        let suffix: String = chars[end + 1..].iter().collect();
+                        let mut start = i + 1;
+                        let mut expanded = Vec::new();
+                        for stop in commas.into_iter().chain(std::iter::once(end)) {
+                            let alternative: String = chars[start..stop].iter().collect();
+                            expanded.extend(expand_braces(&format!("{}{}{}", prefix, alternative, suffix)));
+                            start = stop + 1;
+                        }
+                        return expanded;
+                    }
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    vec![pattern.to_string()]
+}
+
+/// The closing brace of the group opening at `open` and the positions of its
+/// top level commas
+fn brace_group(chars: &[char], open: usize) -> Option<(usize, Vec<usize>)> {
+    let mut depth = 0;
+    let mut commas = Vec::new();
+    let mut i = open + 1;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 1,
+            '{' => depth += 1,
+            '}' if depth == 0 => return Some((i, commas)),
+            '}' => depth -= 1,
+            ',' if depth == 0 => commas.push(i),
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+/// One file glob, with its `{a,b}` groups expanded. Globs containing a `/`
+/// match the path below the search root, like `src/**/*.rs` or
+/// `**/test_*.rs`, the others only the file name
+struct FileGlob {
+    patterns: Vec<glob::Pattern>,
+    path: bool,
+    options: glob::MatchOptions,
+}
+
+impl FileGlob {
+    fn new(pattern: &str, ignore_case: bool) -> Option<Self> {
+        let path = pattern.contains('/');
+        let patterns = expand_braces(pattern).iter()
+            .map(|pattern| glob::Pattern::new(pattern).ok())
+            .collect::<Option<Vec<_>>>()?;
+        Some(Self { patterns, path, options: match_options(ignore_case) })
+    }
+
+    fn matches(&self, file_name: &str, relative: &Path) -> bool {
+        if self.path {
+            self.patterns.iter().any(|pattern| pattern.matches_path_with(relative, self.options))
+        } else {
+            self.matches_name(file_name)
+        }
+    }
+
+    /// Matches a single name, used for directory names while walking
+    fn matches_name(&self, name: &str) -> bool {
+        self.patterns.iter().any(|pattern| pattern.matches_with(name, self.options))
+    }
+}
+
+/// Compiled file patterns. A pattern starting with `!` excludes files the
+/// other patterns would include, `\!` matches a literal leading bang.
+struct FilePatterns {
+    include: Vec<FileGlob>,
+    exclude: Vec<FileGlob>,
+    match_all: bool,
+    /// Search roots, path globs match the path below the root holding a file
+    roots: Vec<PathBuf>,
+    /// File name regex from --name, which names must match as well
+    name: Option<Regex>,
+    /// Skips the `.bak` copies an in-place replace leaves behind
+    skip_backups: bool,
+}
+
+impl FilePatterns {
+    fn new(patterns: &[String], ignore_case: bool) -> Self {
+        let mut include = Vec::new();
+        let mut exclude = Vec::new();
+        let mut has_positive = false;
+
+        for pattern in patterns {
+            if let Some(negated) = pattern.strip_prefix('!') {
+                exclude.extend(FileGlob::new(negated, ignore_case));
+            } else {
+                has_positive = true;
+                // `!` is not special in glob syntax outside of brackets
+                let literal = pattern.strip_prefix("\\!")
+                    .map(|rest| format!("!{}", rest));
+                include.extend(FileGlob::new(literal.as_deref().unwrap_or(pattern), ignore_case));
+            }
+        }
+
+        Self {
+            include,
+            exclude,
+            // Only exclusions given, everything else is included
+            match_all: !has_positive,
+            roots: Vec::new(),
+            name: None,
+            skip_backups: false,
+        }
+    }
+
+    /// The file patterns of `config`, including its --name regex
+    fn for_files(config: &SearchConfig) -> Result<Self> {
+        let name = config.name_pattern.as_deref()
+            .map(|pattern| Regex::new(pattern).with_context(|| format!("Invalid --name regex '{}'", pattern)))
+            .transpose()?;
+        Ok(Self {
+            roots: config.search_roots(),
+            name,
+            skip_backups: config.skip_backups,
+            ..Self::new(&config.patterns, config.glob_ignore_case)
+        })
+    }
+
+    /// `path` below the deepest search root holding it. A file that is a root
+    /// itself, or isn't below one, is just its name
+    fn relative<'p>(&self, path: &'p Path) -> &'p Path {
+        self.roots.iter()
+            .filter_map(|root| path.strip_prefix(root).ok())
+            .filter(|relative| !relative.as_os_str().is_empty())
+            .min_by_key(|relative| relative.components().count())
+            .or_else(|| path.file_name().map(Path::new))
+            .unwrap_or(path)
+    }
+
+    fn matches(&self, path: &Path) -> bool {
+        let file_name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+        let file_name = file_name.as_ref();
+        let relative = self.relative(path);
+        (self.match_all || self.include.iter().any(|p| p.matches(file_name, relative)))
+            && !self.exclude.iter().any(|p| p.matches(file_name, relative))
+            && self.name.as_ref().map_or(true, |name| name.is_match(file_name))
+            && !(self.skip_backups && file_name.ends_with(".bak"))
+    }
+}
+
+/// Whether the walker descends into `entry`. The search roots are always entered.
+/// Other directories are pruned when their name is excluded, or when include
+/// patterns were given and neither the directory nor one of its parents below
+/// the root matches one
+fn enter_dir(dirs: &FilePatterns, entry: &DirEntry) -> bool {
+    if entry.depth() == 0 || !entry.file_type().map_or(false, |ft| ft.is_dir()) {
+        return true;
+    }
+
+    let name = entry.file_name().to_string_lossy();
+    if dirs.exclude.iter().any(|p| p.matches_name(&name)) {
+        return false;
+    }
+
+    dirs.match_all || entry.path()
+        .components()
+        .rev()
+        .take(entry.depth())
+        .any(|component| {
+            let component = component.as_os_str().to_string_lossy();
+            dirs.include.iter().any(|p| p.matches_name(&component))
+        })
+}
+
+/// Whether `entry` is one of the resolved `excluded` directories. The search
+/// roots are never excluded, so they can still be searched on purpose
+fn is_excluded_dir(excluded: &[PathBuf], entry: &DirEntry) -> bool {
+    if entry.depth() == 0 || !entry.file_type().map_or(false, |ft| ft.is_dir()) {
+        return false;
+    }
+    // Only directories of the same name are resolved, which keeps it cheap
+    excluded.iter().any(|dir| {
+        dir.file_name() == Some(entry.file_name())
+            && fs::canonicalize(entry.path()).map_or(false, |path| &path == dir)
+    })
+}
+
+/// Whether `entry` is a directory whose name matches one of `names`. Like
+/// [`is_excluded_dir`], the search roots themselves are always walked
+fn has_excluded_name(names: &[glob::Pattern], entry: &DirEntry) -> bool {
+    entry.depth() > 0
+        && entry.file_type().map_or(false, |ft| ft.is_dir())
+        && names.iter().any(|name| name.matches(&entry.file_name().to_string_lossy()))
+}
+
+// Limits memory on lines with a huge number of hits
+const MAX_SPANS_PER_LINE: usize = 64;
+
+/// Byte offsets `(start, end)` of the matches within a line
+fn match_spans(matcher: &QueryMatcher, line: &[u8]) -> Vec<(usize, usize)> {
+    let mut spans = Vec::new();
+    let _ = matcher.find_iter(line, |m| {
+        spans.push((m.start(), m.end()));
+        spans.len() < MAX_SPANS_PER_LINE
+    });
+    spans
+}
+
+/// Where the lines of a result come from. Only text files have real lines,
+/// the others are numbered as extracted or decompressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SourceKind {
+    Text,
+    /// Text extracted from a PDF, lines and context follow the extracted text
+    Pdf,
+    /// A decompressed .gz file
+    Compressed,
+    /// Text recognized in an image with --ocr
+    Ocr,
+    /// Text returned by a registered extractor, see [`Extractors`]
+    Extracted,
+    /// A value of a JSON or YAML file selected with --jsonpath, the line is
+    /// `location = value` and the line number its place in the selection
+    Structured,
+}
+
+impl SourceKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SourceKind::Text => "text",
+            SourceKind::Pdf => "pdf",
+            SourceKind::Compressed => "compressed",
+            SourceKind::Ocr => "ocr",
+            SourceKind::Extracted => "extracted",
+            SourceKind::Structured => "structured",
+        }
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SearchResult {
     pub path: PathBuf,
     pub line_number: u64,
     pub line: String,
+    /// Byte offsets of every match in `line`, capped per line
+    pub matches: Vec<(usize, usize)>,
     pub context_before: Vec<(u64, String)>,
     pub context_after: Vec<(u64, String)>,
+    /// The line was not valid UTF-8, invalid bytes show as U+FFFD
+    pub lossy: bool,
+    /// Position of the file in the order files were handed to the workers.
+    /// Always 0 for the parallel walk, whose order isn't reproducible anyway
+    pub seq: u64,
+    pub source: SourceKind,
+}
+
+impl SearchResult {
+    /// Width of the line number gutter: fits every line number of this result
+    /// plus the `>` match marker, and is never narrower than 3 columns
+    pub fn gutter_width(&self) -> usize {
+        let max_line = self.context_after.last()
+            .map_or(self.line_number, |(line_num, _)| *line_num);
+        (max_line.to_string().len() + 1).max(3)
+    }
+}
+
+/// Global cap on match occurrences for --max-matches-total, shared by all workers
+#[derive(Clone)]
+struct MatchLimit {
+    found: Arc<AtomicUsize>,
+    max: usize,
+    quit: Arc<AtomicBool>,
+    no_messages: bool,
+}
+
+impl MatchLimit {
+    /// Adds the matches of one line to the total, false once the cap is reached.
+    /// Workers count concurrently and each reports the line that crossed the cap,
+    /// so the total can overshoot by up to one line per worker
+    fn count(&self, matches: usize) -> bool {
+        let before = self.found.fetch_add(matches, Ordering::Relaxed);
+        if before + matches < self.max {
+            return true;
+        }
+        // Only the worker reaching the cap warns
+        if before < self.max && !self.no_messages {
+            eprintln!("Warning: found {} matches (--max-matches-total), stopping", self.max);
+        }
+        self.quit.store(true, Ordering::Relaxed);
+        false
+    }
+}
+
+/// Caps the results per directory for --max-per-dir. Results are counted as
+/// they leave [`search`], so the cap is exact, while workers skip the files of
+/// directories that are full already. Files searched at the same time race for
+/// the last places, which results are kept depends on the timing
+#[derive(Clone)]
+struct DirLimit {
+    max: usize,
+    counts: Arc<Mutex<HashMap<PathBuf, usize>>>,
+}
+
+impl DirLimit {
+    fn is_full(&self, path: &Path) -> bool {
+        let dir = path.parent().unwrap_or(Path::new(""));
+        self.counts.lock().unwrap().get(dir).map_or(false, |&count| count >= self.max)
+    }
+
+    /// Counts a result of `path`, false if its directory has no place left
+    fn admit(&self, path: &Path) -> bool {
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(dir.to_path_buf()).or_default();
+        if *count >= self.max {
+            return false;
+        }
+        *count += 1;
+        true
+    }
 }
 
+// Results are sent in batches of up to this many, so heavily matching files
+// don't make every worker contend on the channel for each line
+const RESULT_BATCH_SIZE: usize = 256;
+
 struct SearchSink<'a> {
-    tx: &'a Sender<SearchResult>,
+    tx: &'a Sender<Vec<SearchResult>>,
+    matcher: &'a QueryMatcher,
     path: PathBuf,
-    context_before: Vec<String>,
-    context_after: Vec<String>,
+    seq: u64,
+    source: SourceKind,
+    context_before: Vec<(u64, String)>,
+    context_after: Vec<(u64, String)>,
     context_lines: usize,
+    /// Stop at the first match of the file, once its context is collected
+    first_match: bool,
+    match_limit: Option<&'a MatchLimit>,
+    /// Line ranges matches have to fall in, other matching lines count as context
+    lines: Option<&'a [(u64, u64)]>,
+    /// Neighbouring matches share their context lines, otherwise every match
+    /// gets its full context and `recent` and `open` are used instead
+    merge_context: bool,
+    // The last `context_lines` lines seen, matches included
+    recent: Vec<(u64, String)>,
+    // Matches still collecting their after context, in line order
+    open: Vec<SearchResult>,
     last_match: Option<SearchResult>,
+    pending: Vec<SearchResult>,
 }
 
 impl<'a> SearchSink<'a> {
-    fn new(tx: &'a Sender<SearchResult>, path: PathBuf, context_lines: usize) -> Self {
+    fn new(
+        tx: &'a Sender<Vec<SearchResult>>,
+        matcher: &'a QueryMatcher,
+        path: PathBuf,
+        seq: u64,
+        context_lines: usize,
+        first_match: bool,
+        match_limit: Option<&'a MatchLimit>,
+    ) -> Self {
         SearchSink {
             tx,
+            matcher,
             path,
+            seq,
+            source: SourceKind::Text,
             context_before: Vec::new(),
             context_after: Vec::new(),
             context_lines,
+            first_match,
+            match_limit,
+            lines: None,
+            merge_context: true,
+            recent: Vec::new(),
+            open: Vec::new(),
             last_match: None,
+            pending: Vec::new(),
+        }
+    }
+
+    fn with_source(mut self, source: SourceKind) -> Self {
+        self.source = source;
+        self
+    }
+
+    fn with_lines(mut self, lines: Option<&'a [(u64, u64)]>) -> Self {
+        self.lines = lines;
+        self
+    }
+
+    fn with_merge_context(mut self, merge_context: bool) -> Self {
+        self.merge_context = merge_context;
+        self
+    }
+
+    /// Without merging: adds a line, match or context, after every open match
+    /// it is in reach of and keeps it for the before context of later matches
+    fn record_line(&mut self, line: (u64, String)) {
+        let reach = self.context_lines as u64;
+        for result in &mut self.open {
+            if line.0 <= result.line_number + reach {
+                result.context_after.push(line.clone());
+            }
+        }
+        // Matches with a complete after context go out
+        while self.open.first().map_or(false, |result| line.0 >= result.line_number + reach) {
+            let result = self.open.remove(0);
+            self.queue(result);
+        }
+        self.recent.push(line);
+        if self.recent.len() > self.context_lines {
+            self.recent.remove(0);
+        }
+    }
+
+    /// Adds a line after the current match while its after context has room,
+    /// otherwise before the next match. Past the after context only happens
+    /// when matches outside `lines` were skipped
+    fn push_context(&mut self, line: (u64, String), after: bool) {
+        if after && self.last_match.is_some() && self.context_after.len() < self.context_lines {
+            self.context_after.push(line);
+            return;
+        }
+        self.context_before.push(line);
+        if self.context_before.len() > self.context_lines {
+            self.context_before.remove(0);
         }
     }
 
     fn send_last_match(&mut self) {
         if let Some(mut result) = self.last_match.take() {
-            result.context_after = self.context_after.iter()
-                .enumerate()
-                .map(|(i, line)| (
-                    result.line_number + i as u64 + 1,
-                    line.clone()
-                ))
-                .collect();
-            self.tx.send(result).unwrap();
-            self.context_after.clear();
+            result.context_after = std::mem::take(&mut self.context_after);
+            self.queue(result);
+        }
+        for result in std::mem::take(&mut self.open) {
+            self.queue(result);
+        }
+    }
+
+    fn queue(&mut self, result: SearchResult) {
+        self.pending.push(result);
+        if self.pending.len() >= RESULT_BATCH_SIZE {
+            self.flush();
+        }
+    }
+
+    // Batches of one file go out in order from one thread, so results of a file stay in line order
+    fn flush(&mut self) {
+        if !self.pending.is_empty() {
+            // Fails only once the receiver is dropped, nobody wants the results then
+            let _ = self.tx.send(std::mem::take(&mut self.pending));
         }
     }
+
+    /// Sends what was found and tells the searcher to skip the rest of the file
+    fn stop(&mut self) -> Result<bool, std::io::Error> {
+        self.send_last_match();
+        self.flush();
+        Ok(false)
+    }
 }
 
 impl<'a> Sink for SearchSink<'a> {
     type Error = std::io::Error;
 
     fn matched(&mut self, _searcher: &Searcher, mat: &SinkMatch<'_>) -> Result<bool, Self::Error> {
-        self.send_last_match();
+        let line_number = mat.line_number().expect("searcher reports line numbers");
+        if self.lines.map_or(false, |lines| !in_ranges(lines, line_number)) {
+            // Kept as context of the matches around it
+            let line = trim_line_ending(&String::from_utf8_lossy(mat.bytes())).to_string();
+            if self.merge_context {
+                self.push_context((line_number, line), true);
+            } else {
+                self.record_line((line_number, line));
+            }
+            return Ok(true);
+        }
+        // A second match inside the first one's after context ends that context early
+        if self.first_match && self.last_match.is_some() {
+            return self.stop();
+        }
+        // Unmerged matches stay open, this line may still be after context of theirs
+        if self.merge_context {
+            self.send_last_match();
+        }
 
-        if let Ok(line) = String::from_utf8(mat.bytes().to_vec()) {
-            let result = SearchResult {
-                path: self.path.clone(),
-                line_number: mat.line_number().unwrap_or(0),
-                line: line.trim().to_string(),
-                context_before: self.context_before.iter()
-                    .enumerate()
-                    .map(|(i, line)| (
-                        mat.line_number().unwrap_or(0) - (self.context_before.len() - i) as u64,
-                        line.clone()
-                    ))
-                    .collect(),
-                context_after: Vec::new(),
-            };
-            
+        // Invalid bytes are replaced rather than dropping the match
+        let line = String::from_utf8_lossy(mat.bytes());
+        let lossy = matches!(line, Cow::Owned(_));
+        let line = trim_line_ending(&line).to_string();
+        let context_before = if self.merge_context {
+            std::mem::take(&mut self.context_before)
+        } else {
+            // Earlier matches within reach are context of this one as well
+            let reach = self.context_lines as u64;
+            let before = self.recent.iter()
+                .filter(|(line_num, _)| line_num + reach >= line_number)
+                .cloned()
+                .collect();
+            self.record_line((line_number, line.clone()));
+            before
+        };
+        let result = SearchResult {
+            path: self.path.clone(),
+            line_number,
+            matches: match_spans(self.matcher, line.as_bytes()),
+            line,
+            context_before,
+            context_after: Vec::new(),
+            lossy,
+            seq: self.seq,
+            source: self.source,
+        };
+        let matches = result.matches.len();
+
+        if self.merge_context {
             self.last_match = Some(result);
             self.context_after.clear();
+        } else {
+            self.open.push(result);
+        }
+        if self.first_match && self.context_lines == 0 {
+            return self.stop();
+        }
+        // The match that reaches the cap is still reported, without its after context
+        if self.match_limit.map_or(false, |limit| !limit.count(matches)) {
+            return self.stop();
         }
         Ok(true)
     }
 
     fn context(&mut self, _searcher: &Searcher, ctx: &SinkContext<'_>) -> Result<bool, Self::Error> {
-        if let Ok(line) = String::from_utf8(ctx.bytes().to_vec()) {
-            match ctx.kind() {
-                SinkContextKind::Before => {
-                    self.context_before.push(line.trim().to_string());
-                    if self.context_before.len() > self.context_lines {
-                        self.context_before.remove(0);
-                    }
-                }
-                SinkContextKind::After => {
-                    if self.context_after.len() < self.context_lines {
-                        self.context_after.push(line.trim().to_string());
-                    }
+        let line = String::from_utf8_lossy(ctx.bytes());
+        let line_number = ctx.line_number().expect("searcher reports line numbers");
+        let line = (line_number, trim_line_ending(&line).to_string());
+        if !self.merge_context {
+            self.record_line(line);
+            return Ok(true);
+        }
+        match ctx.kind() {
+            SinkContextKind::Before => self.push_context(line, false),
+            SinkContextKind::After => {
+                self.push_context(line, true);
+                if self.first_match && self.last_match.is_some() && self.context_after.len() == self.context_lines {
+                    return self.stop();
                 }
-                SinkContextKind::Other => {}
             }
+            SinkContextKind::Other => {}
         }
         Ok(true)
     }
 
     fn finish(&mut self, _searcher: &Searcher, _: &SinkFinish) -> Result<(), Self::Error> {
         self.send_last_match();
+        self.flush();
         Ok(())
     }
 }
 
-fn search_pdf(path: &std::path::Path, matcher: &RegexMatcher, tx: &Sender<SearchResult>, verbose: bool, context_lines: usize) -> Result<()> {
-    let path_buf = path.to_path_buf();
-    
-    let result = std::panic::catch_unwind(|| {
-        let output = Command::new("pdftotext")
-            .arg(path.to_str().unwrap())
-            .arg("-")
-            .arg("-q")
-            .output()
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, 
-                format!("Failed to run pdftotext: {}", e)))?;
+/// Page index of every extracted PDF line. `pdftotext` ends each page with a
+/// form feed, so it shows up at the start of the next page's first line.
+fn pdf_line_pages(lines: &[&str]) -> Vec<usize> {
+    let mut page = 0;
+    lines.iter()
+        .map(|line| {
+            page += line.matches('\x0c').count();
+            page
+        })
+        .collect()
+}
+
+/// Runs an external extractor and collects its stdout, killing it as soon as
+/// `quit` is set. Returns None if the search was cancelled.
+fn run_cancellable(command: &mut Command, quit: &AtomicBool) -> std::io::Result<Option<Output>> {
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+
+    // Drain stdout separately so a full pipe can't stall the child
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let reader = thread::spawn(move || {
+        let mut buffer = Vec::new();
+        stdout.read_to_end(&mut buffer).map(|_| buffer)
+    });
 
-        if !output.status.success() {
-            if verbose {
-                eprintln!("Failed to process PDF {} (no error message)", path.display());
+    loop {
+        if quit.load(Ordering::Relaxed) {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Ok(None);
+        }
+
+        if let Some(status) = child.try_wait()? {
+            let stdout = reader.join().unwrap_or_else(|_| Ok(Vec::new()))?;
+            return Ok(Some(Output { status, stdout, stderr: Vec::new() }));
+        }
+
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+/// Default PDF extractor and its arguments, `{}` stands for the PDF path
+pub const DEFAULT_PDF_COMMAND: &str = "pdftotext";
+pub const DEFAULT_PDF_ARGS: [&str; 3] = ["-q", "{}", "-"];
+
+/// How PDF text is extracted and searched
+#[derive(Clone)]
+struct PdfOptions {
+    command: String,
+    args: Vec<String>,
+    /// Keep the extracted text verbatim instead of the layout-friendly default
+    raw: bool,
+    /// Collapse whitespace runs and drop blank lines
+    normalize: bool,
+    /// Join the lines of each paragraph into one
+    paragraphs: bool,
+    context_lines: usize,
+    first_match: bool,
+    verbose: bool,
+}
+
+impl PdfOptions {
+    /// Builds the extractor command; the path replaces a `{}` argument,
+    /// or is appended when there is none
+    fn command(&self, path: &Path) -> Command {
+        let mut command = Command::new(&self.command);
+        let mut has_placeholder = false;
+
+        // Content stream order, only understood by pdftotext
+        if self.raw && self.command == DEFAULT_PDF_COMMAND {
+            command.arg("-raw");
+        }
+
+        for arg in &self.args {
+            if arg == "{}" {
+                command.arg(path);
+                has_placeholder = true;
+            } else {
+                command.arg(arg);
             }
-            return Ok(());
         }
+        if !has_placeholder {
+            command.arg(path);
+        }
+        command
+    }
+
+    /// Strips surrounding whitespace and form feeds, unless raw output was requested
+    fn clean<'l>(&self, line: &'l str) -> &'l str {
+        if self.raw {
+            line
+        } else {
+            line.trim()
+        }
+    }
+}
+
+/// Joins the lines up to each blank line or page break into one paragraph, with
+/// the whitespace collapsed. Returns the paragraphs and the page of each
+fn pdf_paragraphs(lines: &[&str], pages: &[usize]) -> (Vec<Cow<'static, str>>, Vec<usize>) {
+    let mut paragraphs = Vec::new();
+    let mut paragraph_pages = Vec::new();
+    let mut words: Vec<&str> = Vec::new();
+    let mut page = pages.first().copied().unwrap_or(0);
+
+    for (line, &line_page) in lines.iter().zip(pages) {
+        if (line.trim().is_empty() || line_page != page) && !words.is_empty() {
+            paragraphs.push(Cow::Owned(words.join(" ")));
+            paragraph_pages.push(page);
+            words.clear();
+        }
+        page = line_page;
+        words.extend(line.split_whitespace());
+    }
+    if !words.is_empty() {
+        paragraphs.push(Cow::Owned(words.join(" ")));
+        paragraph_pages.push(page);
+    }
+    (paragraphs, paragraph_pages)
+}
+
+/// The built-in PDF extractor, registered for `.pdf` as the reference
+/// [`Extractor`](crate::extractors::Extractor). A cancelled run gives no text
+fn extract_pdf(path: &Path, options: &PdfOptions, quit: &AtomicBool) -> Result<String> {
+    let output = run_cancellable(&mut options.command(path), quit)
+        .with_context(|| format!("Failed to run {}", options.command))?;
+
+    // Cancelled while extracting
+    let output = match output {
+        Some(output) => output,
+        None => return Ok(String::new()),
+    };
+    if !output.status.success() {
+        bail!("{} failed on {}", options.command, path.display());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
 
-        let text = String::from_utf8_lossy(&output.stdout).to_string();
+/// Searches the extracted `text` of a PDF, keeping context on the page of each match
+fn search_pdf(
+    path: &std::path::Path,
+    seq: u64,
+    text: &str,
+    matcher: &QueryMatcher,
+    tx: &Sender<Vec<SearchResult>>,
+    options: &PdfOptions,
+    match_limit: Option<&MatchLimit>,
+) -> Result<()> {
+    let path_buf = path.to_path_buf();
+    
+    let result = std::panic::catch_unwind(|| {
         let lines: Vec<&str> = text.lines().collect();
+        let pages = pdf_line_pages(&lines);
+        // Pages are found first, normalizing drops the form feeds marking them
+        let (lines, pages): (Vec<Cow<str>>, Vec<usize>) = if options.paragraphs {
+            pdf_paragraphs(&lines, &pages)
+        } else if options.normalize {
+            lines.iter()
+                .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+                .zip(pages)
+                .filter(|(line, _)| !line.is_empty())
+                .map(|(line, page)| (Cow::Owned(line), page))
+                .unzip()
+        } else {
+            (lines.into_iter().map(Cow::Borrowed).collect(), pages)
+        };
+        let mut results = Vec::new();
         
         for (line_number, line) in lines.iter().enumerate() {
-            let trimmed = line.trim();
+            let trimmed = options.clean(line);
             if !trimmed.is_empty() && matcher.is_match(trimmed.as_bytes())? {
                 let line_num = (line_number + 1) as u64;
+
+                // Keep context on the same page as the match
+                let page = pages[line_number];
+                let mut start = line_number.saturating_sub(options.context_lines);
+                while pages[start] != page {
+                    start += 1;
+                }
+                let mut end = std::cmp::min(line_number + 1 + options.context_lines, lines.len());
+                while pages[end - 1] != page {
+                    end -= 1;
+                }
                 
                 // Collect context before
-                let context_before: Vec<(u64, String)> = lines[line_number.saturating_sub(context_lines)..line_number]
+                let context_before: Vec<(u64, String)> = lines[start..line_number]
                     .iter()
                     .enumerate()
-                    .map(|(i, &l)| (
-                        (line_num - (context_lines - i) as u64),
-                        l.trim().to_string()
+                    .map(|(i, l)| (
+                        (start + i + 1) as u64,
+                        options.clean(l).to_string()
                     ))
                     .collect();
 
                 // Collect context after
-                let context_after: Vec<(u64, String)> = lines[line_number + 1..std::cmp::min(line_number + 1 + context_lines, lines.len())]
+                let context_after: Vec<(u64, String)> = lines[line_number + 1..end]
                     .iter()
                     .enumerate()
-                    .map(|(i, &l)| (
+                    .map(|(i, l)| (
                         line_num + i as u64 + 1,
-                        l.trim().to_string()
+                        options.clean(l).to_string()
                     ))
                     .collect();
 
@@ -169,15 +899,28 @@ fn search_pdf(path: &std::path::Path, matcher: &RegexMatcher, tx: &Sender<Search
                     path: path_buf.clone(),
                     line_number: line_num,
                     line: trimmed.to_string(),
+                    matches: match_spans(matcher, trimmed.as_bytes()),
                     context_before,
                     context_after,
+                    lossy: false,
+                    seq,
+                    source: SourceKind::Pdf,
                 };
-                
-                tx.send(result).map_err(|_| {
-                    std::io::Error::new(std::io::ErrorKind::Other, "Failed to send result")
-                })?;
+                let matches = result.matches.len();
+
+                results.push(result);
+                if options.first_match || match_limit.map_or(false, |limit| !limit.count(matches)) {
+                    break;
+                }
             }
         }
+
+        // A PDF is extracted in one go, so its results go out as one batch
+        if !results.is_empty() {
+            tx.send(results).map_err(|_| {
+                std::io::Error::new(std::io::ErrorKind::Other, "Failed to send result")
+            })?;
+        }
         
         Ok(())
     });
@@ -185,7 +928,7 @@ fn search_pdf(path: &std::path::Path, matcher: &RegexMatcher, tx: &Sender<Search
     match result {
         Ok(res) => res,
         Err(_) => {
-            if verbose {
+            if options.verbose {
                 eprintln!("Failed to process PDF {} (no error message)", path_buf.display());
             }
             Ok(())
@@ -193,6 +936,136 @@ fn search_pdf(path: &std::path::Path, matcher: &RegexMatcher, tx: &Sender<Search
     }
 }
 
+/// OCR engine for --ocr, it prints the text of the image given first with `stdout`
+pub const OCR_COMMAND: &str = "tesseract";
+/// Extensions of the images --ocr reads text from
+const OCR_EXTENSIONS: [&str; 5] = ["png", "jpg", "jpeg", "tif", "tiff"];
+
+fn is_image(path: &Path) -> bool {
+    path.extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .map_or(false, |ext| OCR_EXTENSIONS.contains(&ext.as_str()))
+}
+
+/// Runs OCR on an image and searches the recognized text, like [`search_pdf`]
+/// does with the text of a PDF. Lines are numbered as recognized
+fn search_image(
+    path: &Path,
+    seq: u64,
+    matcher: &QueryMatcher,
+    tx: &Sender<Vec<SearchResult>>,
+    options: &PdfOptions,
+    match_limit: Option<&MatchLimit>,
+    quit: &AtomicBool,
+) -> Result<()> {
+    let mut command = Command::new(OCR_COMMAND);
+    command.arg(path).arg("stdout");
+    let output = match run_cancellable(&mut command, quit).with_context(|| format!("Failed to run {}", OCR_COMMAND))? {
+        Some(output) => output,
+        // Cancelled while recognizing
+        None => return Ok(()),
+    };
+    if !output.status.success() {
+        bail!("{} failed on {}", OCR_COMMAND, path.display());
+    }
+
+    let text = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<&str> = text.lines().map(str::trim).collect();
+    let numbered = |start: usize, lines: &[&str]| -> Vec<(u64, String)> {
+        lines.iter().enumerate().map(|(i, line)| ((start + i + 1) as u64, line.to_string())).collect()
+    };
+
+    let mut results = Vec::new();
+    for (index, line) in lines.iter().enumerate() {
+        if line.is_empty() || !matcher.is_match(line.as_bytes())? {
+            continue;
+        }
+        let start = index.saturating_sub(options.context_lines);
+        let end = (index + 1 + options.context_lines).min(lines.len());
+        let result = SearchResult {
+            path: path.to_path_buf(),
+            line_number: index as u64 + 1,
+            line: line.to_string(),
+            matches: match_spans(matcher, line.as_bytes()),
+            context_before: numbered(start, &lines[start..index]),
+            context_after: numbered(index + 1, &lines[index + 1..end]),
+            lossy: false,
+            seq,
+            source: SourceKind::Ocr,
+        };
+        let matches = result.matches.len();
+
+        results.push(result);
+        if options.first_match || match_limit.map_or(false, |limit| !limit.count(matches)) {
+            break;
+        }
+    }
+
+    // Like a PDF, an image is recognized in one go and its results go out as one batch
+    if !results.is_empty() {
+        let _ = tx.send(results);
+    }
+    Ok(())
+}
+
+fn build_matcher(config: &SearchConfig) -> Result<QueryMatcher> {
+    // An empty pattern matches every line of every file
+    if config.query.is_empty() && !config.match_empty {
+        bail!("No search text provided, use -t TEXT (or --match-empty to print every line)");
+    }
+
+    // Literal queries are escaped, so syntax flags must not change their meaning
+    if !config.use_regex {
+        // Plain substring search skips the regex engine entirely
+        if !config.query.contains('\n') {
+            return Ok(QueryMatcher::Literal(LiteralMatcher::new(&config.query).with_crlf(config.crlf)));
+        }
+        return Ok(QueryMatcher::Regex(RegexMatcher::new(&escape(&config.query))?));
+    }
+
+    if config.pcre2 {
+        return build_pcre2_matcher(config);
+    }
+
+    let matcher = RegexMatcherBuilder::new()
+        .dot_matches_new_line(config.dot_all)
+        .multi_line(config.multi_line)
+        .ignore_whitespace(config.ignore_whitespace)
+        .crlf(config.crlf)
+        .build(&config.query);
+    match matcher {
+        Ok(matcher) => Ok(QueryMatcher::Regex(matcher)),
+        Err(_) if has_lookaround(&config.query) => {
+            bail!("Lookaround is not supported by the default regex engine, search with -P/--pcre2")
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn has_lookaround(pattern: &str) -> bool {
+    ["(?=", "(?!", "(?<=", "(?<!"].iter().any(|group| pattern.contains(group))
+}
+
+#[cfg(feature = "pcre2")]
+fn build_pcre2_matcher(config: &SearchConfig) -> Result<QueryMatcher> {
+    let matcher = grep_pcre2::RegexMatcherBuilder::new()
+        .dotall(config.dot_all)
+        .multi_line(config.multi_line)
+        .extended(config.ignore_whitespace)
+        .crlf(config.crlf)
+        .jit_if_available(true)
+        .build(&config.query)?;
+    Ok(QueryMatcher::Pcre2(matcher))
+}
+
+#[cfg(not(feature = "pcre2"))]
+fn build_pcre2_matcher(_config: &SearchConfig) -> Result<QueryMatcher> {
+    bail!("quicksearch was built without PCRE2 support, rebuild with `--features pcre2` to use -P")
+}
+
+/// Collects all results of a search. A file whose search fails doesn't end
+/// the search: the results found before the failure are still returned and
+/// the file is added to `config.failed_files`
 pub fn search_files(
     config: &SearchConfig,
     quit: Arc<AtomicBool>
@@ -202,114 +1075,857 @@ pub fn search_files(
     Ok(results)
 }
 
-pub fn search(
-    config: &SearchConfig,
-    quit: Arc<AtomicBool>
-) -> Result<impl Iterator<Item = SearchResult>> {
-    let (tx, rx) = mpsc::channel();
-    let quit = quit.clone();
+/// Everything a worker thread needs to search files
+#[derive(Clone)]
+struct Worker {
+    tx: Sender<Vec<SearchResult>>,
+    matcher: QueryMatcher,
+    quit: Arc<AtomicBool>,
+    pdf_options: PdfOptions,
+    context_lines: usize,
+    first_match: bool,
+    merge_context: bool,
+    crlf: bool,
+    binary_detection: BinaryDetection,
+    /// Files with a larger share of NUL bytes in their first `BINARY_SAMPLE_BYTES` are skipped
+    binary_threshold: Option<f64>,
+    verbose: bool,
+    /// File name globs and the encoding used to decode matching files
+    encodings: Arc<Vec<(glob::Pattern, Encoding)>>,
+    files_processed: Arc<AtomicUsize>,
+    bytes_searched: Arc<AtomicU64>,
+    max_total_bytes: Option<u64>,
+    match_limit: Option<MatchLimit>,
+    mmap: MmapMode,
+    search_pdfs: bool,
+    search_compressed: bool,
+    ocr: bool,
+    jsonpath: Option<Arc<JsonPathQuery>>,
+    extractors: Extractors,
+    no_messages: bool,
+    // Set once the large file hint was printed, shared by all workers
+    large_file_hinted: Arc<AtomicBool>,
+    // Set once the missing OCR engine was reported
+    ocr_missing_warned: Arc<AtomicBool>,
+    stats_by_type: Option<StatsByType>,
+    searched_files: Option<SearchedFiles>,
+    failed_files: FailedFiles,
+    // Resolved paths of the files searched so far, only with --canonicalize
+    canonical_files: Option<Arc<Mutex<HashSet<PathBuf>>>>,
+    changed_lines: Option<Arc<ChangedLines>>,
+    dir_limit: Option<DirLimit>,
+}
 
-    // Clone only what we need from config before the thread spawn
-    let patterns = config.patterns.clone();
-    let search_path = config.get_search_path();
-    let query = config.query.clone();
-    let use_regex = config.use_regex;  // Get the regex flag
+// Files at least this big are memory mapped with --mmap auto
+const LARGE_FILE_BYTES: u64 = 1 << 30;
 
-    let num_threads = if config.num_workers == 0 {
-        thread::available_parallelism()
-            .map(|v| v.get())
-            .unwrap_or(2)
-    } else {
-        config.num_workers
-    };
+// Bytes at the start of a file --binary-threshold looks at
+const BINARY_SAMPLE_BYTES: usize = 64 * 1024;
 
-    if config.verbose {
-        println!("Using {} worker threads", num_threads);
+/// Share of NUL bytes in `sample`, in percent
+fn nul_percent(sample: &[u8]) -> f64 {
+    if sample.is_empty() {
+        return 0.0;
     }
+    let nuls = sample.iter().filter(|&&byte| byte == 0).count();
+    nuls as f64 * 100.0 / sample.len() as f64
+}
 
-    let (work_tx, work_rx) = crossbeam_channel::unbounded::<DirEntry>();
-    let mut handles = Vec::new();
+/// The first `BINARY_SAMPLE_BYTES` of `path`
+fn read_sample(path: &Path) -> std::io::Result<Vec<u8>> {
+    let mut sample = Vec::with_capacity(BINARY_SAMPLE_BYTES);
+    File::open(path)?.take(BINARY_SAMPLE_BYTES as u64).read_to_end(&mut sample)?;
+    Ok(sample)
+}
 
-    // Spawn worker threads
-    for _ in 0..num_threads {
-        let work_rx = work_rx.clone();
-        let tx = tx.clone();
-        let quit = quit.clone();
-        let query = query.clone();
+/// A file handed to the search workers
+struct Job {
+    entry: DirEntry,
+    /// Position in the order files were handed out, see `SearchResult::seq`
+    seq: u64,
+    /// Set when a separate IO stage read the file already
+    contents: Option<Vec<u8>>,
+}
 
-        // Create matcher based on use_regex flag
-        let matcher = if use_regex {
-            RegexMatcher::new(&query)
-        } else {
-            RegexMatcher::new(&escape(&query))
-        }.unwrap();
+impl Job {
+    fn new(entry: DirEntry, seq: u64) -> Self {
+        Self { entry, seq, contents: None }
+    }
+}
 
-        let verbose = config.verbose;
-        let context_lines = config.context_lines;
-        let search_binary = config.search_binary;
-        
-        handles.push(thread::spawn(move || {
-            while let Ok(entry) = work_rx.recv() {
-                if quit.load(Ordering::Relaxed) {
-                    break;
-                }
+/// Thread counts of the search stages. With `io` at 0 the workers read and
+/// match each file themselves, otherwise `io` threads walk and read files
+/// and `cpu` threads only match
+#[derive(Debug, Clone, Copy)]
+struct Threads {
+    io: usize,
+    cpu: usize,
+}
 
-                let path = entry.path();
-                
-                // Handle PDFs separately
-                if path.extension().map_or(false, |ext| ext == "pdf") {
-                    if let Err(e) = search_pdf(path, &matcher, &tx, verbose, context_lines) {
-                        if verbose {
-                            eprintln!("Error searching PDF {}: {}", path.display(), e);
-                        }
+impl Threads {
+    fn combined(num_threads: usize) -> Self {
+        Self { io: 0, cpu: num_threads }
+    }
+}
+
+impl fmt::Display for Threads {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.io == 0 {
+            write!(f, "{} worker threads", self.cpu)
+        } else {
+            write!(f, "{} IO threads and {} matching threads", self.io, self.cpu)
+        }
+    }
+}
+
+impl Worker {
+    fn run(self, work_rx: crossbeam_channel::Receiver<Job>) {
+        // Per-thread counters, merged once the worker is done
+        let mut local_stats: HashMap<String, TypeStats> = HashMap::new();
+        let mut local_files = Vec::new();
+
+        while let Ok(job) = work_rx.recv() {
+            if self.quit.load(Ordering::Relaxed) {
+                break;
+            }
+
+            let started = Instant::now();
+            let entry = &job.entry;
+            let path = match self.resolve(entry.path()) {
+                Some(path) => path,
+                None => continue,
+            };
+            // A panic only loses the rest of this file, the worker goes on with the next one
+            let searched = panic::catch_unwind(AssertUnwindSafe(|| {
+                self.search_entry(entry, &path, job.contents.as_deref(), job.seq)
+            }));
+            match searched {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(payload) => {
+                    if self.verbose {
+                        eprintln!("Error searching {}: {}", path.display(), panic_message(&*payload));
                     }
+                    self.failed_files.lock().unwrap().push(path);
                     continue;
                 }
+            }
 
-                // Skip if not a regular file
-                if !entry.file_type().map_or(false, |ft| ft.is_file()) {
-                    continue;
+            self.files_processed.fetch_add(1, Ordering::Relaxed);
+            let bytes = entry.metadata().map(|m| m.len()).unwrap_or(0);
+            self.count_bytes(bytes);
+
+            if self.stats_by_type.is_some() {
+                let stats = local_stats.entry(extension_key(entry.path())).or_default();
+                stats.files += 1;
+                stats.bytes += bytes;
+                stats.elapsed += started.elapsed();
+            }
+            if self.searched_files.is_some() {
+                local_files.push(path);
+            }
+        }
+
+        if let Some(searched_files) = &self.searched_files {
+            searched_files.lock().unwrap().extend(local_files);
+        }
+
+        if let Some(stats_by_type) = &self.stats_by_type {
+            let mut merged = stats_by_type.lock().unwrap();
+            for (ext, stats) in local_stats {
+                merged.entry(ext).or_default().merge(&stats);
+            }
+        }
+    }
+
+    /// Adds a searched file's size to the total and stops the search once the
+    /// --max-total-bytes limit is exceeded
+    fn count_bytes(&self, bytes: u64) {
+        let before = self.bytes_searched.fetch_add(bytes, Ordering::Relaxed);
+        if let Some(max) = self.max_total_bytes {
+            // Only the worker crossing the limit warns
+            if before <= max && before + bytes > max {
+                if !self.no_messages {
+                    eprintln!("Warning: searched more than {} bytes (--max-total-bytes), stopping", max);
+                }
+                self.quit.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Whether to memory map a file of `size` bytes instead of reading it in chunks
+    fn use_mmap(&self, size: u64, path: &Path) -> bool {
+        match self.mmap {
+            MmapMode::Always => true,
+            MmapMode::Auto => size >= LARGE_FILE_BYTES,
+            MmapMode::Never => {
+                if size >= LARGE_FILE_BYTES && !self.no_messages
+                    && !self.large_file_hinted.swap(true, Ordering::Relaxed)
+                {
+                    eprintln!("Hint: {} is {} bytes, --mmap auto searches files this big faster",
+                        path.display(), size);
                 }
+                false
+            }
+        }
+    }
+
+    /// Reads a file for the IO stage. Files with an extractor, compressed files
+    /// and files that can't be read are left to the workers, which report their errors
+    fn read_job(&self, job: Job) -> Job {
+        let path = job.entry.path();
+        let special = (self.ocr && is_image(path)) || self.extractors.get(path).is_some()
+            || path.extension().map_or(false, |ext| self.search_compressed && ext == "gz");
+        if special || !is_regular_file(&job.entry) {
+            return job;
+        }
+
+        let contents = fs::read(path).ok();
+        Job { contents, ..job }
+    }
 
-                let mut searcher = SearcherBuilder::new()
-                    .binary_detection(if search_binary {
-                        BinaryDetection::none()
-                    } else {
-                        BinaryDetection::quit(b'\x00')
-                    })
-                    .before_context(context_lines)
-                    .after_context(context_lines)
-                    .build();
+    /// Path results are reported under. With --canonicalize that's the resolved
+    /// path, and `None` for a file already reached through another path
+    fn resolve(&self, path: &Path) -> Option<PathBuf> {
+        let canonical_files = match &self.canonical_files {
+            Some(canonical_files) => canonical_files,
+            None => return Some(path.to_path_buf()),
+        };
+        // Broken symlinks can't be resolved, they keep the path they were found under
+        let canonical = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        canonical_files.lock().unwrap().insert(canonical.clone()).then_some(canonical)
+    }
+
+    /// Matches the values `query` selects in a JSON or YAML file, one result per
+    /// value. False when the file doesn't parse
+    fn search_structured(&self, path: &Path, seq: u64, query: &JsonPathQuery) -> Result<bool> {
+        let text = fs::read_to_string(path)?;
+        let values = match query.select(path, &text) {
+            Some(values) => values,
+            None => return Ok(false),
+        };
+
+        let mut results = Vec::new();
+        for (index, (location, value)) in values.iter().enumerate() {
+            if !self.matcher.is_match(value.as_bytes())? {
+                continue;
+            }
+            // Only the value is highlighted, not a key that happens to match
+            let prefix = format!("{} = ", location);
+            let matches: Vec<(usize, usize)> = match_spans(&self.matcher, value.as_bytes()).into_iter()
+                .map(|(start, end)| (start + prefix.len(), end + prefix.len()))
+                .collect();
+            let count = matches.len();
+            results.push(SearchResult {
+                path: path.to_path_buf(),
+                line_number: index as u64 + 1,
+                line: prefix + value,
+                matches,
+                context_before: Vec::new(),
+                context_after: Vec::new(),
+                lossy: false,
+                seq,
+                source: SourceKind::Structured,
+            });
+            if self.first_match || self.match_limit.as_ref().map_or(false, |limit| !limit.count(count)) {
+                break;
+            }
+        }
 
-                let mut sink = SearchSink::new(&tx, path.to_path_buf(), context_lines);
+        if !results.is_empty() {
+            let _ = self.tx.send(results);
+        }
+        Ok(true)
+    }
 
-                if let Err(e) = searcher.search_path(&matcher, path, &mut sink) {
-                    if verbose {
+    /// Searches a single file under `path`, or its `contents` when they were read
+    /// already. Results carry `seq`. Returns false if the file was skipped
+    fn search_entry(&self, entry: &DirEntry, path: &Path, contents: Option<&[u8]>, seq: u64) -> bool {
+        if !is_regular_file(entry) {
+            if self.verbose {
+                eprintln!("Skipping {}: not a regular file", path.display());
+            }
+            return false;
+        }
+
+        if self.dir_limit.as_ref().map_or(false, |limit| limit.is_full(path)) {
+            if self.verbose {
+                eprintln!("Skipping {}: --max-per-dir reached for its directory", path.display());
+            }
+            return false;
+        }
+
+        // With --diff-only only files with changed lines are searched, as text
+        let changed_lines = match &self.changed_lines {
+            Some(changed) => match changed.get(path) {
+                Some(lines) => Some(lines),
+                None => return false,
+            },
+            None => None,
+        };
+
+        if let (Some(query), true) = (&self.jsonpath, changed_lines.is_none() && is_structured(path)) {
+            match self.search_structured(path, seq, query) {
+                Ok(true) => return true,
+                // Not valid JSON or YAML, searched as text below
+                Ok(false) => {
+                    if self.verbose {
+                        eprintln!("{} doesn't parse, searching it as text", path.display());
+                    }
+                }
+                Err(e) => {
+                    if self.verbose {
                         eprintln!("Error searching {}: {}", path.display(), e);
                     }
+                    return true;
+                }
+            }
+        }
+
+        if self.ocr && changed_lines.is_none() && is_image(path) {
+            if let Err(e) = search_image(path, seq, &self.matcher, &self.tx, &self.pdf_options, self.match_limit.as_ref(), &self.quit) {
+                let missing = e.root_cause().downcast_ref::<std::io::Error>()
+                    .map_or(false, |e| e.kind() == std::io::ErrorKind::NotFound);
+                if missing {
+                    // Reported once, every other image would fail the same way
+                    if !self.no_messages && !self.ocr_missing_warned.swap(true, Ordering::Relaxed) {
+                        eprintln!("Warning: --ocr needs {}, which was not found, images are skipped", OCR_COMMAND);
+                    }
+                } else if self.verbose {
+                    eprintln!("Error searching image {}: {:#}", path.display(), e);
                 }
             }
-        }));
+            return true;
+        }
+
+        let is_pdf = path.extension().map_or(false, |ext| ext == "pdf");
+        if is_pdf && !self.search_pdfs {
+            return false;
+        }
+
+        // Registered formats are searched in the text their extractor returns,
+        // PDFs split into pages
+        let extracted = match self.extractors.get(path).filter(|_| changed_lines.is_none()) {
+            Some(extractor) => match extractor(path) {
+                Ok(text) if is_pdf => {
+                    if let Err(e) = search_pdf(path, seq, &text, &self.matcher, &self.tx, &self.pdf_options, self.match_limit.as_ref()) {
+                        if self.verbose {
+                            eprintln!("Error searching PDF {}: {}", path.display(), e);
+                        }
+                    }
+                    return true;
+                }
+                Ok(text) => Some(text),
+                Err(e) => {
+                    if self.verbose {
+                        eprintln!("Error extracting {}: {:#}", path.display(), e);
+                    }
+                    return true;
+                }
+            },
+            None => None,
+        };
+        let contents = extracted.as_deref().map(str::as_bytes).or(contents);
+
+        // Gzip files are searched decompressed, line numbers refer to the decompressed text
+        let compressed = contents.is_none() && self.search_compressed && path.extension().map_or(false, |ext| ext == "gz");
+
+        // Extracted text is never binary, gzip files are only converted as their
+        // compressed bytes say nothing about the text
+        if let Some(threshold) = self.binary_threshold.filter(|_| extracted.is_none() && !compressed) {
+            let percent = match contents {
+                Some(contents) => nul_percent(&contents[..contents.len().min(BINARY_SAMPLE_BYTES)]),
+                None => read_sample(path).map_or(0.0, |sample| nul_percent(&sample)),
+            };
+            if percent > threshold {
+                if self.verbose {
+                    eprintln!("Skipping {}: binary, {:.1}% NUL bytes", path.display(), percent);
+                }
+                return false;
+            }
+        }
+
+        // First matching glob decides the encoding, files default to UTF-8
+        let file_name = entry.file_name().to_string_lossy();
+        let encoding = self.encodings.iter()
+            .find(|(pattern, _)| pattern.matches(&file_name))
+            .map(|(_, encoding)| encoding.clone());
+
+        let size = entry.metadata().map(|m| m.len()).unwrap_or(0);
+        let mmap = if contents.is_none() && self.use_mmap(size, path) {
+            // Safety: a file changing while mapped gives wrong results or SIGBUS, accepted
+            // in exchange for speed, the same trade-off ripgrep makes
+            unsafe { MmapChoice::auto() }
+        } else {
+            MmapChoice::never()
+        };
+
+        let mut searcher = SearcherBuilder::new()
+            .line_terminator(if self.crlf { LineTerminator::crlf() } else { LineTerminator::byte(b'\n') })
+            .line_number(true)
+            .memory_map(mmap)
+            .encoding(encoding)
+            .binary_detection(self.binary_detection.clone())
+            .before_context(self.context_lines)
+            .after_context(self.context_lines)
+            .build();
+
+        let mut sink = SearchSink::new(
+            &self.tx,
+            &self.matcher,
+            path.to_path_buf(),
+            seq,
+            self.context_lines,
+            self.first_match,
+            self.match_limit.as_ref(),
+        ).with_source(match (extracted.is_some(), compressed) {
+            (true, _) => SourceKind::Extracted,
+            (false, true) => SourceKind::Compressed,
+            (false, false) => SourceKind::Text,
+        })
+        .with_lines(changed_lines)
+        .with_merge_context(self.merge_context);
+
+        let searched = if let Some(contents) = contents {
+            searcher.search_slice(&self.matcher, contents, &mut sink)
+        } else if compressed {
+            File::open(path).and_then(|file| {
+                searcher.search_reader(&self.matcher, GzDecoder::new(file), &mut sink)
+            })
+        } else {
+            searcher.search_path(&self.matcher, path, &mut sink)
+        };
+
+        if let Err(e) = searched {
+            if self.verbose {
+                eprintln!("Error searching {}: {}", path.display(), e);
+            }
+        }
+        true
     }
+}
+
+/// Whether `entry` is a regular file or a symlink to one. Reading a FIFO, socket
+/// or device could block a worker forever or never end
+fn is_regular_file(entry: &DirEntry) -> bool {
+    match entry.file_type() {
+        Some(ft) if ft.is_symlink() => fs::metadata(entry.path()).map_or(false, |m| m.is_file()),
+        Some(ft) => ft.is_file(),
+        None => false,
+    }
+}
+
+/// Settings shaping the directory walk
+#[derive(Clone)]
+struct WalkOptions {
+    dir_patterns: Vec<String>,
+    /// --dir-pattern ignores case, like -p with --iglob
+    dir_ignore_case: bool,
+    /// Directory names never entered, from --exclude-dir
+    exclude_dirs: Vec<glob::Pattern>,
+    gitignore: bool,
+    git_global: bool,
+    git_exclude: bool,
+    ignore_file: bool,
+    /// Walk directory entries by name, so the walk order is the same every run
+    sorted: bool,
+    errors: Arc<WalkErrors>,
+    verbose: bool,
+    /// Directories quicksearch writes to itself, see [`excluded_dirs`]
+    excluded_dirs: Vec<PathBuf>,
+    disabled_ignore_files: Vec<PathBuf>,
+}
+
+impl WalkOptions {
+    fn new(config: &SearchConfig) -> Self {
+        Self {
+            dir_patterns: config.dir_patterns.clone(),
+            dir_ignore_case: config.glob_ignore_case,
+            // Checked when parsing the arguments
+            exclude_dirs: config.exclude_dirs.iter()
+                .filter_map(|pattern| glob::Pattern::new(pattern).ok())
+                .collect(),
+            gitignore: config.respect_gitignore,
+            git_global: config.respect_gitignore && config.git_global,
+            git_exclude: config.respect_gitignore && config.git_exclude,
+            ignore_file: config.respect_ignore_file,
+            sorted: config.ordered,
+            errors: config.walk_errors.clone(),
+            verbose: config.verbose,
+            excluded_dirs: excluded_dirs(),
+            disabled_ignore_files: config.disabled_ignore_files.clone(),
+        }
+    }
+}
+
+/// Project specific exclusions in gitignore syntax, applied in the directory
+/// holding the file and below it
+pub const IGNORE_FILE_NAME: &str = ".quicksearchignore";
+
+/// `.gitignore` files below `roots`, so single ones can be switched off
+pub fn find_gitignore_files(roots: &[PathBuf]) -> Vec<PathBuf> {
+    let mut builder = WalkBuilder::new(&roots[0]);
+    for root in &roots[1..] {
+        builder.add(root);
+    }
+    builder
+        .standard_filters(false)
+        .filter_entry(|entry| entry.file_name() != ".git");
+    builder.build()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name() == ".gitignore" && entry.file_type().map_or(false, |ft| ft.is_file()))
+        .map(DirEntry::into_path)
+        .collect()
+}
 
-// TODO: Add configuration parameter to control .gitignore behavior
-//       - Add bool field to SearchConfig like `respect_gitignore`
-//       - Default to false for searching everything
-//       - Add bool field to SearchConfig like `respect_gitignore`
-//       - Default to false for searching everything
-//       - When true, respect .gitignore rules
-    let walker = WalkBuilder::new(&search_path)
+/// The enabled .gitignore files, applied in place of the walker's own ones
+/// once some of them were switched off
+struct GitignoreFiles {
+    // Deepest first, a nested file overrides the ones above it
+    matchers: Vec<Gitignore>,
+}
+
+impl GitignoreFiles {
+    fn new(roots: &[PathBuf], disabled: &[PathBuf]) -> Self {
+        let mut matchers: Vec<Gitignore> = find_gitignore_files(roots).into_iter()
+            .filter(|path| !disabled.contains(path))
+            .map(|path| Gitignore::new(path).0)
+            .collect();
+        matchers.sort_by_key(|matcher| Reverse(matcher.path().components().count()));
+        Self { matchers }
+    }
+
+    fn ignores(&self, entry: &DirEntry) -> bool {
+        let is_dir = entry.file_type().map_or(false, |ft| ft.is_dir());
+        for matcher in self.matchers.iter().filter(|matcher| entry.path().starts_with(matcher.path())) {
+            let matched = matcher.matched(entry.path(), is_dir);
+            if !matched.is_none() {
+                return matched.is_ignore();
+            }
+        }
+        false
+    }
+}
+
+fn walk_builder(roots: &[PathBuf], walk: &WalkOptions) -> WalkBuilder {
+    let mut builder = WalkBuilder::new(&roots[0]);
+    for root in &roots[1..] {
+        builder.add(root);
+    }
+    let gitignores = (walk.gitignore && !walk.disabled_ignore_files.is_empty())
+        .then(|| GitignoreFiles::new(roots, &walk.disabled_ignore_files));
+    // Everything is searched by default, git rules only apply with --gitignore
+    builder
         .hidden(false)
         .ignore(false)
-        .git_ignore(false)
-        .build_parallel();
+        .git_ignore(walk.gitignore && gitignores.is_none())
+        .git_global(walk.git_global)
+        .git_exclude(walk.git_exclude);
+    if walk.ignore_file {
+        builder.add_custom_ignore_filename(IGNORE_FILE_NAME);
+    }
+    if walk.sorted {
+        builder.sort_by_file_name(|a, b| a.cmp(b));
+    }
+
+    // Prune while walking, so skipped trees are never read
+    let dirs = (!walk.dir_patterns.is_empty()).then(|| FilePatterns::new(&walk.dir_patterns, walk.dir_ignore_case));
+    let excluded = walk.excluded_dirs.clone();
+    let exclude_names = walk.exclude_dirs.clone();
+    if dirs.is_some() || !excluded.is_empty() || !exclude_names.is_empty() || gitignores.is_some() {
+        builder.filter_entry(move |entry| {
+            !is_excluded_dir(&excluded, entry)
+                && !has_excluded_name(&exclude_names, entry)
+                && !gitignores.as_ref().map_or(false, |gitignores| gitignores.ignores(entry))
+                && dirs.as_ref().map_or(true, |dirs| enter_dir(dirs, entry))
+        });
+    }
+    builder
+}
+
+/// Matching files under `roots`, walked on the calling thread
+fn walk_entries(roots: &[PathBuf], walk: &WalkOptions, patterns: &FilePatterns, quit: &AtomicBool) -> Vec<DirEntry> {
+    let mut entries = Vec::new();
+
+    for entry in walk_builder(roots, walk).build() {
+        if quit.load(Ordering::Relaxed) {
+            break;
+        }
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                walk.errors.record(&e, walk.verbose);
+                continue;
+            }
+        };
+        if entry.file_type().map_or(false, |ft| ft.is_file())
+            && patterns.matches(entry.path())
+        {
+            entries.push(entry);
+        }
+    }
+
+    entries
+}
+
+/// Files a search over `config` would look at, so several searches over the
+/// same corpus can skip walking the directory tree again
+pub fn list_files(config: &SearchConfig, quit: &AtomicBool) -> Result<Vec<PathBuf>> {
+    if let Some(files) = &config.files {
+        return Ok(files.clone());
+    }
+    let patterns = FilePatterns::for_files(config)?;
+    let files = walk_entries(&config.search_roots(), &WalkOptions::new(config), &patterns, quit)
+        .into_iter()
+        .map(DirEntry::into_path)
+        .collect();
+    Ok(files)
+}
+
+/// The `paths` matching the file patterns of `config`, for files found some
+/// other way, like the changes reported while watching. They arrive absolute
+/// and are mapped onto the search root holding them, so they read like the
+/// paths of the walk: `./app.log` rather than `/home/me/app.log` for the root `.`
+pub fn matching_files(config: &SearchConfig, paths: Vec<PathBuf>) -> Result<Vec<PathBuf>> {
+    let patterns = FilePatterns::for_files(config)?;
+    // Each root as an absolute and as a resolved path, symlinks may differ
+    let current_dir = std::env::current_dir()?;
+    let roots: Vec<(PathBuf, &PathBuf)> = patterns.roots.iter()
+        .flat_map(|root| {
+            let absolute = current_dir.join(root);
+            let resolved = fs::canonicalize(root).unwrap_or_else(|_| absolute.clone());
+            [(absolute, root), (resolved, root)]
+        })
+        .collect();
+
+    Ok(paths.into_iter()
+        .map(|path| onto_root(&roots, path))
+        .filter(|path| patterns.matches(path))
+        .collect())
+}
+
+/// `path` below the deepest of the absolute `roots` holding it, joined to the
+/// root as it was given. Paths outside every root stay as they are
+fn onto_root(roots: &[(PathBuf, &PathBuf)], path: PathBuf) -> PathBuf {
+    roots.iter()
+        .filter_map(|(absolute, root)| path.strip_prefix(absolute).ok().map(|relative| (*root, relative)))
+        .min_by_key(|(_, relative)| relative.components().count())
+        .map(|(root, relative)| {
+            if relative.as_os_str().is_empty() {
+                root.clone()
+            } else {
+                root.join(relative)
+            }
+        })
+        .unwrap_or(path)
+}
+
+/// The files git tracks under the search roots that match the file patterns, as
+/// `git ls-files` reports them. `None` when a root is not inside a git work tree
+/// (or git can't be run), the caller walks the roots then
+pub fn git_tracked_files(config: &SearchConfig) -> Result<Option<Vec<PathBuf>>> {
+    let patterns = FilePatterns::for_files(config)?;
+    let mut files = Vec::new();
+
+    for root in config.search_roots() {
+        // A file named on the command line is searched as given
+        if root.is_file() {
+            files.push(root);
+            continue;
+        }
+        let output = match Command::new("git").arg("-C").arg(&root).args(["ls-files", "-z"]).output() {
+            Ok(output) if output.status.success() => output,
+            _ => return Ok(None),
+        };
+        // Deleted but still tracked files and submodules are no regular files
+        files.extend(output.stdout.split(|&byte| byte == 0)
+            .filter(|name| !name.is_empty())
+            .map(|name| root.join(String::from_utf8_lossy(name).as_ref()))
+            .filter(|path| path.is_file())
+            .filter(|path| patterns.matches(path)));
+    }
+
+    Ok(Some(files))
+}
+
+/// Files of `changed` below the search roots that match the file patterns,
+/// in path order, for --diff-only
+pub fn changed_files(config: &SearchConfig, changed: &ChangedLines) -> Result<Vec<PathBuf>> {
+    let patterns = FilePatterns::for_files(config)?;
+    let mut files: Vec<PathBuf> = changed.files()
+        .filter(|path| path.is_file())
+        .filter(|path| patterns.matches(path))
+        .map(Path::to_path_buf)
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+/// Files with at least one match for `config`, in path order, for the first
+/// stage of --then-search. One match per file is enough, and the counters of
+/// `config` are left to the second stage
+pub fn files_matching(config: &SearchConfig, quit: Arc<AtomicBool>) -> Result<Vec<PathBuf>> {
+    let first_stage = SearchConfig {
+        first_match: true,
+        files_processed: Arc::default(),
+        matches_found: Arc::default(),
+        stats_by_type: None,
+        searched_files: None,
+        ..config.clone()
+    };
+    let mut files: Vec<PathBuf> = search(&first_stage, quit)?
+        .map(|result| result.path)
+        .collect();
+    files.sort();
+    files.dedup();
+    Ok(files)
+}
+
+/// Number of files a search over `config` would look at, to size a progress
+/// display before searching. Walks with the same ignore rules and patterns but
+/// reads no file. Once `quit` is set it returns the count so far
+pub fn count_candidate_files(config: &SearchConfig, quit: &AtomicBool) -> Result<usize> {
+    let files = list_files(config, quit)?;
+    Ok(files.iter()
+        .filter(|path| config.search_pdfs || path.extension().map_or(true, |ext| ext != "pdf"))
+        .count())
+}
+
+fn sort_entries(entries: &mut [DirEntry], order: SortFiles) {
+    match order {
+        SortFiles::Path => entries.sort_by(|a, b| a.path().cmp(b.path())),
+        // Largest and newest first, files without metadata go last
+        SortFiles::Size => entries.sort_by_cached_key(|entry| {
+            Reverse(entry.metadata().map_or(0, |m| m.len()))
+        }),
+        SortFiles::Mtime => entries.sort_by_cached_key(|entry| {
+            Reverse(entry.metadata().ok().and_then(|m| m.modified().ok()))
+        }),
+    }
+}
+
+/// Starts the worker threads, and the reader threads of a separate IO stage.
+/// Returns the sender taking the files to search and the threads to wait for.
+fn spawn_stages(threads: Threads, worker: &Worker) -> (Sender<Job>, Vec<thread::JoinHandle<()>>) {
+    // Readers only stay a few files ahead, so memory holds just what is being matched
+    let (work_tx, work_rx) = if threads.io > 0 {
+        crossbeam_channel::bounded::<Job>(threads.cpu * 4)
+    } else {
+        crossbeam_channel::unbounded::<Job>()
+    };
+
+    let mut handles: Vec<_> = (0..threads.cpu)
+        .map(|_| {
+            let worker = worker.clone();
+            let work_rx = work_rx.clone();
+            thread::spawn(move || worker.run(work_rx))
+        })
+        .collect();
+
+    if threads.io == 0 {
+        return (work_tx, handles);
+    }
 
-    let quit_walker = quit.clone();
+    let (read_tx, read_rx) = crossbeam_channel::unbounded::<Job>();
+    handles.extend((0..threads.io).map(|_| {
+        let worker = worker.clone();
+        let read_rx = read_rx.clone();
+        let work_tx = work_tx.clone();
+        thread::spawn(move || {
+            while let Ok(job) = read_rx.recv() {
+                if worker.quit.load(Ordering::Relaxed) {
+                    break;
+                }
+                if work_tx.send(worker.read_job(job)).is_err() {
+                    break;
+                }
+            }
+        })
+    }));
+    (read_tx, handles)
+}
+
+/// Collects the matching files under `roots` first, sorted when an `order` is
+/// given, then hands them to the workers in that order
+fn spawn_sorted_pool(roots: Vec<PathBuf>, walk: WalkOptions, threads: Threads, worker: &Worker, patterns: Arc<FilePatterns>, order: Option<SortFiles>) {
+    let (work_tx, handles) = spawn_stages(threads, worker);
+
+    let quit = worker.quit.clone();
+    thread::spawn(move || {
+        let mut entries = walk_entries(&roots, &walk, &patterns, &quit);
+        if let Some(order) = order {
+            sort_entries(&mut entries, order);
+        }
+
+        for (seq, entry) in entries.into_iter().enumerate() {
+            if quit.load(Ordering::Relaxed) || work_tx.send(Job::new(entry, seq as u64)).is_err() {
+                break;
+            }
+        }
+
+        // Signal workers to stop and wait for them
+        drop(work_tx);
+        for handle in handles {
+            let _ = handle.join();
+        }
+    });
+}
+
+/// Hands exactly the listed files to the workers, nothing is walked
+fn spawn_list_pool(files: Vec<PathBuf>, threads: Threads, worker: &Worker, no_messages: bool) {
+    let (work_tx, handles) = spawn_stages(threads, worker);
+
+    let quit = worker.quit.clone();
+    thread::spawn(move || {
+        for (seq, path) in files.into_iter().enumerate() {
+            if quit.load(Ordering::Relaxed) {
+                break;
+            }
+            if !path.is_file() {
+                if !no_messages {
+                    eprintln!("Warning: {}: not found or not a file", path.display());
+                }
+                continue;
+            }
+
+            // A walk of depth 0 only looks at the path itself, it's how a DirEntry is made
+            let entry = match WalkBuilder::new(&path).max_depth(Some(0)).build().next() {
+                Some(Ok(entry)) => entry,
+                _ => continue,
+            };
+            if work_tx.send(Job::new(entry, seq as u64)).is_err() {
+                break;
+            }
+        }
+
+        // Signal workers to stop and wait for them
+        drop(work_tx);
+        for handle in handles {
+            let _ = handle.join();
+        }
+    });
+}
+
+/// Walks `roots` and searches the matching files
+fn spawn_pool(roots: &[PathBuf], walk: &WalkOptions, threads: Threads, worker: &Worker, patterns: Arc<FilePatterns>) {
+    let (work_tx, handles) = spawn_stages(threads, worker);
+
+    let mut builder = walk_builder(roots, walk);
+    // The walker is IO bound as well, it gets the IO stage's thread count
+    if threads.io > 0 {
+        builder.threads(threads.io);
+    }
+    let walker = builder.build_parallel();
+
+    let quit_walker = worker.quit.clone();
+    let errors = walk.errors.clone();
+    let verbose = walk.verbose;
     thread::spawn(move || {
         walker.run(|| {
             let work_tx = work_tx.clone();
-            let patterns = patterns.clone();  // Use cloned patterns
+            let patterns = patterns.clone();
             let quit = quit_walker.clone();
+            let errors = errors.clone();
             
             Box::new(move |result| {
                 if quit.load(Ordering::Relaxed) {
@@ -318,7 +1934,10 @@ pub fn search(
 
                 let entry = match result {
                     Ok(entry) => entry,
-                    Err(_) => return WalkState::Continue,
+                    Err(e) => {
+                        errors.record(&e, verbose);
+                        return WalkState::Continue;
+                    }
                 };
 
                 // Skip if not a file
@@ -326,16 +1945,13 @@ pub fn search(
                     return WalkState::Continue;
                 }
 
-                // Check if file matches any pattern
-                let file_name = entry.file_name().to_string_lossy();
-                if !patterns.iter().any(|p| {
-                    glob::Pattern::new(p).map_or(false, |pat| pat.matches(&file_name))
-                }) {
+                // Check if file matches the patterns
+                if !patterns.matches(entry.path()) {
                     return WalkState::Continue;
                 }
 
                 // Distribute work to worker threads
-                if work_tx.send(entry).is_err() {
+                if work_tx.send(Job::new(entry, 0)).is_err() {
                     return WalkState::Quit;
                 }
 
@@ -351,6 +1967,918 @@ pub fn search(
             let _ = handle.join();
         }
     });
+}
 
-    Ok(rx.into_iter())
+/// Worker threads used for `num_workers`, where 0 means one per CPU core
+pub fn worker_threads(num_workers: usize) -> usize {
+    if num_workers == 0 {
+        thread::available_parallelism()
+            .map(|v| v.get())
+            .unwrap_or(2)
+    } else {
+        num_workers
+    }
+}
+
+pub fn search(
+    config: &SearchConfig,
+    quit: Arc<AtomicBool>
+) -> Result<impl Iterator<Item = SearchResult>> {
+    let (tx, rx) = match config.result_buffer {
+        Some(capacity) => crossbeam_channel::bounded(capacity),
+        None => crossbeam_channel::unbounded(),
+    };
+
+    let patterns = Arc::new(FilePatterns::for_files(config)?);
+    let roots = config.search_roots();
+
+    let num_threads = worker_threads(config.num_workers);
+
+    let encodings = config.encodings.iter()
+        .map(|(label, pattern)| Ok((glob::Pattern::new(pattern)?, Encoding::new(label)?)))
+        .collect::<Result<Vec<_>>>()?;
+
+    let match_limit = config.max_matches_total.map(|max| MatchLimit {
+        found: config.matches_found.clone(),
+        max,
+        quit: quit.clone(),
+        no_messages: config.no_messages,
+    });
+    let dir_limit = config.max_per_dir.map(|max| DirLimit { max, counts: Arc::default() });
+
+    let pdf_options = PdfOptions {
+        command: config.pdf_command.clone(),
+        args: config.pdf_args.clone(),
+        raw: config.pdf_raw,
+        normalize: config.pdf_normalize,
+        paragraphs: config.pdf_paragraphs,
+        context_lines: config.context_lines,
+        first_match: config.first_match,
+        verbose: config.verbose,
+    };
+    // Built-in formats first, so extractors from the config can replace them
+    let mut extractors = Extractors::default();
+    let options_for_pdf = pdf_options.clone();
+    let quit_for_pdf = quit.clone();
+    extractors.register("pdf", move |path| extract_pdf(path, &options_for_pdf, &quit_for_pdf));
+    extractors.extend(&config.extractors);
+
+    let worker = Worker {
+        tx,
+        matcher: build_matcher(config)?,
+        quit,
+        pdf_options,
+        context_lines: config.context_lines,
+        first_match: config.first_match,
+        merge_context: config.merge_context,
+        crlf: config.crlf,
+        binary_detection: match config.binary_detection {
+            _ if config.search_binary => BinaryDetection::none(),
+            // Files over the threshold never reach the searcher
+            _ if config.binary_threshold.is_some() => BinaryDetection::convert(b'\x00'),
+            BinaryMode::Quit => BinaryDetection::quit(b'\x00'),
+            BinaryMode::Convert => BinaryDetection::convert(b'\x00'),
+            BinaryMode::None => BinaryDetection::none(),
+        },
+        binary_threshold: config.binary_threshold.filter(|_| !config.search_binary),
+        verbose: config.verbose,
+        encodings: Arc::new(encodings),
+        files_processed: config.files_processed.clone(),
+        bytes_searched: config.bytes_searched.clone(),
+        max_total_bytes: config.max_total_bytes,
+        match_limit,
+        mmap: config.mmap,
+        search_pdfs: config.search_pdfs,
+        search_compressed: config.search_compressed,
+        ocr: config.ocr,
+        jsonpath: config.jsonpath.as_deref().map(JsonPathQuery::new).transpose()?.map(Arc::new),
+        extractors,
+        no_messages: config.no_messages,
+        large_file_hinted: Arc::new(AtomicBool::new(false)),
+        ocr_missing_warned: Arc::new(AtomicBool::new(false)),
+        stats_by_type: config.stats_by_type.clone(),
+        searched_files: config.searched_files.clone(),
+        failed_files: config.failed_files.clone(),
+        canonical_files: config.canonicalize.then(Default::default),
+        changed_lines: config.changed_lines.clone(),
+        dir_limit: dir_limit.clone(),
+    };
+
+    // -j sizes both stages, --io-threads or --cpu-threads split reading from matching
+    let threads = if config.io_threads.is_some() || config.cpu_threads.is_some() {
+        Threads {
+            io: config.io_threads.unwrap_or(num_threads).max(1),
+            cpu: config.cpu_threads.unwrap_or(num_threads).max(1),
+        }
+    } else {
+        Threads::combined(num_threads)
+    };
+
+    let walk = WalkOptions::new(config);
+    if let Some(files) = &config.files {
+        if config.verbose {
+            println!("Using {} for {} listed files", threads, files.len());
+        }
+        spawn_list_pool(files.clone(), threads, &worker, config.no_messages);
+    } else if config.sort_files.is_some() || config.ordered {
+        // One sequential walk, so the whole file list can be sorted before searching
+        // and each file gets its place in the order
+        if config.verbose {
+            match config.sort_files {
+                Some(order) => println!("Using {}, files sorted by {:?}", threads, order),
+                None => println!("Using {}, files in walk order", threads),
+            }
+        }
+        spawn_sorted_pool(roots, walk, threads, &worker, patterns, config.sort_files);
+    } else if config.threads_per_path > 0 && roots.len() > 1 {
+        // Separate walker and workers per root, so a huge root can't starve a small one
+        if config.verbose {
+            println!("Using {} worker threads for each of {} paths", config.threads_per_path, roots.len());
+        }
+        for root in &roots {
+            spawn_pool(std::slice::from_ref(root), &walk, Threads::combined(config.threads_per_path), &worker, patterns.clone());
+        }
+    } else {
+        if config.verbose {
+            println!("Using {}", threads);
+        }
+        spawn_pool(&roots, &walk, threads, &worker, patterns);
+    }
+
+    // Dropping the template's sender lets the iterator end with the last worker
+    drop(worker);
+
+    Ok(rx.into_iter().flatten()
+        .filter(move |result| dir_limit.as_ref().map_or(true, |limit| limit.admit(&result.path))))
+}
+
+/// Async variant of [`search`]. Results arrive on an `async_channel::Receiver`,
+/// which implements `futures_core::Stream`, while the walker and workers keep
+/// running on their own threads, so async callers can await each result as it
+/// is found instead of blocking on [`search_files`].
+///
+/// Not behind a feature: the GUI's [`spawn_search`] runs on it, and
+/// async-channel is a dependency of the GUI anyway. It needs no runtime, any
+/// executor can poll the stream
+pub fn search_stream(
+    config: &SearchConfig,
+    quit: Arc<AtomicBool>
+) -> Result<async_channel::Receiver<SearchResult>> {
+    let results = search(config, quit.clone())?;
+    let (tx, rx) = match config.result_buffer {
+        // Unlike crossbeam, async_channel has no zero capacity channel
+        Some(capacity) => async_channel::bounded(capacity.max(1)),
+        None => async_channel::unbounded(),
+    };
+    let max_results = config.max_results;
+
+    thread::spawn(move || {
+        for (count, result) in results.enumerate() {
+            // Receiver dropped, nobody is interested anymore
+            if tx.send_blocking(result).is_err() {
+                break;
+            }
+            // Results already in flight are still passed on, so callers can count them
+            if max_results == Some(count + 1) {
+                quit.store(true, Ordering::Relaxed);
+            }
+        }
+    });
+
+    Ok(rx)
+}
+
+/// A search running on its own threads, started with [`spawn_search`]
+pub struct SearchHandle {
+    quit: Arc<AtomicBool>,
+    results: async_channel::Receiver<SearchResult>,
+}
+
+impl SearchHandle {
+    /// Stops the walker and workers, results found so far stay in the receiver
+    pub fn cancel(&self) {
+        self.quit.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.quit.load(Ordering::Relaxed)
+    }
+
+    /// Results as they are found, usable with `recv().await` or `recv_blocking()`.
+    /// The channel closes once the search is done or cancelled.
+    pub fn results(&self) -> async_channel::Receiver<SearchResult> {
+        self.results.clone()
+    }
+
+    /// Counts the files of the search on another thread, see [`count_candidate_files`].
+    /// Cancelling the search stops the count, the channel then closes without a value
+    pub fn count_candidates(&self, config: &SearchConfig) -> async_channel::Receiver<usize> {
+        let (tx, rx) = async_channel::bounded(1);
+        let config = config.clone();
+        let quit = self.quit.clone();
+        thread::spawn(move || {
+            if let Ok(count) = count_candidate_files(&config, &quit) {
+                if !quit.load(Ordering::Relaxed) {
+                    let _ = tx.send_blocking(count);
+                }
+            }
+        });
+        rx
+    }
+}
+
+/// Starts a search in the background. Wraps [`search_stream`] with its own quit
+/// flag, use [`search`] directly to share a flag between searches.
+pub fn spawn_search(config: &SearchConfig) -> Result<SearchHandle> {
+    let quit = Arc::new(AtomicBool::new(false));
+    let results = search_stream(config, quit.clone())?;
+    Ok(SearchHandle { quit, results })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{temp_dir, TempDir, WriteFile};
+
+    fn config(dir: &TempDir, query: &str) -> SearchConfig {
+        SearchConfig {
+            paths: vec![dir.path().to_path_buf()],
+            query: query.to_string(),
+            ..SearchConfig::default()
+        }
+    }
+
+    /// Results in path and line order, the workers send them in any order
+    fn search_sorted(config: &SearchConfig) -> Vec<SearchResult> {
+        let mut results = search_files(config, Arc::new(AtomicBool::new(false))).expect("search");
+        results.sort_by(|a, b| a.path.cmp(&b.path).then(a.line_number.cmp(&b.line_number)));
+        results
+    }
+
+    /// Path below `dir` and line number of each result
+    fn found(dir: &TempDir, results: &[SearchResult]) -> Vec<(String, u64)> {
+        results.iter()
+            .map(|result| {
+                let relative = result.path.strip_prefix(dir.path()).unwrap_or(&result.path);
+                (relative.to_string_lossy().replace('\\', "/"), result.line_number)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn trailing_whitespace_is_matched_and_reported() {
+        let dir = temp_dir("trailing-space");
+        dir.write("a.txt", "let x = 1;   \nclean\n");
+
+        let results = search_sorted(&SearchConfig { use_regex: true, ..config(&dir, r"\s+$") });
+        assert_eq!(found(&dir, &results), [("a.txt".to_string(), 1)]);
+        assert_eq!(results[0].line, "let x = 1;   ");
+        assert_eq!(results[0].matches, [(10, 13)]);
+    }
+
+    #[test]
+    fn trim_line_ending_keeps_other_whitespace() {
+        assert_eq!(trim_line_ending("  indented  \n"), "  indented  ");
+        assert_eq!(trim_line_ending("windows \r\n"), "windows ");
+        assert_eq!(trim_line_ending("two\r\r\n"), "two\r");
+        assert_eq!(trim_line_ending("last"), "last");
+    }
+
+    fn pdf_options(context_lines: usize) -> PdfOptions {
+        PdfOptions {
+            command: DEFAULT_PDF_COMMAND.to_string(),
+            args: Vec::new(),
+            raw: false,
+            normalize: false,
+            paragraphs: false,
+            context_lines,
+            first_match: false,
+            verbose: false,
+        }
+    }
+
+    /// Results of searching the extracted text of a PDF for `query`
+    fn search_pdf_text(text: &str, query: &str, options: &PdfOptions) -> Vec<SearchResult> {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let matcher = QueryMatcher::Literal(LiteralMatcher::new(query));
+        search_pdf(Path::new("doc.pdf"), 0, text, &matcher, &tx, options, None).expect("search PDF text");
+        drop(tx);
+        rx.into_iter().flatten().collect()
+    }
+
+    #[test]
+    fn pdf_pages_start_at_form_feeds() {
+        assert_eq!(pdf_line_pages(&["a", "\x0cb", "c", "\x0c\x0cd"]), [0, 1, 1, 3]);
+    }
+
+    #[test]
+    fn pdf_context_stays_on_the_page_of_the_match() {
+        let text = "intro\nneedle one\n\x0cneedle two\nlast\n";
+        let results = search_pdf_text(text, "needle", &pdf_options(1));
+        assert_eq!(results.len(), 2);
+
+        assert_eq!(results[0].line_number, 2);
+        assert_eq!(results[0].context_before, [(1, "intro".to_string())]);
+        assert!(results[0].context_after.is_empty());
+
+        assert_eq!(results[1].line_number, 3);
+        assert_eq!(results[1].line, "needle two");
+        assert!(results[1].context_before.is_empty());
+        assert_eq!(results[1].context_after, [(4, "last".to_string())]);
+    }
+
+    fn patterns(patterns: &[&str]) -> FilePatterns {
+        let patterns: Vec<String> = patterns.iter().map(|pattern| pattern.to_string()).collect();
+        FilePatterns::new(&patterns, false)
+    }
+
+    #[test]
+    fn negated_patterns_exclude_from_the_included_files() {
+        let rust = patterns(&["*.rs", "!generated_*.rs"]);
+        assert!(rust.matches(Path::new("main.rs")));
+        assert!(!rust.matches(Path::new("generated_api.rs")));
+        assert!(!rust.matches(Path::new("notes.txt")));
+
+        // Only exclusions: everything else is included
+        let no_logs = patterns(&["!*.log"]);
+        assert!(no_logs.matches(Path::new("notes.txt")));
+        assert!(!no_logs.matches(Path::new("debug.log")));
+    }
+
+    #[test]
+    fn escaped_bang_matches_a_literal_bang() {
+        let bang = patterns(&["\\!important.txt"]);
+        assert!(bang.matches(Path::new("!important.txt")));
+        assert!(!bang.matches(Path::new("important.txt")));
+    }
+
+    #[test]
+    fn line_numbers_start_at_1() {
+        let dir = temp_dir("line-numbers");
+        dir.write("a.txt", "needle\nother\nneedle\n");
+
+        let results = search_sorted(&SearchConfig { context_lines: 1, ..config(&dir, "needle") });
+        assert_eq!(found(&dir, &results), [("a.txt".to_string(), 1), ("a.txt".to_string(), 3)]);
+        // Shared by both matches, merged into the context after the first one
+        assert_eq!(results[0].context_after, [(2, "other".to_string())]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn cancelled_extractor_is_killed() {
+        let quit = Arc::new(AtomicBool::new(false));
+        let quit_later = quit.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            quit_later.store(true, Ordering::Relaxed);
+        });
+
+        let started = Instant::now();
+        let output = run_cancellable(Command::new("sleep").arg("10"), &quit).expect("run sleep");
+        assert!(output.is_none());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn finished_extractor_returns_its_output() {
+        let quit = AtomicBool::new(false);
+        let output = run_cancellable(Command::new("echo").arg("text"), &quit)
+            .expect("run echo")
+            .expect("not cancelled");
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"text\n");
+    }
+
+    #[test]
+    fn tiny_root_is_searched_next_to_a_large_one() {
+        let tiny = temp_dir("tiny-root");
+        tiny.write("only.txt", "needle\n");
+        let large = temp_dir("large-root");
+        for i in 0..200 {
+            large.write(&format!("dir{}/file{}.txt", i % 10, i), "needle\n");
+        }
+
+        let config = SearchConfig {
+            paths: vec![large.path().to_path_buf(), tiny.path().to_path_buf()],
+            threads_per_path: 1,
+            ..config(&tiny, "needle")
+        };
+        let results = search_sorted(&config);
+        assert_eq!(results.iter().filter(|result| result.path.starts_with(large.path())).count(), 200);
+        assert_eq!(found(&tiny, &results).iter().filter(|(path, _)| path == "only.txt").count(), 1);
+        assert_eq!(config.files_processed.load(Ordering::Relaxed), 201);
+    }
+
+    #[test]
+    fn encoding_rule_decodes_latin1_files() {
+        let dir = temp_dir("latin1");
+        dir.write("menu.txt", b"caf\xe9 au lait\n");
+
+        assert!(search_sorted(&config(&dir, "café")).is_empty());
+
+        let results = search_sorted(&SearchConfig {
+            encodings: vec![("latin1".to_string(), "*.txt".to_string())],
+            ..config(&dir, "café")
+        });
+        assert_eq!(found(&dir, &results), [("menu.txt".to_string(), 1)]);
+        assert_eq!(results[0].line, "café au lait");
+        assert!(!results[0].lossy);
+    }
+
+    #[test]
+    fn match_in_a_long_file_widens_the_gutter() {
+        let dir = temp_dir("long-file");
+        let text: String = (1..1200).map(|i| format!("line {}\n", i)).collect();
+        dir.write("big.txt", text + "needle\n");
+
+        let results = search_sorted(&config(&dir, "needle"));
+        assert_eq!(found(&dir, &results), [("big.txt".to_string(), 1200)]);
+        assert_eq!(results[0].gutter_width(), 5);
+    }
+
+    #[test]
+    fn dir_patterns_prune_the_walk() {
+        let dir = temp_dir("dir-patterns");
+        for file in ["top.txt", "src/a.txt", "src/nested/b.txt", "src_old/c.txt", "docs/d.txt", "target/e.txt"] {
+            dir.write(file, "needle\n");
+        }
+        let files = |dir_patterns: &[&str]| -> Vec<String> {
+            let config = SearchConfig {
+                dir_patterns: dir_patterns.iter().map(|pattern| pattern.to_string()).collect(),
+                ..config(&dir, "needle")
+            };
+            found(&dir, &search_sorted(&config)).into_iter().map(|(path, _)| path).collect()
+        };
+
+        // Files directly in the root are always searched
+        assert_eq!(files(&["src*"]), ["src/a.txt", "src/nested/b.txt", "src_old/c.txt", "top.txt"]);
+        assert_eq!(files(&["!target"]), ["docs/d.txt", "src/a.txt", "src/nested/b.txt", "src_old/c.txt", "top.txt"]);
+    }
+
+    #[test]
+    fn embedded_nul_follows_the_binary_mode() {
+        let dir = temp_dir("embedded-nul");
+        dir.write("mixed.dat", b"needle one\n\0\nneedle two\n");
+        let lines = |binary_detection: BinaryMode| -> Vec<String> {
+            let config = SearchConfig { binary_detection, ..config(&dir, "needle") };
+            search_sorted(&config).into_iter().map(|result| result.line).collect()
+        };
+
+        // Quit stops reading at the NUL byte
+        assert_eq!(lines(BinaryMode::Quit), ["needle one"]);
+        assert_eq!(lines(BinaryMode::Convert), ["needle one", "needle two"]);
+        assert_eq!(lines(BinaryMode::None), ["needle one", "needle two"]);
+    }
+
+    #[test]
+    fn large_file_is_searched_without_mmap() {
+        let dir = temp_dir("large-file");
+        let path = dir.write("huge.log", "needle\n");
+        // Sparse, so it takes no disk space, the rest reads as NUL bytes
+        File::options().write(true).open(&path).expect("open").set_len(LARGE_FILE_BYTES).expect("grow file");
+
+        let config = config(&dir, "needle");
+        let results = search_sorted(&config);
+        assert_eq!(found(&dir, &results), [("huge.log".to_string(), 1)]);
+        assert_eq!(config.bytes_searched.load(Ordering::Relaxed), LARGE_FILE_BYTES);
+    }
+
+    #[test]
+    fn memory_mapped_search_finds_the_same_lines() {
+        let dir = temp_dir("mmap");
+        dir.write("a.txt", "first\nneedle\n");
+
+        let results = search_sorted(&SearchConfig { mmap: MmapMode::Always, ..config(&dir, "needle") });
+        assert_eq!(found(&dir, &results), [("a.txt".to_string(), 2)]);
+    }
+
+    #[test]
+    fn canonicalized_files_are_searched_once() {
+        let dir = temp_dir("canonicalize");
+        let file = dir.write("a.txt", "needle\n");
+        dir.write("sub/b.txt", "other\n");
+        let paths = vec![dir.path().to_path_buf(), dir.path().join("sub").join("..").join("a.txt")];
+
+        let results = search_sorted(&SearchConfig { paths: paths.clone(), ..config(&dir, "needle") });
+        assert_eq!(results.len(), 2);
+
+        let results = search_sorted(&SearchConfig { paths, canonicalize: true, ..config(&dir, "needle") });
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].path, fs::canonicalize(file).expect("canonicalize"));
+    }
+
+    #[test]
+    fn first_match_stops_after_the_first_result() {
+        let dir = temp_dir("first-match");
+        dir.write("a.txt", "needle\ntwo\nneedle\nfour\n");
+
+        let results = search_sorted(&SearchConfig { first_match: true, ..config(&dir, "needle") });
+        assert_eq!(found(&dir, &results), [("a.txt".to_string(), 1)]);
+
+        let config = SearchConfig { first_match: true, context_lines: 1, ..config(&dir, "needle") };
+        let results = search_sorted(&config);
+        assert_eq!(found(&dir, &results), [("a.txt".to_string(), 1)]);
+        assert_eq!(results[0].context_after, [(2, "two".to_string())]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unreadable_directories_count_as_permission_denied() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = temp_dir("permission-denied");
+        dir.write("a.txt", "needle\n");
+        dir.write("locked/b.txt", "needle\n");
+        let locked = dir.path().join("locked");
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).expect("lock directory");
+        if fs::read_dir(&locked).is_ok() {
+            // Running as root, nothing is denied
+            fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).expect("unlock directory");
+            return;
+        }
+
+        let config = config(&dir, "needle");
+        let results = search_sorted(&config);
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).expect("unlock directory");
+        assert_eq!(found(&dir, &results), [("a.txt".to_string(), 1)]);
+        assert!(config.walk_errors.permission_denied.load(Ordering::Relaxed) >= 1);
+    }
+
+    #[test]
+    fn max_matches_total_counts_across_files() {
+        let dir = temp_dir("max-matches-total");
+        for file in 0..20 {
+            dir.write(&format!("{}.txt", file), "needle\n".repeat(10));
+        }
+
+        let config = SearchConfig {
+            num_workers: 4,
+            max_matches_total: Some(10),
+            no_messages: true,
+            ..config(&dir, "needle")
+        };
+        let results = search_sorted(&config);
+        // Each worker may report the line that crossed the cap
+        assert!((10..=14).contains(&results.len()), "{} results", results.len());
+    }
+
+    #[test]
+    fn invalid_utf8_lines_are_reported_lossy() {
+        let dir = temp_dir("lossy");
+        dir.write("a.txt", b"caf\xff needle\nplain needle\n");
+
+        let results = search_sorted(&config(&dir, "needle"));
+        assert_eq!(found(&dir, &results), [("a.txt".to_string(), 1), ("a.txt".to_string(), 2)]);
+        assert_eq!(results[0].line, "caf\u{FFFD} needle");
+        assert_eq!(results[0].matches, [(7, 13)]);
+        assert!(results[0].lossy);
+        assert!(!results[1].lossy);
+    }
+
+    #[test]
+    fn quicksearchignore_files_are_applied_unless_disabled() {
+        let dir = temp_dir("quicksearchignore");
+        dir.write(IGNORE_FILE_NAME, "skipped.txt\n");
+        dir.write("kept.txt", "needle\n");
+        dir.write("sub/skipped.txt", "needle\n");
+
+        let results = search_sorted(&config(&dir, "needle"));
+        assert_eq!(found(&dir, &results), [("kept.txt".to_string(), 1)]);
+
+        let results = search_sorted(&SearchConfig { respect_ignore_file: false, ..config(&dir, "needle") });
+        assert_eq!(found(&dir, &results), [("kept.txt".to_string(), 1), ("sub/skipped.txt".to_string(), 1)]);
+    }
+
+    #[test]
+    fn empty_query_needs_match_empty() {
+        let dir = temp_dir("empty-query");
+        dir.write("a.txt", "one\ntwo\n");
+
+        let error = build_matcher(&config(&dir, "")).unwrap_err();
+        assert!(error.to_string().contains("No search text provided"), "{}", error);
+
+        let results = search_sorted(&SearchConfig { match_empty: true, ..config(&dir, "") });
+        assert_eq!(found(&dir, &results), [("a.txt".to_string(), 1), ("a.txt".to_string(), 2)]);
+    }
+
+    #[test]
+    fn name_regex_and_content_must_both_match() {
+        let dir = temp_dir("name-pattern");
+        dir.write("test_a.rs", "needle\n");
+        dir.write("test_b.rs", "other\n");
+        dir.write("main.rs", "needle\n");
+
+        let name_pattern = Some("^test_".to_string());
+        let results = search_sorted(&SearchConfig { name_pattern, ..config(&dir, "needle") });
+        assert_eq!(found(&dir, &results), [("test_a.rs".to_string(), 1)]);
+
+        let name_pattern = Some("(".to_string());
+        let error = FilePatterns::for_files(&SearchConfig { name_pattern, ..config(&dir, "needle") })
+            .err()
+            .expect("invalid --name regex");
+        assert!(error.to_string().contains("Invalid --name regex"), "{}", error);
+    }
+
+    #[test]
+    fn panicking_file_is_recorded_and_the_search_goes_on() {
+        let dir = temp_dir("panic");
+        dir.write("a.txt", "needle\n");
+        let boom = dir.write("b.boom", "needle\n");
+        let mut extractors = Extractors::default();
+        extractors.register("boom", |_| panic!("extractor failed"));
+
+        let config = SearchConfig { extractors, num_workers: 1, ..config(&dir, "needle") };
+        let results = search_sorted(&config);
+        assert_eq!(found(&dir, &results), [("a.txt".to_string(), 1)]);
+        assert_eq!(*config.failed_files.lock().unwrap(), [boom]);
+    }
+
+    #[test]
+    fn each_encoding_rule_applies_to_its_own_files() {
+        let dir = temp_dir("encodings");
+        dir.write("menu.lat", b"caf\xe9 au lait\n");
+        let utf16: Vec<u8> = "café noir\n".encode_utf16().flat_map(|unit| unit.to_le_bytes()).collect();
+        dir.write("menu.u16", utf16);
+        dir.write("menu.txt", "café crème\n");
+
+        let results = search_sorted(&SearchConfig {
+            encodings: vec![
+                ("latin1".to_string(), "*.lat".to_string()),
+                ("utf-16le".to_string(), "*.u16".to_string()),
+            ],
+            ..config(&dir, "café")
+        });
+        assert_eq!(found(&dir, &results), [
+            ("menu.lat".to_string(), 1),
+            ("menu.txt".to_string(), 1),
+            ("menu.u16".to_string(), 1),
+        ]);
+        let lines: Vec<&str> = results.iter().map(|result| result.line.as_str()).collect();
+        assert_eq!(lines, ["café au lait", "café crème", "café noir"]);
+    }
+
+    /// Runs git in `dir`, false when git is not installed
+    fn git(dir: &TempDir, args: &[&str]) -> bool {
+        match Command::new("git").arg("-C").arg(dir.path()).args(args).output() {
+            Ok(output) => {
+                assert!(output.status.success(), "git {:?}: {}", args, String::from_utf8_lossy(&output.stderr));
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    #[test]
+    fn git_tracked_files_leave_out_untracked_ones() {
+        let dir = temp_dir("git-tracked");
+        dir.write("tracked.txt", "needle\n");
+        dir.write("untracked.txt", "needle\n");
+        if !git(&dir, &["init", "-q"]) {
+            return;
+        }
+        git(&dir, &["add", "tracked.txt"]);
+
+        let files = git_tracked_files(&config(&dir, "needle")).expect("list tracked files");
+        assert_eq!(files, Some(vec![dir.path().join("tracked.txt")]));
+    }
+
+    #[test]
+    fn pdf_whitespace_is_trimmed_kept_or_collapsed() {
+        let text = "  spaced    needle  \n\nneedle again\n";
+        let lines = |options: &PdfOptions| -> Vec<(u64, String)> {
+            search_pdf_text(text, "needle", options).into_iter()
+                .map(|result| (result.line_number, result.line))
+                .collect()
+        };
+
+        assert_eq!(lines(&pdf_options(0)),
+            [(1, "spaced    needle".to_string()), (3, "needle again".to_string())]);
+        assert_eq!(lines(&PdfOptions { raw: true, ..pdf_options(0) }),
+            [(1, "  spaced    needle  ".to_string()), (3, "needle again".to_string())]);
+        // Blank lines are dropped, so later lines move up
+        assert_eq!(lines(&PdfOptions { normalize: true, ..pdf_options(0) }),
+            [(1, "spaced needle".to_string()), (2, "needle again".to_string())]);
+
+        let options = PdfOptions { raw: true, ..pdf_options(0) };
+        let command = options.command(Path::new("doc.pdf"));
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(args, ["-raw", "doc.pdf"]);
+    }
+
+    #[test]
+    fn pdf_paragraphs_end_at_blank_lines_and_pages() {
+        let lines = ["first  line", "of para", "", "second", "\x0cthird"];
+        let (paragraphs, pages) = pdf_paragraphs(&lines, &pdf_line_pages(&lines));
+        assert_eq!(paragraphs, ["first line of para", "second", "third"]);
+        assert_eq!(pages, [0, 0, 1]);
+
+        let options = PdfOptions { paragraphs: true, ..pdf_options(0) };
+        let results = search_pdf_text("intro\n\nthe needle\nspans lines\n", "needle spans", &options);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].line_number, 2);
+        assert_eq!(results[0].line, "the needle spans lines");
+    }
+
+    #[test]
+    fn double_star_spans_directories_below_the_root() {
+        let rooted = |globs: &[&str]| FilePatterns { roots: vec![PathBuf::from("root")], ..patterns(globs) };
+
+        let foo = rooted(&["**/foo.rs"]);
+        assert!(foo.matches(Path::new("root/foo.rs")));
+        assert!(foo.matches(Path::new("root/a/b/foo.rs")));
+        assert!(!foo.matches(Path::new("root/a/bar.rs")));
+
+        let nested = rooted(&["a/**/b.txt"]);
+        assert!(nested.matches(Path::new("root/a/b.txt")));
+        assert!(nested.matches(Path::new("root/a/x/y/b.txt")));
+        assert!(!nested.matches(Path::new("root/c/b.txt")));
+        // The root itself is not part of the matched path
+        assert!(!rooted(&["root/**/b.txt"]).matches(Path::new("root/a/b.txt")));
+
+        // Without a `/` only the file name counts, at any depth
+        let rust = rooted(&["*.rs"]);
+        assert!(rust.matches(Path::new("root/a/b/main.rs")));
+        assert!(!rooted(&["a/*.rs"]).matches(Path::new("root/a/b/main.rs")));
+    }
+
+    #[test]
+    fn candidate_count_matches_the_files_searched() {
+        let dir = temp_dir("candidates");
+        dir.write("a.txt", "needle\n");
+        dir.write("sub/b.txt", "hay\n");
+        dir.write("c.log", "needle\n");
+        dir.write("doc.pdf", "not searched\n");
+
+        let config = SearchConfig {
+            patterns: vec!["*.txt".to_string(), "*.pdf".to_string()],
+            search_pdfs: false,
+            ..config(&dir, "needle")
+        };
+        let quit = AtomicBool::new(false);
+        assert_eq!(count_candidate_files(&config, &quit).expect("count files"), 2);
+        search_sorted(&config);
+        assert_eq!(config.files_processed.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn ocr_images_are_not_searched_as_text() {
+        let dir = temp_dir("ocr");
+        dir.write("notes.txt", "needle\n");
+        // Not a readable image, OCR fails on it or is not installed
+        dir.write("scan.png", "needle\n");
+
+        let results = search_sorted(&config(&dir, "needle"));
+        assert_eq!(found(&dir, &results), [("notes.txt".to_string(), 1), ("scan.png".to_string(), 1)]);
+
+        let results = search_sorted(&SearchConfig { ocr: true, no_messages: true, ..config(&dir, "needle") });
+        assert_eq!(found(&dir, &results), [("notes.txt".to_string(), 1)]);
+    }
+
+    #[test]
+    fn max_per_dir_caps_each_directory() {
+        let dir = temp_dir("max-per-dir");
+        dir.write("top.txt", "needle\nneedle\nneedle\n");
+        for name in ["a/1.txt", "a/2.txt", "a/3.txt"] {
+            dir.write(name, "needle\nneedle\n");
+        }
+        dir.write("b/1.txt", "needle\n");
+
+        let results = search_sorted(&SearchConfig { max_per_dir: Some(2), ..config(&dir, "needle") });
+        let mut per_dir: HashMap<PathBuf, usize> = HashMap::new();
+        for result in &results {
+            let parent = result.path.parent().expect("file in a directory");
+            *per_dir.entry(parent.strip_prefix(dir.path()).expect("below the root").to_path_buf()).or_default() += 1;
+        }
+        assert_eq!(per_dir.len(), 3);
+        assert_eq!(per_dir[Path::new("")], 2);
+        assert_eq!(per_dir[Path::new("a")], 2);
+        assert_eq!(per_dir[Path::new("b")], 1);
+    }
+
+    #[test]
+    fn excluded_dirs_are_never_entered() {
+        let dir = temp_dir("exclude-dir");
+        dir.write("a.txt", "needle\n");
+        dir.write("src/b.txt", "needle\n");
+        dir.write("node_modules/c.txt", "needle\n");
+        dir.write("src/build-1/d.txt", "needle\n");
+
+        let config = SearchConfig {
+            exclude_dirs: vec!["node_modules".to_string(), "build-*".to_string()],
+            ..config(&dir, "needle")
+        };
+        let results = search_sorted(&config);
+        assert_eq!(found(&dir, &results), [("a.txt".to_string(), 1), ("src/b.txt".to_string(), 1)]);
+        assert_eq!(config.files_processed.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn unmerged_adjacent_matches_are_context_of_each_other() {
+        let dir = temp_dir("no-context-merge");
+        dir.write("a.txt", "a\nneedle one\nneedle two\nd\n");
+
+        let results = search_sorted(&SearchConfig { context_lines: 1, merge_context: false, ..config(&dir, "needle") });
+        assert_eq!(found(&dir, &results), [("a.txt".to_string(), 2), ("a.txt".to_string(), 3)]);
+        assert_eq!(results[0].context_before, [(1, "a".to_string())]);
+        assert_eq!(results[0].context_after, [(3, "needle two".to_string())]);
+        assert_eq!(results[1].context_before, [(2, "needle one".to_string())]);
+        assert_eq!(results[1].context_after, [(4, "d".to_string())]);
+    }
+
+    #[test]
+    fn literal_query_finds_crlf_lines_with_trim_cr() {
+        let dir = temp_dir("crlf-literal");
+        dir.write("a.txt", "first\r\nneedle here\r\nlast\r\n");
+
+        let results = search_sorted(&SearchConfig { crlf: true, ..config(&dir, "needle") });
+        assert_eq!(found(&dir, &results), [("a.txt".to_string(), 2)]);
+        assert_eq!(results[0].line, "needle here");
+    }
+
+    #[test]
+    fn braces_expand_to_every_alternative() {
+        assert_eq!(expand_braces("*.{txt,md}"), ["*.txt", "*.md"]);
+        assert_eq!(expand_braces("{a,b{c,d}}.rs"), ["a.rs", "bc.rs", "bd.rs"]);
+        assert_eq!(expand_braces("{x,y}-{1,2}"), ["x-1", "x-2", "y-1", "y-2"]);
+        // Kept as written: no comma, no closing brace, escaped or in a class
+        assert_eq!(expand_braces("{single}.rs"), ["{single}.rs"]);
+        assert_eq!(expand_braces("{open,ended"), ["{open,ended"]);
+        assert_eq!(expand_braces("\\{a,b}"), ["\\{a,b}"]);
+        assert_eq!(expand_braces("[{]a,b}"), ["[{]a,b}"]);
+
+        let chars: Vec<char> = "{a,{b,c},d}".chars().collect();
+        assert_eq!(brace_group(&chars, 0), Some((10, vec![2, 8])));
+        assert_eq!(brace_group(&chars, 3), Some((7, vec![5])));
+        assert_eq!(brace_group(&['{', 'a'], 0), None);
+    }
+
+    #[test]
+    fn file_patterns_ignore_case_only_with_iglob() {
+        let globs = vec!["*.{TXT,md}".to_string()];
+        let exact = FilePatterns::new(&globs, false);
+        assert!(exact.matches(Path::new("a.TXT")));
+        assert!(!exact.matches(Path::new("a.txt")));
+        assert!(!exact.matches(Path::new("README.MD")));
+
+        let ignore_case = FilePatterns::new(&globs, true);
+        assert!(ignore_case.matches(Path::new("a.txt")));
+        assert!(ignore_case.matches(Path::new("README.MD")));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn case_differing_files_are_told_apart() {
+        let dir = temp_dir("glob-case");
+        dir.write("upper.TXT", "needle\n");
+        dir.write("lower.txt", "needle\n");
+
+        let results = search_sorted(&SearchConfig { patterns: vec!["*.txt".to_string()], ..config(&dir, "needle") });
+        assert_eq!(found(&dir, &results), [("lower.txt".to_string(), 1)]);
+
+        let config = SearchConfig { patterns: vec!["*.txt".to_string()], glob_ignore_case: true, ..config(&dir, "needle") };
+        let results = search_sorted(&config);
+        assert_eq!(found(&dir, &results), [("lower.txt".to_string(), 1), ("upper.TXT".to_string(), 1)]);
+    }
+
+    // The file system ignores case here, the globs still don't without --iglob
+    #[cfg(any(target_os = "macos", target_os = "windows"))]
+    #[test]
+    fn globs_stay_case_sensitive_on_case_insensitive_file_systems() {
+        let dir = temp_dir("glob-case");
+        dir.write("notes.TXT", "needle\n");
+
+        let results = search_sorted(&SearchConfig { patterns: vec!["*.txt".to_string()], ..config(&dir, "needle") });
+        assert!(results.is_empty());
+
+        let config = SearchConfig { patterns: vec!["*.txt".to_string()], glob_ignore_case: true, ..config(&dir, "needle") };
+        assert_eq!(found(&dir, &search_sorted(&config)), [("notes.TXT".to_string(), 1)]);
+    }
+
+    #[test]
+    fn nul_share_is_a_percentage_of_the_sample() {
+        assert_eq!(nul_percent(b""), 0.0);
+        assert_eq!(nul_percent(b"text"), 0.0);
+        assert_eq!(nul_percent(b"ab\0\0"), 50.0);
+        assert_eq!(nul_percent(&[0; 8]), 100.0);
+    }
+
+    #[test]
+    fn binary_threshold_skips_files_above_it() {
+        let dir = temp_dir("binary-threshold");
+        dir.write("text.txt", "needle\n");
+        // 1% and about 88% NUL bytes
+        let mut low = b"needle\n".to_vec();
+        low.extend([b'x'; 92]);
+        low.push(0);
+        dir.write("low.bin", low);
+        let mut high = b"needle\n".to_vec();
+        high.extend([0; 50]);
+        dir.write("high.bin", high);
+
+        let config = SearchConfig { binary_threshold: Some(10.0), ..config(&dir, "needle") };
+        let results = search_sorted(&config);
+        assert_eq!(found(&dir, &results), [("low.bin".to_string(), 1), ("text.txt".to_string(), 1)]);
+        assert_eq!(config.files_processed.load(Ordering::Relaxed), 2);
+    }
 }
@@ -0,0 +1,59 @@
+use anyhow::Result;
+use std::path::Path;
+
+/// Files whose values --jsonpath selects, everything else is searched as text
+pub fn is_structured(path: &Path) -> bool {
+    path.extension().map_or(false, |ext| ext == "json" || ext == "yaml" || ext == "yml")
+}
+
+/// A parsed --jsonpath expression such as `$.servers[*].host`
+#[cfg(feature = "structured")]
+pub struct JsonPathQuery(serde_json_path::JsonPath);
+
+#[cfg(feature = "structured")]
+impl JsonPathQuery {
+    pub fn new(expr: &str) -> Result<Self> {
+        let path = serde_json_path::JsonPath::parse(expr)
+            .map_err(|e| anyhow::anyhow!("Invalid --jsonpath '{}': {}", expr, e))?;
+        Ok(Self(path))
+    }
+
+    /// The values selected in `text`, parsed as YAML or JSON by the extension of
+    /// `path`, each with its location like `$['servers'][0]['host']`. `None`
+    /// when the text doesn't parse
+    pub fn select(&self, path: &Path, text: &str) -> Option<Vec<(String, String)>> {
+        use serde_json::Value;
+
+        let yaml = path.extension().map_or(false, |ext| ext == "yaml" || ext == "yml");
+        let document: Value = if yaml {
+            serde_yaml::from_str(text).ok()?
+        } else {
+            serde_json::from_str(text).ok()?
+        };
+        let values = self.0.query_located(&document).into_iter()
+            .map(|node| {
+                // Strings as written, unless a newline would split the result
+                let value = match node.node() {
+                    Value::String(value) if !value.contains('\n') => value.clone(),
+                    value => value.to_string(),
+                };
+                (node.location().to_string(), value)
+            })
+            .collect();
+        Some(values)
+    }
+}
+
+#[cfg(not(feature = "structured"))]
+pub struct JsonPathQuery;
+
+#[cfg(not(feature = "structured"))]
+impl JsonPathQuery {
+    pub fn new(_expr: &str) -> Result<Self> {
+        anyhow::bail!("quicksearch was built without the `structured` feature (rebuild with --features structured to use --jsonpath)")
+    }
+
+    pub fn select(&self, _path: &Path, _text: &str) -> Option<Vec<(String, String)>> {
+        None
+    }
+}
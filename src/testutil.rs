@@ -0,0 +1,28 @@
+use std::{fs, path::PathBuf};
+
+pub use tempfile::TempDir;
+
+/// A scratch directory below the system temp directory, named after the test
+/// and removed again when dropped
+pub fn temp_dir(name: &str) -> TempDir {
+    tempfile::Builder::new()
+        .prefix(&format!("quicksearch-{}-", name))
+        .tempdir()
+        .expect("create temp dir")
+}
+
+pub trait WriteFile {
+    /// Writes `contents` to `name` below the directory, creating its parents
+    fn write(&self, name: &str, contents: impl AsRef<[u8]>) -> PathBuf;
+}
+
+impl WriteFile for TempDir {
+    fn write(&self, name: &str, contents: impl AsRef<[u8]>) -> PathBuf {
+        let path = self.path().join(name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).expect("create parent dirs");
+        }
+        fs::write(&path, contents).expect("write test file");
+        path
+    }
+}
@@ -0,0 +1,291 @@
+use anyhow::Result;
+use crossterm::{
+    event::{self, Event, KeyCode, KeyEventKind},
+    execute,
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use ratatui::{
+    backend::CrosstermBackend,
+    layout::{Constraint, Direction, Layout},
+    style::{Color, Modifier, Style},
+    text::Line,
+    widgets::{Block, Borders, Paragraph},
+    Frame, Terminal,
+};
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, TryRecvError},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+use crate::search::{search, SearchResult};
+use crate::SearchConfig;
+
+// Upper bound of results taken from the channel per frame, keeps typing responsive
+const RESULTS_PER_TICK: usize = 1000;
+const PAGE_SIZE: usize = 20;
+
+#[derive(PartialEq)]
+enum Focus {
+    Query,
+    Results,
+}
+
+struct TuiState {
+    config: SearchConfig,
+    query: String,
+    lines: Vec<Line<'static>>,
+    scroll: usize,
+    focus: Focus,
+    status: String,
+    matches: usize,
+    quit: Arc<AtomicBool>,
+    results_rx: Option<Receiver<Result<SearchResult, String>>>,
+}
+
+fn format_result(result: &SearchResult) -> Vec<Line<'static>> {
+    let width = result.gutter_width();
+    let mut lines = Vec::new();
+    lines.push(Line::styled(
+        format!("File: {}:{}", result.path.display(), result.line_number),
+        Style::default().fg(Color::Cyan),
+    ));
+
+    for (line_num, line) in &result.context_before {
+        lines.push(Line::raw(format!("{:>width$} | {}", line_num, line, width = width)));
+    }
+
+    lines.push(Line::styled(
+        format!(">{:>width$} | {}", result.line_number, result.line, width = width - 1),
+        Style::default().add_modifier(Modifier::BOLD),
+    ));
+
+    for (line_num, line) in &result.context_after {
+        lines.push(Line::raw(format!("{:>width$} | {}", line_num, line, width = width)));
+    }
+
+    lines.push(Line::raw(""));
+    lines
+}
+
+impl TuiState {
+    fn new(config: SearchConfig) -> Self {
+        Self {
+            query: config.query.clone(),
+            config,
+            lines: Vec::new(),
+            scroll: 0,
+            focus: Focus::Query,
+            status: "Ready".to_string(),
+            matches: 0,
+            quit: Arc::new(AtomicBool::new(false)),
+            results_rx: None,
+        }
+    }
+
+    fn start_search(&mut self) {
+        self.cancel();
+        self.lines.clear();
+        self.scroll = 0;
+        self.matches = 0;
+
+        // Every search gets its own quit flag so a cancelled one can't stop the next
+        let quit = Arc::new(AtomicBool::new(false));
+        self.quit = quit.clone();
+
+        let mut config = self.config.clone();
+        config.query = self.query.clone();
+
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            match search(&config, quit) {
+                Ok(results) => {
+                    for result in results {
+                        if tx.send(Ok(result)).is_err() {
+                            break;
+                        }
+                    }
+                }
+                Err(e) => {
+                    let _ = tx.send(Err(e.to_string()));
+                }
+            }
+        });
+
+        self.results_rx = Some(rx);
+        self.status = "Searching...".to_string();
+    }
+
+    fn cancel(&mut self) {
+        self.quit.store(true, Ordering::Relaxed);
+        if self.results_rx.take().is_some() {
+            self.status = format!("Search cancelled after {} matches", self.matches);
+        }
+    }
+
+    fn drain_results(&mut self) {
+        let mut finished = false;
+
+        if let Some(rx) = &self.results_rx {
+            for _ in 0..RESULTS_PER_TICK {
+                match rx.try_recv() {
+                    Ok(Ok(result)) => {
+                        self.matches += 1;
+                        self.lines.extend(format_result(&result));
+                    }
+                    Ok(Err(e)) => {
+                        self.status = format!("Search error: {}", e);
+                    }
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        finished = true;
+                        break;
+                    }
+                }
+            }
+        }
+
+        if finished {
+            self.results_rx = None;
+            if !self.status.starts_with("Search error") {
+                self.status = format!("Found {} matches", self.matches);
+            }
+        } else if self.results_rx.is_some() {
+            self.status = format!("Searching... {} matches", self.matches);
+        }
+    }
+
+    fn scroll_by(&mut self, delta: isize) {
+        let max = self.lines.len().saturating_sub(1);
+        self.scroll = self.scroll.saturating_add_signed(delta).min(max);
+    }
+
+    /// Returns false once the user asked to leave
+    fn handle_key(&mut self, code: KeyCode) -> bool {
+        match code {
+            KeyCode::Esc => return self.cancel_or_exit(),
+            KeyCode::Char('q') if self.focus == Focus::Results => return self.cancel_or_exit(),
+            KeyCode::Tab => {
+                self.focus = match self.focus {
+                    Focus::Query => Focus::Results,
+                    Focus::Results => Focus::Query,
+                };
+            }
+            KeyCode::Enter if self.focus == Focus::Query => self.start_search(),
+            KeyCode::Backspace if self.focus == Focus::Query => {
+                self.query.pop();
+            }
+            KeyCode::Char('k') if self.focus == Focus::Results => self.scroll_by(-1),
+            KeyCode::Char('j') if self.focus == Focus::Results => self.scroll_by(1),
+            KeyCode::Char(c) if self.focus == Focus::Query => self.query.push(c),
+            KeyCode::Up => self.scroll_by(-1),
+            KeyCode::Down => self.scroll_by(1),
+            KeyCode::PageUp => self.scroll_by(-(PAGE_SIZE as isize)),
+            KeyCode::PageDown => self.scroll_by(PAGE_SIZE as isize),
+            _ => {}
+        }
+        true
+    }
+
+    // Esc/q stops a running search first, and leaves only when idle
+    fn cancel_or_exit(&mut self) -> bool {
+        if self.results_rx.is_some() {
+            self.cancel();
+            true
+        } else {
+            false
+        }
+    }
+}
+
+fn focus_style(active: bool) -> Style {
+    if active {
+        Style::default().fg(Color::Yellow)
+    } else {
+        Style::default()
+    }
+}
+
+fn draw(frame: &mut Frame, state: &TuiState) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Length(3),
+            Constraint::Min(1),
+            Constraint::Length(1),
+        ])
+        .split(frame.size());
+
+    let input = Paragraph::new(state.query.as_str())
+        .block(Block::default()
+            .borders(Borders::ALL)
+            .title("Search")
+            .border_style(focus_style(state.focus == Focus::Query)));
+    frame.render_widget(input, chunks[0]);
+
+    // Only hand the visible slice to ratatui, result lists can be huge
+    let height = chunks[1].height.saturating_sub(2) as usize;
+    let start = state.scroll.min(state.lines.len());
+    let end = (start + height).min(state.lines.len());
+    let results = Paragraph::new(state.lines[start..end].to_vec())
+        .block(Block::default()
+            .borders(Borders::ALL)
+            .title("Results")
+            .border_style(focus_style(state.focus == Focus::Results)));
+    frame.render_widget(results, chunks[1]);
+
+    let status = Paragraph::new(format!(
+        "{}  |  Enter: search  Tab: switch focus  Esc/q: cancel or quit",
+        state.status
+    ));
+    frame.render_widget(status, chunks[2]);
+
+    if state.focus == Focus::Query {
+        frame.set_cursor(
+            chunks[0].x + 1 + state.query.chars().count() as u16,
+            chunks[0].y + 1,
+        );
+    }
+}
+
+fn event_loop(terminal: &mut Terminal<CrosstermBackend<io::Stdout>>, mut state: TuiState) -> Result<()> {
+    if !state.query.is_empty() {
+        state.start_search();
+    }
+
+    loop {
+        state.drain_results();
+        terminal.draw(|frame| draw(frame, &state))?;
+
+        if event::poll(Duration::from_millis(50))? {
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press && !state.handle_key(key.code) {
+                    break;
+                }
+            }
+        }
+    }
+
+    state.cancel();
+    Ok(())
+}
+
+pub fn run(config: SearchConfig) -> Result<()> {
+    enable_raw_mode()?;
+    let mut stdout = io::stdout();
+    execute!(stdout, EnterAlternateScreen)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(stdout))?;
+
+    let result = event_loop(&mut terminal, TuiState::new(config));
+
+    // Always restore the terminal, even if the loop failed
+    disable_raw_mode()?;
+    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+    terminal.show_cursor()?;
+
+    result
+}
@@ -0,0 +1,18 @@
+/// Built-in file types, each a name and the globs selecting its files
+pub const FILE_TYPES: &[(&str, &[&str])] = &[
+    ("C/C++", &["*.c", "*.h", "*.cc", "*.cpp", "*.hpp"]),
+    ("Go", &["*.go"]),
+    ("HTML", &["*.html", "*.htm"]),
+    ("Java", &["*.java"]),
+    ("JavaScript", &["*.js", "*.mjs", "*.jsx"]),
+    ("JSON", &["*.json"]),
+    ("Markdown", &["*.md", "*.markdown"]),
+    ("PDF", &["*.pdf"]),
+    ("Python", &["*.py", "*.pyi"]),
+    ("Rust", &["*.rs"]),
+    ("Shell", &["*.sh", "*.bash", "*.zsh"]),
+    ("Text", &["*.txt"]),
+    ("TOML", &["*.toml"]),
+    ("TypeScript", &["*.ts", "*.tsx"]),
+    ("YAML", &["*.yml", "*.yaml"]),
+];
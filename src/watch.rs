@@ -0,0 +1,92 @@
+use anyhow::Result;
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use std::{
+    collections::HashSet,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc,
+    },
+    time::Duration,
+};
+use crate::config::excluded_dirs;
+use crate::search::{matching_files, search, SearchResult};
+use crate::SearchConfig;
+
+// How often the quit flag is checked while idle
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+// Changes closer together than this are searched as one batch
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Identifies a match so unchanged lines are not reported again
+pub type MatchKey = (PathBuf, u64, String);
+
+pub fn match_key(result: &SearchResult) -> MatchKey {
+    (result.path.clone(), result.line_number, result.line.clone())
+}
+
+fn collect_paths(event: notify::Result<Event>, changed: &mut HashSet<PathBuf>) {
+    if let Ok(event) = event {
+        if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+            changed.extend(event.paths);
+        }
+    }
+}
+
+/// Watches the search roots and searches files again when they change, calling
+/// `on_result` for matches not seen before. Runs until `quit` is set.
+pub fn watch_changes(
+    config: &SearchConfig,
+    mut seen: HashSet<MatchKey>,
+    quit: Arc<AtomicBool>,
+    mut on_result: impl FnMut(&SearchResult) -> std::io::Result<()>,
+) -> Result<()> {
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(move |event| {
+        let _ = tx.send(event);
+    })?;
+    for root in config.search_roots() {
+        watcher.watch(&root, RecursiveMode::Recursive)?;
+    }
+    // Writing the search history must not trigger another search
+    let excluded = excluded_dirs();
+
+    while !quit.load(Ordering::Relaxed) {
+        let mut changed = HashSet::new();
+        match rx.recv_timeout(POLL_INTERVAL) {
+            Ok(event) => collect_paths(event, &mut changed),
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        // Wait for a quiet period, editors and loggers often write in bursts
+        while let Ok(event) = rx.recv_timeout(DEBOUNCE) {
+            collect_paths(event, &mut changed);
+        }
+
+        let paths: Vec<PathBuf> = changed.into_iter()
+            .filter(|path| path.is_file() && !excluded.iter().any(|dir| path.starts_with(dir)))
+            .collect();
+        if paths.is_empty() {
+            continue;
+        }
+
+        // Search just the changed files that match the -p patterns, with the same settings
+        let files = matching_files(config, paths)?;
+        if files.is_empty() {
+            continue;
+        }
+        let changed_config = SearchConfig {
+            files: Some(files),
+            ..config.clone()
+        };
+        for result in search(&changed_config, quit.clone())? {
+            if seen.insert(match_key(&result)) {
+                on_result(&result)?;
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,324 @@
+use std::{
+    fs,
+    io::{BufRead, BufReader, Read, Write},
+    process::{Command, Stdio},
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+use flate2::read::GzDecoder;
+
+// The test helpers of the crate, shared with its unit tests
+#[path = "../src/testutil.rs"]
+mod testutil;
+
+use testutil::{temp_dir, TempDir, WriteFile};
+
+/// The binary run in `dir`. The config directory doesn't exist, so the
+/// user's config file and history stay out of the tests
+fn quicksearch(dir: &TempDir) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_quicksearch"));
+    command.current_dir(dir.path())
+        .env("XDG_CONFIG_HOME", dir.path().with_extension("config"));
+    command
+}
+
+/// Stdout of a run that has to succeed
+fn run(command: &mut Command) -> String {
+    let output = command.output().expect("run quicksearch");
+    assert!(output.status.success(), "quicksearch failed: {}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).expect("UTF-8 output")
+}
+
+#[test]
+fn match_on_the_first_line_is_line_1() {
+    let dir = temp_dir("first-line");
+    dir.write("a.txt", "needle\nother\n");
+
+    let output = run(quicksearch(&dir).args(["-t", "needle", "a.txt"]));
+    assert_eq!(output, "File: a.txt:1\n> 1 | needle\n\n");
+}
+
+#[cfg(unix)]
+#[test]
+fn ctrl_c_sets_the_quit_flag() {
+    let dir = temp_dir("ctrl-c");
+    // Reading the file list from stdin keeps the search waiting for the signal
+    let mut child = quicksearch(&dir)
+        .args(["-t", "needle", "--files-from", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .expect("start quicksearch");
+    // Time to install the handler
+    thread::sleep(Duration::from_millis(1000));
+    let killed = Command::new("kill").args(["-INT", &child.id().to_string()]).status().expect("run kill");
+    assert!(killed.success());
+
+    drop(child.stdin.take());
+    let status = child.wait().expect("wait for quicksearch");
+    // The handler only sets the quit flag, which ends the run with the exit code of SIGINT
+    assert_eq!(status.code(), Some(130));
+}
+
+#[test]
+fn gitignore_applies_the_global_excludes_and_info_exclude() {
+    let dir = temp_dir("git-ignores");
+    let home = temp_dir("git-ignores-home");
+    home.write("git/ignore", "global.txt\n");
+    dir.write(".git/info/exclude", "local.txt\n");
+    for file in ["global.txt", "local.txt", "kept.txt"] {
+        dir.write(file, "needle\n");
+    }
+
+    let listed = |flags: &[&str]| -> String {
+        run(quicksearch(&dir)
+            .env("HOME", home.path())
+            .env("XDG_CONFIG_HOME", home.path())
+            .args(["-l", "--relative", "-t", "needle"])
+            .args(flags))
+    };
+    assert_eq!(listed(&[]), "global.txt\nkept.txt\nlocal.txt\n");
+    assert_eq!(listed(&["--gitignore"]), "kept.txt\n");
+    assert_eq!(listed(&["--gitignore", "--no-global-ignore"]), "global.txt\nkept.txt\n");
+    assert_eq!(listed(&["--gitignore", "--no-git-exclude"]), "kept.txt\nlocal.txt\n");
+}
+
+#[test]
+fn files_without_match_lists_only_those() {
+    let dir = temp_dir("files-without-match");
+    dir.write("a.txt", "needle\n");
+    dir.write("b.txt", "hay\n");
+    dir.write("sub/c.txt", "more hay\n");
+    dir.write("sub/d.txt", "hay\nneedle\n");
+
+    let output = run(quicksearch(&dir).args(["-L", "--relative", "--path-separator", "/", "-t", "needle"]));
+    assert_eq!(output, "b.txt\nsub/c.txt\n");
+}
+
+#[cfg(unix)]
+#[test]
+fn results_are_printed_while_the_search_runs() {
+    let dir = temp_dir("streaming");
+    dir.write("a.txt", "needle\n");
+    // Run by `sh` as the PDF extractor, it keeps one worker busy
+    dir.write("slow.pdf", "sleep 10\n");
+
+    let mut child = quicksearch(&dir)
+        .args(["-t", "needle", "--relative", "-j", "2", "--pdf-command", "sh"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("start quicksearch");
+    let stdout = child.stdout.take().expect("piped stdout");
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    let first = rx.recv_timeout(Duration::from_secs(5)).ok();
+    let running = child.try_wait().expect("check quicksearch").is_none();
+    let _ = child.kill();
+    let _ = child.wait();
+    assert_eq!(first.as_deref(), Some("File: a.txt:1"));
+    assert!(running, "the search ended before the extractor did");
+}
+
+#[test]
+fn replace_count_summarizes_without_writing() {
+    let dir = temp_dir("replace-count");
+    dir.write("a.txt", "foo foo\nfoo\nbar\n");
+    dir.write("b.txt", "foo\n");
+
+    let output = run(quicksearch(&dir).args(["-t", "foo", "--replace", "baz", "--count"]));
+    assert_eq!(output, "4 replacements on 3 lines in 2 files\n");
+    assert_eq!(fs::read_to_string(dir.path().join("a.txt")).expect("read a.txt"), "foo foo\nfoo\nbar\n");
+}
+
+#[test]
+fn ordered_output_is_the_same_every_run() {
+    let dir = temp_dir("ordered");
+    dir.write("a.txt", "hay\nneedle\n");
+    dir.write("b.txt", "needle\n");
+    dir.write("sub/c.txt", "needle\nhay\n");
+
+    for _ in 0..5 {
+        let output = run(quicksearch(&dir)
+            .args(["-t", "needle", "--ordered", "-j", "4", "--relative", "--path-separator", "/"]));
+        assert_eq!(output, "File: a.txt:2\n> 2 | needle\n\nFile: b.txt:1\n> 1 | needle\n\nFile: sub/c.txt:1\n> 1 | needle\n\n");
+    }
+}
+
+#[test]
+fn own_config_directory_is_not_searched() {
+    let dir = temp_dir("config-dir");
+    dir.write("a.txt", "needle\n");
+    dir.write(".config/quicksearch/history", "{\"query\":\"needle\"}\n");
+    dir.write(".config/other.txt", "needle\n");
+
+    let output = run(quicksearch(&dir)
+        .env("XDG_CONFIG_HOME", dir.path().join(".config"))
+        .args(["-l", "--relative", "--path-separator", "/", "-t", "needle"]));
+    assert_eq!(output, ".config/other.txt\na.txt\n");
+}
+
+/// Stdout of `command`, failing the test if it is still running after 10 seconds
+#[cfg(unix)]
+fn run_with_timeout(command: &mut Command) -> String {
+    let mut child = command.stdout(Stdio::piped()).spawn().expect("start quicksearch");
+    for _ in 0..100 {
+        if child.try_wait().expect("check quicksearch").is_some() {
+            let output = child.wait_with_output().expect("read output");
+            assert!(output.status.success(), "quicksearch failed");
+            return String::from_utf8(output.stdout).expect("UTF-8 output");
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+    panic!("quicksearch still running after 10 seconds");
+}
+
+#[cfg(unix)]
+#[test]
+fn fifos_are_skipped_without_blocking() {
+    let dir = temp_dir("fifo");
+    dir.write("a.txt", "needle\n");
+    let fifo = dir.path().join("pipe");
+    match Command::new("mkfifo").arg(&fifo).status() {
+        Ok(status) if status.success() => {}
+        // No mkfifo, nothing to test
+        _ => return,
+    }
+    dir.write("list", "a.txt\npipe\n");
+
+    let output = run_with_timeout(quicksearch(&dir).args(["-l", "--relative", "-t", "needle"]));
+    assert_eq!(output, "a.txt\n");
+
+    let output = run_with_timeout(quicksearch(&dir).args(["-l", "--files-from", "list", "-t", "needle"]));
+    assert_eq!(output, "a.txt\n");
+}
+
+/// Stdout of `command` run with `input` on stdin, asserting it succeeds
+fn run_with_input(command: &mut Command, input: &str) -> String {
+    let mut child = command.stdin(Stdio::piped()).stdout(Stdio::piped()).spawn().expect("start quicksearch");
+    child.stdin.take().expect("piped stdin").write_all(input.as_bytes()).expect("write stdin");
+    let output = child.wait_with_output().expect("wait for quicksearch");
+    assert!(output.status.success(), "quicksearch failed: {}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).expect("UTF-8 output")
+}
+
+#[test]
+fn confirm_asks_before_writing_each_file() {
+    let dir = temp_dir("confirm");
+    dir.write("a.txt", "foo\n");
+    dir.write("b.txt", "foo\n");
+    let read = |name: &str| fs::read_to_string(dir.path().join(name)).expect("read file");
+
+    let output = run_with_input(quicksearch(&dir)
+        .args(["-t", "foo", "--replace", "bar", "--in-place", "--confirm"]), "y\nn\n");
+    assert!(output.ends_with("Replaced 1 matches in 1 files (originals saved as .bak)\n"), "{}", output);
+    assert_eq!(read("a.txt"), "bar\n");
+    assert_eq!(read("b.txt"), "foo\n");
+
+    let output = run_with_input(quicksearch(&dir)
+        .args(["-t", "foo", "--replace", "bar", "--in-place", "--confirm"]), "q\n");
+    assert!(output.ends_with("Replaced 0 matches in 0 files (originals saved as .bak)\n"), "{}", output);
+    assert_eq!(read("b.txt"), "foo\n");
+}
+
+#[test]
+fn gzip_output_file_decompresses_to_the_results() {
+    let dir = temp_dir("gzip-output");
+    dir.write("a.txt", "needle\n");
+    let out_dir = temp_dir("gzip-output-file");
+    let gzip = out_dir.path().join("results.txt.gz");
+
+    let output = run(quicksearch(&dir).args(["-t", "needle", "--relative", "--output-file"]).arg(&gzip));
+    assert_eq!(output, "");
+
+    let mut text = String::new();
+    GzDecoder::new(fs::File::open(&gzip).expect("open output file"))
+        .read_to_string(&mut text)
+        .expect("decompress output file");
+    assert_eq!(text, "File: a.txt:1\n> 1 | needle\n\n");
+}
+
+#[test]
+fn unmerged_context_repeats_shared_lines() {
+    let dir = temp_dir("no-context-merge");
+    dir.write("a.txt", "a\nneedle\nb\nneedle\nc\n");
+
+    let output = run(quicksearch(&dir).args(["-t", "needle", "-c", "1", "--no-context-merge", "--relative"]));
+    assert_eq!(output, "File: a.txt:2\n  1 | a\n> 2 | needle\n  3 | b\n--\n  3 | b\n> 4 | needle\n  5 | c\n\n");
+}
+
+#[test]
+fn replace_preview_stops_at_the_limit() {
+    let dir = temp_dir("replace-preview-limit");
+    dir.write("a.txt", "foo 1\nfoo 2\nfoo 3\nfoo 4\n");
+
+    let output = run(quicksearch(&dir)
+        .args(["-t", "foo", "--replace", "bar", "--replace-preview-limit", "2", "--relative"]));
+    assert_eq!(output, "File: a.txt:1\n> 1 | bar 1\n> 2 | bar 2\n\n... and 2 more\n4 replacements in 1 files\n");
+}
+
+#[test]
+fn then_search_looks_only_in_files_with_the_first_text() {
+    let dir = temp_dir("then-search");
+    dir.write("a.txt", "alpha\nbeta\n");
+    dir.write("b.txt", "beta\n");
+    dir.write("c.txt", "alpha\n");
+
+    let output = run(quicksearch(&dir).args(["-t", "alpha", "--then-search", "beta", "--relative"]));
+    assert_eq!(output, "File: a.txt:2\n> 2 | beta\n\n");
+}
+
+#[test]
+fn watch_prints_only_the_new_matches_of_a_changed_file() {
+    let dir = temp_dir("watch");
+    let log = dir.write("app.log", "needle old\n");
+
+    let mut child = quicksearch(&dir)
+        .args(["-t", "needle", "--watch", "--relative"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("start quicksearch");
+    let stdout = child.stdout.take().expect("piped stdout");
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    // The lines of one result, up to the blank line ending it
+    let next_block = |timeout: Duration| -> Vec<String> {
+        let mut block = Vec::new();
+        while let Ok(line) = rx.recv_timeout(timeout) {
+            if line.is_empty() {
+                break;
+            }
+            block.push(line);
+        }
+        block
+    };
+
+    let initial = next_block(Duration::from_secs(10));
+    // Give the watcher time to start after the first search
+    thread::sleep(Duration::from_secs(1));
+    fs::OpenOptions::new().append(true).open(&log).expect("open log")
+        .write_all(b"needle new\n").expect("append to log");
+    let changed = next_block(Duration::from_secs(10));
+    let more = next_block(Duration::from_secs(2));
+    let _ = child.kill();
+    let _ = child.wait();
+
+    assert_eq!(initial, ["File: app.log:1", "> 1 | needle old"]);
+    assert_eq!(changed, ["File: app.log:2", "> 2 | needle new"]);
+    assert!(more.is_empty(), "printed again: {:?}", more);
+}